@@ -0,0 +1,269 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+
+use crate::extract::hex_digest;
+
+/// Name of the record a download leaves in its destination, so later runs
+/// know which tarball a tree came from.
+pub const SOURCE_RECORD: &str = ".ktp-source.json";
+pub const MANIFEST: &str = "manifest.json";
+/// Marks a `<build dir>-verify` directory as made by `ktp build verify`,
+/// which only ever deletes directories carrying it.
+pub const VERIFY_MARKER: &str = ".ktp-verify";
+/// The module signing key Kbuild generates when `CONFIG_MODULE_SIG_KEY` is
+/// left at its default, and the certificate extracted from it.
+pub const SIGNING_KEY_FILES: &[&str] = &["certs/signing_key.pem", "certs/signing_key.x509"];
+
+/// Boot images Kbuild produces under `arch/<arch>/boot`, across architectures.
+const BOOT_IMAGES: &[&str] = &[
+    "bzImage", "zImage", "Image", "Image.gz", "Image.lz4", "Image.zst", "vmlinuz", "vmlinuz.efi", "vmlinux.efi",
+    "uImage", "xipImage", "bzImage.efi",
+];
+
+/// Where the source tree came from.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SourceInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dirty: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tarball: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tarball_sha256: Option<String>,
+}
+
+impl SourceInfo {
+    /// The Git commit of the tree if it is a repository, plus the download
+    /// record left by a previous transfer.
+    pub fn detect(kernel_path: &Path) -> Self {
+        let mut info: SourceInfo = fs::read_to_string(kernel_path.join(SOURCE_RECORD))
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        if let Ok(repo) = git2::Repository::open(kernel_path) {
+            if let Ok(commit) = repo.head().and_then(|head| head.peel_to_commit()) {
+                info.commit = Some(commit.id().to_string());
+                let mut options = git2::StatusOptions::new();
+                options.include_untracked(false);
+                info.dirty = repo.statuses(Some(&mut options)).ok().map(|statuses| !statuses.is_empty());
+            }
+        }
+        info
+    }
+
+    /// Records a downloaded tarball in `dest`.
+    pub fn record_download(dest: &Path, url: &str, sha256: &str) -> Result<()> {
+        let info = SourceInfo {
+            tarball: Some(url.to_string()),
+            tarball_sha256: Some(sha256.to_string()),
+            ..Default::default()
+        };
+        fs::write(dest.join(SOURCE_RECORD), serde_json::to_string_pretty(&info)?)
+            .with_context(|| format!("Failed to write {:?}", dest.join(SOURCE_RECORD)))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolVersion {
+    pub role: String,
+    pub name: String,
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactFile {
+    /// Path relative to the artifact directory.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// `manifest.json` of an artifact directory. Later stages (modules,
+/// packages) add their outputs to it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub release: String,
+    pub arch: String,
+    /// Seconds since the epoch.
+    pub created: u64,
+    pub source: SourceInfo,
+    pub config_sha256: Option<String>,
+    pub toolchain: Vec<ToolVersion>,
+    pub files: Vec<ArtifactFile>,
+    /// Built with `--reproducible`.
+    #[serde(default)]
+    pub reproducible: bool,
+}
+
+impl Manifest {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST);
+        let text = fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        serde_json::from_str(&text).with_context(|| format!("Invalid manifest {:?}", path))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(MANIFEST);
+        fs::write(&path, serde_json::to_string_pretty(self)?).with_context(|| format!("Failed to write {:?}", path))
+    }
+
+    /// Hashes `path` (inside `dir`) and adds or replaces its entry.
+    pub fn add_file(&mut self, dir: &Path, path: &Path) -> Result<()> {
+        let relative = path.strip_prefix(dir).unwrap_or(path).to_string_lossy().into_owned();
+        let entry = ArtifactFile {
+            size: fs::metadata(path).with_context(|| format!("Failed to stat {:?}", path))?.len(),
+            sha256: sha256_file(path)?,
+            path: relative,
+        };
+        self.files.retain(|f| f.path != entry.path);
+        self.files.push(entry);
+        Ok(())
+    }
+}
+
+/// Result of comparing a rebuild's manifest with the original one.
+#[derive(Debug, Default)]
+pub struct Comparison {
+    pub matching: Vec<String>,
+    /// Paths with the original and the rebuilt hash.
+    pub differing: Vec<(String, String, String)>,
+    /// Files of the original the rebuild did not produce.
+    pub missing: Vec<String>,
+}
+
+impl Manifest {
+    pub fn compare(&self, rebuilt: &Manifest) -> Comparison {
+        let mut comparison = Comparison::default();
+        for file in &self.files {
+            match rebuilt.files.iter().find(|f| f.path == file.path) {
+                Some(other) if other.sha256 == file.sha256 => comparison.matching.push(file.path.clone()),
+                Some(other) => {
+                    comparison.differing.push((file.path.clone(), file.sha256.clone(), other.sha256.clone()))
+                }
+                None => comparison.missing.push(file.path.clone()),
+            }
+        }
+        comparison
+    }
+}
+
+/// The boot image `make install` would install for `srcarch`.
+pub fn boot_image(output_dir: &Path, srcarch: &str) -> Option<PathBuf> {
+    let boot = output_dir.join("arch").join(srcarch).join("boot");
+    BOOT_IMAGES.iter().map(|image| boot.join(image)).find(|path| path.is_file())
+}
+
+/// Copies the boot image(s), `vmlinux`, `System.map`, `.config` and the
+/// device trees of `srcarch` from `output_dir` into `dest`, returning the
+/// copied paths.
+pub fn collect(output_dir: &Path, srcarch: &str, dest: &Path) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(dest).with_context(|| format!("Failed to create {:?}", dest))?;
+    let boot = output_dir.join("arch").join(srcarch).join("boot");
+    let mut copied = Vec::new();
+
+    let mut copy = |from: PathBuf, to: PathBuf| -> Result<()> {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(&from, &to).with_context(|| format!("Failed to copy {:?} to {:?}", from, to))?;
+        copied.push(to);
+        Ok(())
+    };
+
+    for image in BOOT_IMAGES {
+        let path = boot.join(image);
+        if path.is_file() {
+            copy(path, dest.join(image))?;
+        }
+    }
+    for name in ["vmlinux", "System.map", ".config", "Module.symvers"] {
+        let path = output_dir.join(name);
+        if path.is_file() {
+            let target = if name == ".config" { "config".to_string() } else { name.to_string() };
+            copy(path, dest.join(target))?;
+        }
+    }
+
+    let dts = boot.join("dts");
+    for dtb in find_files(&dts, "dtb")? {
+        let relative = dtb.strip_prefix(&dts).unwrap_or(&dtb).to_path_buf();
+        copy(dtb, dest.join("dtbs").join(relative))?;
+    }
+    Ok(copied)
+}
+
+fn find_files(dir: &Path, extension: &str) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    if !dir.is_dir() {
+        return Ok(found);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            found.extend(find_files(&path, extension)?);
+        } else if path.extension().is_some_and(|e| e == extension) {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex_digest(&hasher.finish()))
+}
+
+/// Checks that a release string is usable as a directory name.
+pub fn validate_release(release: &str) -> Result<()> {
+    if release.is_empty() || release.contains('/') || release.starts_with('.') {
+        anyhow::bail!("Unusable kernel release string '{}'", release);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_release_rejects_path_like_strings() {
+        validate_release("6.9.0-acme").unwrap();
+        validate_release("6.10.0-rc2+").unwrap();
+        for release in ["", "..", "../6.9.0", ".hidden", "6.9/0", "/boot"] {
+            assert!(validate_release(release).is_err(), "{}", release);
+        }
+    }
+
+    fn manifest(files: &[(&str, &str)]) -> Manifest {
+        let files = files
+            .iter()
+            .map(|(path, sha256)| ArtifactFile { path: path.to_string(), size: 1, sha256: sha256.to_string() })
+            .collect();
+        Manifest { files, ..Default::default() }
+    }
+
+    #[test]
+    fn compare_sorts_files_into_matching_differing_and_missing() {
+        let original = manifest(&[("vmlinuz", "aa"), ("System.map", "bb"), ("config", "cc")]);
+        let rebuilt = manifest(&[("vmlinuz", "aa"), ("System.map", "bd"), ("extra", "ee")]);
+        let comparison = original.compare(&rebuilt);
+        assert_eq!(comparison.matching, ["vmlinuz"]);
+        assert_eq!(comparison.differing, [("System.map".to_string(), "bb".to_string(), "bd".to_string())]);
+        assert_eq!(comparison.missing, ["config"]);
+    }
+}
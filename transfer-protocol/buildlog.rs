@@ -0,0 +1,621 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::Command as TokioCommand;
+use tokio::sync::mpsc;
+
+use crate::kbuild;
+
+/// How many errors the end-of-run summary lists.
+const SUMMARY_ERRORS: usize = 10;
+
+/// Diagnostics of the last successful full build in a log directory, the
+/// default warning baseline. Incremental builds only recompile what
+/// changed, so their warnings cannot serve as one.
+pub const BASELINE: &str = "baseline.diagnostics.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Note,
+    Warning,
+    Error,
+}
+
+/// A compiler, linker or modpost message found in the build output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+    /// The warning option that triggered it, such as `-Wunused-variable`.
+    pub flag: Option<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+            if let Some(line) = self.line {
+                write!(f, "{}:", line)?;
+            }
+            if let Some(column) = self.column {
+                write!(f, "{}:", column)?;
+            }
+            write!(f, " ")?;
+        }
+        let severity = match self.severity {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}", severity, self.message)?;
+        if let Some(flag) = &self.flag {
+            write!(f, " [{}]", flag)?;
+        }
+        Ok(())
+    }
+}
+
+/// Recognises GCC/Clang (`file:line:col: warning: msg [-Wflag]`), GNU ld and
+/// lld, and modpost messages.
+pub fn parse_line(line: &str) -> Option<Diagnostic> {
+    let line = line.trim_end();
+
+    for (prefix, severity) in [("ERROR: modpost: ", Severity::Error), ("WARNING: modpost: ", Severity::Warning)] {
+        if let Some(message) = line.strip_prefix(prefix) {
+            return Some(Diagnostic {
+                file: None,
+                line: None,
+                column: None,
+                severity,
+                message: message.to_string(),
+                flag: None,
+            });
+        }
+    }
+
+    for (marker, severity) in [
+        (": fatal error: ", Severity::Error),
+        (": error: ", Severity::Error),
+        (": warning: ", Severity::Warning),
+        (": note: ", Severity::Note),
+    ] {
+        let Some(index) = line.find(marker) else {
+            continue;
+        };
+        let location = &line[..index];
+        let (message, flag) = split_flag(&line[index + marker.len()..]);
+        let mut parts = location.split(':');
+        let file = parts.next().unwrap_or_default().trim();
+        let line_number = parts.next().and_then(|p| p.trim().parse().ok());
+        let column = parts.next().and_then(|p| p.trim().parse().ok());
+
+        // `ld: error: ...` and `ld.lld: warning: ...` name the tool, not a file.
+        let is_tool = line_number.is_none() && (is_linker(file) || file == "make");
+        return Some(Diagnostic {
+            file: (!is_tool && !file.is_empty()).then(|| file.to_string()),
+            line: line_number,
+            column,
+            severity,
+            message,
+            flag,
+        });
+    }
+
+    // GNU ld: `foo.c:(.text+0x1c): undefined reference to `bar'`
+    if let Some(index) = line.find(": undefined reference to ") {
+        let file = line[..index].split(":(").next().unwrap_or_default();
+        return Some(Diagnostic {
+            file: Some(file.to_string()),
+            line: None,
+            column: None,
+            severity: Severity::Error,
+            message: line[index + 2..].to_string(),
+            flag: None,
+        });
+    }
+    None
+}
+
+/// Whether `name` is a linker binary, possibly with a path or a
+/// `CROSS_COMPILE` prefix (`aarch64-linux-gnu-ld.bfd`).
+fn is_linker(name: &str) -> bool {
+    let base = Path::new(name).file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let tool = base.rsplit('-').next().unwrap_or_default();
+    matches!(tool, "ld" | "ld.bfd" | "ld.gold" | "ld.lld")
+}
+
+/// Splits a trailing `[-Wflag]` (Clang: `[-Wflag,-Werror]`) off a message.
+fn split_flag(message: &str) -> (String, Option<String>) {
+    if let Some(start) = message.rfind(" [-W") {
+        if message.ends_with(']') {
+            let flag = &message[start + 2..message.len() - 1];
+            let flag = flag.split(',').next().unwrap_or(flag);
+            return (message[..start].to_string(), Some(flag.to_string()));
+        }
+    }
+    (message.to_string(), None)
+}
+
+/// Captures the output of every make stage in one log file per run and
+/// collects the diagnostics found in it.
+pub struct BuildLog {
+    pub path: PathBuf,
+    /// The warning baseline in the same log directory, if there is one.
+    pub baseline: Option<PathBuf>,
+    file: tokio::fs::File,
+    pub diagnostics: Vec<Diagnostic>,
+    tail: Vec<String>,
+}
+
+impl BuildLog {
+    /// Opens `build-<unix time>.log` in `dir`, with `latest.log` pointing to
+    /// it. Runs started in the same second get `build-<time>-<n>.log`. A log
+    /// directory KTP creates ignores itself for Git, so the default one
+    /// inside an in-tree build leaves the checkout clean.
+    pub async fn create(dir: &Path) -> Result<Self> {
+        if !dir.exists() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("Failed to create log directory {:?}", dir))?;
+            tokio::fs::write(dir.join(".gitignore"), "*\n").await?;
+        }
+        let stamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let baseline = Some(dir.join(BASELINE)).filter(|path| path.is_file());
+        let mut attempt = 0;
+        let (path, file) = loop {
+            let name = match attempt {
+                0 => format!("build-{}.log", stamp),
+                n => format!("build-{}-{}.log", stamp, n),
+            };
+            let path = dir.join(name);
+            match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt < 1000 => attempt += 1,
+                Err(e) => return Err(e).with_context(|| format!("Failed to create build log {:?}", path)),
+            }
+        };
+
+        #[cfg(unix)]
+        {
+            let latest = dir.join("latest.log");
+            let _ = std::fs::remove_file(&latest);
+            let _ = std::os::unix::fs::symlink(path.file_name().expect("log file name"), &latest);
+        }
+
+        println!("Logging build output to {:?}", path);
+        Ok(Self { path, baseline, file, diagnostics: Vec::new(), tail: Vec::new() })
+    }
+
+    /// Runs `cmd`, echoing its output to the terminal while appending it to
+    /// the log and parsing diagnostics.
+    pub async fn run(&mut self, mut cmd: TokioCommand, stage: &str) -> Result<ExitStatus> {
+        let header = format!("==> {} ({:?})\n", stage, cmd.as_std());
+        self.file.write_all(header.as_bytes()).await?;
+        self.tail.clear();
+
+        let mut child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to start {}", stage))?;
+        let (tx, mut rx) = mpsc::channel::<(bool, String)>(256);
+        let readers = [
+            tokio::spawn(forward_lines(child.stdout.take().expect("piped stdout"), false, tx.clone())),
+            tokio::spawn(forward_lines(child.stderr.take().expect("piped stderr"), true, tx)),
+        ];
+
+        while let Some((is_stderr, line)) = rx.recv().await {
+            if is_stderr {
+                eprintln!("{}", line);
+            } else {
+                println!("{}", line);
+            }
+            self.file.write_all(line.as_bytes()).await?;
+            self.file.write_all(b"\n").await?;
+            if let Some(diagnostic) = parse_line(&line) {
+                self.diagnostics.push(diagnostic);
+            }
+            if self.tail.len() == 20 {
+                self.tail.remove(0);
+            }
+            self.tail.push(line);
+        }
+        for reader in readers {
+            reader.await.context("Output reader panicked")??;
+        }
+
+        let status = child.wait().await?;
+        self.file.write_all(format!("==> {}: {}\n\n", stage, status).as_bytes()).await?;
+        self.file.flush().await?;
+        Ok(status)
+    }
+
+    /// Notes a stage that ran on the terminal without being captured.
+    pub async fn note(&mut self, message: &str) -> Result<()> {
+        self.file.write_all(format!("==> {}\n\n", message).as_bytes()).await?;
+        Ok(())
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.diagnostics.iter().filter(|d| d.severity == severity).count()
+    }
+
+    /// Writes the diagnostics as JSON next to the log file.
+    pub fn save_diagnostics(&self) -> Result<PathBuf> {
+        let path = self.path.with_extension("diagnostics.json");
+        std::fs::write(&path, serde_json::to_string_pretty(&self.diagnostics)?)
+            .with_context(|| format!("Failed to write {:?}", path))?;
+        Ok(path)
+    }
+
+    /// Makes this run's diagnostics the warning baseline of later runs.
+    pub fn save_baseline(&self) -> Result<PathBuf> {
+        let path = self.path.with_file_name(BASELINE);
+        std::fs::write(&path, serde_json::to_string_pretty(&self.diagnostics)?)
+            .with_context(|| format!("Failed to write {:?}", path))?;
+        Ok(path)
+    }
+
+    /// Prints the first errors and the warning counts per flag. When the
+    /// build failed without a recognisable diagnostic, the last lines of
+    /// output are shown instead.
+    pub fn print_summary(&self, failed: bool) {
+        let errors: Vec<&Diagnostic> = self.diagnostics.iter().filter(|d| d.severity == Severity::Error).collect();
+        let warnings = self.count(Severity::Warning);
+
+        println!();
+        println!("Build summary: {} error(s), {} warning(s); full log in {:?}", errors.len(), warnings, self.path);
+        if !errors.is_empty() {
+            println!("First errors:");
+            for error in errors.iter().take(SUMMARY_ERRORS) {
+                println!("  {}", error);
+            }
+            if errors.len() > SUMMARY_ERRORS {
+                println!("  ... and {} more", errors.len() - SUMMARY_ERRORS);
+            }
+        } else if failed && !self.tail.is_empty() {
+            println!("Last output lines:");
+            for line in &self.tail {
+                println!("  {}", line);
+            }
+        }
+
+        if warnings > 0 {
+            let mut by_flag: BTreeMap<&str, usize> = BTreeMap::new();
+            for warning in self.diagnostics.iter().filter(|d| d.severity == Severity::Warning) {
+                *by_flag.entry(warning.flag.as_deref().unwrap_or("(no flag)")).or_default() += 1;
+            }
+            let mut by_flag: Vec<(&str, usize)> = by_flag.into_iter().collect();
+            by_flag.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+            println!("Warnings by flag:");
+            for (flag, count) in by_flag {
+                println!("  {:>5}  {}", count, flag);
+            }
+        }
+    }
+}
+
+async fn forward_lines<R: AsyncRead + Unpin>(reader: R, is_stderr: bool, tx: mpsc::Sender<(bool, String)>) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf).await? == 0 {
+            return Ok(());
+        }
+        let line = String::from_utf8_lossy(&buf).trim_end_matches(['\n', '\r']).to_string();
+        if tx.send((is_stderr, line)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// A warning with its position stripped, so that code moving around does
+/// not make an old warning look new.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WarningKey {
+    pub file: String,
+    pub flag: String,
+    pub message: String,
+}
+
+impl fmt::Display for WarningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}: ", self.file)?;
+        }
+        write!(f, "{}", self.message)?;
+        if !self.flag.is_empty() {
+            write!(f, " [{}]", self.flag)?;
+        }
+        Ok(())
+    }
+}
+
+/// The warnings of one build, counted per key.
+#[derive(Debug, Default)]
+pub struct WarningSet {
+    pub warnings: BTreeMap<WarningKey, usize>,
+}
+
+impl WarningSet {
+    /// Builds the set from diagnostics. The compiler runs in `build_root`,
+    /// so relative file names are resolved against it; names are then made
+    /// relative to `build_root` (generated files) or `source_root`, so that
+    /// builds in different directories compare equal.
+    pub fn from_diagnostics(diagnostics: &[Diagnostic], source_root: &Path, build_root: &Path) -> Self {
+        let canonical = |dir: &Path| dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        let (source_root, build_root) = (canonical(source_root), canonical(build_root));
+        let mut set = WarningSet::default();
+        for warning in diagnostics.iter().filter(|d| d.severity == Severity::Warning) {
+            let file = match warning.file.as_deref() {
+                Some(file) => {
+                    let path = kbuild::normalize_path(&build_root.join(file));
+                    let relative = path.strip_prefix(&build_root).or_else(|_| path.strip_prefix(&source_root));
+                    relative.unwrap_or(&path).to_string_lossy().into_owned()
+                }
+                None => String::new(),
+            };
+            let key = WarningKey {
+                file,
+                flag: warning.flag.clone().unwrap_or_default(),
+                message: strip_line_refs(&warning.message),
+            };
+            *set.warnings.entry(key).or_default() += 1;
+        }
+        set
+    }
+
+    pub fn load(path: &Path, source_root: &Path, build_root: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let diagnostics: Vec<Diagnostic> =
+            serde_json::from_str(&text).with_context(|| format!("Invalid diagnostics file {:?}", path))?;
+        Ok(Self::from_diagnostics(&diagnostics, source_root, build_root))
+    }
+
+    /// Warnings that occur more often than in `baseline`, and the ones
+    /// that went away, with how many occurrences changed.
+    pub fn diff(&self, baseline: &WarningSet) -> WarningDiff {
+        let count = |set: &WarningSet, key: &WarningKey| set.warnings.get(key).copied().unwrap_or(0);
+        let new = self
+            .warnings
+            .iter()
+            .filter(|(key, n)| **n > count(baseline, key))
+            .map(|(key, n)| (key.clone(), n - count(baseline, key)))
+            .collect();
+        let fixed = baseline
+            .warnings
+            .iter()
+            .filter(|(key, n)| **n > count(self, key))
+            .map(|(key, n)| (key.clone(), n - count(self, key)))
+            .collect();
+        WarningDiff { new, fixed }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WarningDiff {
+    pub new: Vec<(WarningKey, usize)>,
+    pub fixed: Vec<(WarningKey, usize)>,
+}
+
+impl WarningDiff {
+    pub fn new_count(&self) -> usize {
+        self.new.iter().map(|(_, n)| n).sum()
+    }
+
+    pub fn fixed_count(&self) -> usize {
+        self.fixed.iter().map(|(_, n)| n).sum()
+    }
+
+    pub fn print(&self) {
+        for (sign, list) in [("+", &self.new), ("-", &self.fixed)] {
+            for (key, count) in list {
+                let times = if *count > 1 { format!(" (x{})", count) } else { String::new() };
+                println!("  {} {}{}", sign, key, times);
+            }
+        }
+    }
+}
+
+/// Drops `file.h:12` / `file.h:12:3` style positions from a message.
+fn strip_line_refs(message: &str) -> String {
+    let chars: Vec<char> = message.chars().collect();
+    let mut result = String::with_capacity(message.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let after_name = i > 0 && (chars[i - 1].is_ascii_alphanumeric() || chars[i - 1] == '_');
+        if chars[i] == ':' && after_name && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || (chars[i] == ':' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()))) {
+                i += 1;
+            }
+            continue;
+        }
+        result.push(chars[i]);
+        i += 1;
+    }
+    result
+}
+
+/// The warning baseline in the log directory `dir`, or else its most
+/// recent `build-<time>.diagnostics.json`.
+pub fn find_baseline(dir: &Path) -> Option<PathBuf> {
+    let baseline = dir.join(BASELINE);
+    if baseline.is_file() {
+        return Some(baseline);
+    }
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            Some((log_order(name.strip_suffix(".diagnostics.json")?)?, entry.path()))
+        })
+        .max_by_key(|(stamp, _)| *stamp)
+        .map(|(_, path)| path)
+}
+
+/// Sort key of a `build-<time>[-<n>]` log name.
+fn log_order(stem: &str) -> Option<(u64, u64)> {
+    let stamp = stem.strip_prefix("build-")?;
+    Some(match stamp.split_once('-') {
+        Some((secs, n)) => (secs.parse().ok()?, n.parse().ok()?),
+        None => (stamp.parse().ok()?, 0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_line_reads_compiler_messages() {
+        let d = parse_line("kernel/fork.c:123:9: warning: unused variable 'x' [-Wunused-variable]\n").unwrap();
+        assert_eq!(d.file.as_deref(), Some("kernel/fork.c"));
+        assert_eq!((d.line, d.column), (Some(123), Some(9)));
+        assert_eq!(d.severity, Severity::Warning);
+        assert_eq!(d.message, "unused variable 'x'");
+        assert_eq!(d.flag.as_deref(), Some("-Wunused-variable"));
+        assert_eq!(d.to_string(), "kernel/fork.c:123:9: warning: unused variable 'x' [-Wunused-variable]");
+
+        let d = parse_line("drivers/x.c:5:1: error: unused function 'f' [-Werror,-Wunused-function]").unwrap();
+        assert_eq!(d.severity, Severity::Error);
+        assert_eq!(d.flag.as_deref(), Some("-Werror"));
+
+        let d = parse_line("init/main.c:1:10: fatal error: missing.h: No such file or directory").unwrap();
+        assert_eq!(d.severity, Severity::Error);
+        assert_eq!(d.message, "missing.h: No such file or directory");
+        assert_eq!(parse_line("include/linux/x.h:3: note: declared here").unwrap().column, None);
+    }
+
+    #[test]
+    fn parse_line_reads_linker_and_modpost_messages() {
+        let d = parse_line("ld.lld: error: undefined symbol: foo").unwrap();
+        assert_eq!((d.file, d.severity), (None, Severity::Error));
+        assert_eq!(parse_line("x86_64-linux-gnu-ld: warning: creating DT_TEXTREL").unwrap().file, None);
+
+        let d = parse_line("kernel/sys.c:(.text+0x1c): undefined reference to `bar'").unwrap();
+        assert_eq!(d.file.as_deref(), Some("kernel/sys.c"));
+        assert_eq!(d.message, "undefined reference to `bar'");
+
+        let d = parse_line("ERROR: modpost: \"foo\" [drivers/x.ko] undefined!").unwrap();
+        assert_eq!((d.severity, d.file), (Severity::Error, None));
+        assert_eq!(parse_line("WARNING: modpost: missing MODULE_LICENSE()").unwrap().severity, Severity::Warning);
+
+        assert!(parse_line("  CC      kernel/fork.o").is_none());
+        assert!(parse_line("make[1]: Entering directory '/src/linux'").is_none());
+    }
+
+    fn warning(file: &str, message: &str) -> Diagnostic {
+        Diagnostic {
+            file: Some(file.to_string()),
+            line: Some(1),
+            column: None,
+            severity: Severity::Warning,
+            message: message.to_string(),
+            flag: Some("-Wunused-variable".to_string()),
+        }
+    }
+
+    fn files(set: &WarningSet) -> Vec<&str> {
+        set.warnings.keys().map(|key| key.file.as_str()).collect()
+    }
+
+    #[test]
+    fn only_linker_names_count_as_tools() {
+        for tool in ["ld", "ld.bfd", "ld.gold", "ld.lld", "/usr/bin/ld", "aarch64-linux-gnu-ld.bfd"] {
+            assert!(is_linker(tool), "{}", tool);
+        }
+        for file in ["build", "scripts/mod/old", "ld.c", "vmlinux.lds", "world"] {
+            assert!(!is_linker(file), "{}", file);
+        }
+        let d = parse_line("build: warning: ignored").unwrap();
+        assert_eq!(d.file.as_deref(), Some("build"));
+    }
+
+    #[test]
+    fn warning_files_are_relative_to_the_roots() {
+        let (source, build) = (Path::new("/nonexistent/linux"), Path::new("/nonexistent/linux/build"));
+        let set = WarningSet::from_diagnostics(
+            &[
+                warning("../kernel/fork.c", "unused variable 'a'"),
+                warning("/nonexistent/linux/kernel/fork.c", "unused variable 'a'"),
+                warning("include/generated/autoconf.h", "unused variable 'b'"),
+                warning("./init/main.c", "unused variable 'c'"),
+            ],
+            source,
+            build,
+        );
+        assert_eq!(files(&set), vec!["include/generated/autoconf.h", "init/main.c", "kernel/fork.c"]);
+        let fork = set.warnings.iter().find(|(key, _)| key.file == "kernel/fork.c").unwrap();
+        assert_eq!(*fork.1, 2);
+    }
+
+    #[test]
+    fn warning_files_outside_the_roots_stay_distinct() {
+        let (source, build) = (Path::new("/nonexistent/src/linux"), Path::new("/nonexistent/src/linux/out"));
+        let set = WarningSet::from_diagnostics(
+            &[warning("../x.c", "m"), warning("../../x.c", "m"), warning("../../../x.c", "m")],
+            source,
+            build,
+        );
+        assert_eq!(files(&set), vec!["/nonexistent/src/x.c", "/nonexistent/x.c", "x.c"]);
+    }
+
+    #[test]
+    fn diff_ignores_moved_lines() {
+        let root = Path::new("/nonexistent");
+        let mut moved = warning("a.c", "'x' defined but not used, see a.h:12");
+        moved.line = Some(40);
+        let baseline = WarningSet::from_diagnostics(&[warning("a.c", "'x' defined but not used, see a.h:10")], root, root);
+        let current = WarningSet::from_diagnostics(&[moved, warning("b.c", "new")], root, root);
+        let diff = current.diff(&baseline);
+        assert_eq!(diff.new_count(), 1);
+        assert_eq!(diff.new[0].0.file, "b.c");
+        assert_eq!(diff.fixed_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn logs_started_in_the_same_second_do_not_collide() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("ktp-logs");
+        let first = BuildLog::create(&dir).await.unwrap();
+        let second = BuildLog::create(&dir).await.unwrap();
+        let third = BuildLog::create(&dir).await.unwrap();
+        assert_ne!(first.path, second.path);
+        assert_ne!(second.path, third.path);
+        assert_eq!(std::fs::read_to_string(dir.join(".gitignore")).unwrap(), "*\n");
+    }
+
+    #[test]
+    fn find_baseline_prefers_the_saved_baseline_then_the_latest_run() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        assert_eq!(find_baseline(dir), None);
+        for name in ["build-1700000000.diagnostics.json", "build-1700000000-2.diagnostics.json", "build-1700000001.log"] {
+            std::fs::write(dir.join(name), "[]").unwrap();
+        }
+        assert_eq!(find_baseline(dir), Some(dir.join("build-1700000000-2.diagnostics.json")));
+        std::fs::write(dir.join(BASELINE), "[]").unwrap();
+        assert_eq!(find_baseline(dir), Some(dir.join(BASELINE)));
+    }
+
+    #[test]
+    fn log_order_sorts_retries_after_the_first_run() {
+        assert_eq!(log_order("build-1700000000"), Some((1700000000, 0)));
+        assert_eq!(log_order("build-1700000000-2"), Some((1700000000, 2)));
+        assert_eq!(log_order("latest"), None);
+        assert!(log_order("build-1700000000-2") < log_order("build-1700000001"));
+    }
+}
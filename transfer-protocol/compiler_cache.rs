@@ -0,0 +1,209 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command as TokioCommand;
+
+/// Average compile cost of the last build that had misses, kept in the
+/// output directory to estimate the time later cache hits save.
+const COST_RECORD: &str = ".ktp-cache.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheTool {
+    #[default]
+    Ccache,
+    Sccache,
+}
+
+impl CacheTool {
+    pub fn program(self) -> &'static str {
+        match self {
+            CacheTool::Ccache => "ccache",
+            CacheTool::Sccache => "sccache",
+        }
+    }
+}
+
+/// Compiler cache wrapping `CC` and `HOSTCC`, configured per toolchain profile.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub tool: CacheTool,
+    /// Cache directory (`CCACHE_DIR` / `SCCACHE_DIR`); the tool's default if unset.
+    pub dir: Option<PathBuf>,
+    /// Maximum size such as `"20G"` (`CCACHE_MAXSIZE` / `SCCACHE_CACHE_SIZE`).
+    pub max_size: Option<String>,
+}
+
+impl CacheConfig {
+    /// Environment the wrapped compilers and the statistics queries share.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let (dir_var, size_var) = match self.tool {
+            CacheTool::Ccache => ("CCACHE_DIR", "CCACHE_MAXSIZE"),
+            CacheTool::Sccache => ("SCCACHE_DIR", "SCCACHE_CACHE_SIZE"),
+        };
+        let mut env = Vec::new();
+        if let Some(dir) = &self.dir {
+            env.push((dir_var.to_string(), dir.display().to_string()));
+        }
+        if let Some(size) = &self.max_size {
+            env.push((size_var.to_string(), size.clone()));
+        }
+        env
+    }
+
+    /// Current hit and miss counters, or `None` if the tool cannot report them.
+    pub async fn stats(&self) -> Option<CacheStats> {
+        let mut cmd = TokioCommand::new(self.tool.program());
+        cmd.envs(self.env());
+        match self.tool {
+            CacheTool::Ccache => cmd.arg("--print-stats"),
+            CacheTool::Sccache => cmd.arg("--show-stats").arg("--stats-format=json"),
+        };
+        let output = cmd.output().await.ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let text = String::from_utf8_lossy(&output.stdout);
+        match self.tool {
+            CacheTool::Ccache => Some(parse_ccache_stats(&text)),
+            CacheTool::Sccache => parse_sccache_stats(&text),
+        }
+    }
+}
+
+pub fn parse_cache_tool(s: &str) -> Result<CacheTool, String> {
+    match s {
+        "ccache" => Ok(CacheTool::Ccache),
+        "sccache" => Ok(CacheTool::Sccache),
+        other => Err(format!("unknown compiler cache '{}' (expected ccache or sccache)", other)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn since(self, before: CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits.saturating_sub(before.hits),
+            misses: self.misses.saturating_sub(before.misses),
+        }
+    }
+
+    pub fn hit_rate(self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 * 100.0 / total as f64)
+    }
+}
+
+/// `ccache --print-stats` prints one `key<TAB>value` pair per line.
+fn parse_ccache_stats(text: &str) -> CacheStats {
+    let mut stats = CacheStats::default();
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once('\t') else {
+            continue;
+        };
+        let value: u64 = value.trim().parse().unwrap_or(0);
+        match key {
+            "direct_cache_hit" | "preprocessed_cache_hit" | "remote_cache_hit" => stats.hits += value,
+            "cache_miss" => stats.misses += value,
+            _ => {}
+        }
+    }
+    stats
+}
+
+fn parse_sccache_stats(text: &str) -> Option<CacheStats> {
+    let json: serde_json::Value = serde_json::from_str(text).ok()?;
+    let count = |name: &str| -> u64 {
+        json["stats"][name]["counts"]
+            .as_object()
+            .map_or(0, |counts| counts.values().filter_map(|v| v.as_u64()).sum())
+    };
+    Some(CacheStats { hits: count("cache_hits"), misses: count("cache_misses") })
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CostRecord {
+    /// Wall-clock seconds one compile occupies a job slot.
+    seconds_per_compile: f64,
+}
+
+/// Prints the hit rate of a build and an estimate of the time its hits
+/// saved. A miss is assumed to cost what misses cost on average in this
+/// build (or the last one that had any).
+pub fn report(stats: CacheStats, elapsed: Duration, jobs: usize, output_dir: &Path) -> Result<()> {
+    let Some(rate) = stats.hit_rate() else {
+        println!("Compiler cache: no compilations went through the cache.");
+        return Ok(());
+    };
+    println!("Compiler cache: {} hits, {} misses ({:.1}% hit rate)", stats.hits, stats.misses, rate);
+
+    let path = output_dir.join(COST_RECORD);
+    let cost = if stats.misses > 0 {
+        let seconds_per_compile = elapsed.as_secs_f64() * jobs as f64 / stats.misses as f64;
+        let record = CostRecord { seconds_per_compile };
+        std::fs::write(&path, serde_json::to_string_pretty(&record)?)
+            .with_context(|| format!("Failed to write {:?}", path))?;
+        Some(seconds_per_compile)
+    } else {
+        std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<CostRecord>(&text).ok())
+            .map(|record| record.seconds_per_compile)
+    };
+    if let Some(cost) = cost.filter(|_| stats.hits > 0) {
+        let saved = stats.hits as f64 * cost / jobs.max(1) as f64;
+        println!("Compiler cache saved about {:.0}s of build time.", saved);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ccache_hits_add_up_across_kinds() {
+        let stats = parse_ccache_stats(
+            "stats_updated_timestamp\t1717000000\n\
+             direct_cache_hit\t40\n\
+             preprocessed_cache_hit\t8\n\
+             remote_cache_hit\t2\n\
+             cache_miss\t50\n\
+             files_in_cache\t1234\n\
+             not a stat line\n",
+        );
+        assert_eq!((stats.hits, stats.misses), (50, 50));
+        assert_eq!(stats.hit_rate(), Some(50.0));
+        assert_eq!(parse_ccache_stats("").hit_rate(), None);
+    }
+
+    #[test]
+    fn sccache_counts_are_summed_per_language() {
+        let stats = parse_sccache_stats(
+            r#"{"stats": {
+                "cache_hits": {"counts": {"C/C++": 30, "Rust": 2}, "adv_counts": {}},
+                "cache_misses": {"counts": {"C/C++": 8}, "adv_counts": {}},
+                "compile_requests": 40
+            }}"#,
+        )
+        .unwrap();
+        assert_eq!((stats.hits, stats.misses), (32, 8));
+        assert_eq!(parse_sccache_stats(r#"{"stats": {}}"#).map(|s| (s.hits, s.misses)), Some((0, 0)));
+        assert!(parse_sccache_stats("sccache: error: couldn't connect to server").is_none());
+    }
+
+    #[test]
+    fn since_never_goes_negative() {
+        let before = CacheStats { hits: 10, misses: 5 };
+        let after = CacheStats { hits: 12, misses: 3 };
+        let delta = after.since(before);
+        assert_eq!((delta.hits, delta.misses), (2, 0));
+    }
+}
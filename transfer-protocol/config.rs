@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::compiler_cache::CacheConfig;
+use crate::ktp_mk::KtpMkConfig;
+use crate::packaging::PackageFormat;
+
+/// KTP configuration, read from `--config`, `$KTP_CONFIG` or
+/// `$XDG_CONFIG_HOME/ktp/config.toml`. Every section is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct KtpConfig {
+    pub http: HttpConfig,
+    pub tls: TlsConfig,
+    /// Named cross-compilation profiles, selected with `--toolchain <name>`.
+    pub toolchain: BTreeMap<String, ToolchainProfile>,
+    pub package: PackageConfig,
+    /// Trust policy for running a tree's `KTP.mk`.
+    pub ktp_mk: KtpMkConfig,
+    /// The file this configuration was read from, or the default location
+    /// when there is no config file.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Look up credentials in `~/.netrc` (or `netrc_file`) when nothing else matches.
+    pub netrc: bool,
+    pub netrc_file: Option<PathBuf>,
+    pub credentials: Vec<HostCredentials>,
+}
+
+/// TLS settings shared by the HTTP backend, GitFetcher and libgit2.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// Extra PEM CA certificates trusted in addition to the built-in roots.
+    pub ca_certs: Vec<PathBuf>,
+    /// Client certificate: a PKCS#12 bundle (`.p12`/`.pfx`) or otherwise a
+    /// PEM chain (with `client_key`). Presented by the HTTP backend and
+    /// GitFetcher only: libgit2 has no way to present one, so Git HTTPS
+    /// remotes are refused while it is set.
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub client_cert_password: Option<String>,
+    pub client_cert_password_env: Option<String>,
+    /// Server public key pins in curl's `sha256//<base64>` form.
+    pub pins: Vec<String>,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("ca_certs", &self.ca_certs)
+            .field("client_cert", &self.client_cert)
+            .field("client_key", &self.client_key)
+            .field("client_cert_password", &self.client_cert_password.as_ref().map(|_| "<redacted>"))
+            .field("client_cert_password_env", &self.client_cert_password_env)
+            .field("pins", &self.pins)
+            .finish()
+    }
+}
+
+impl TlsConfig {
+    pub fn is_default(&self) -> bool {
+        self.ca_certs.is_empty() && self.client_cert.is_none() && self.pins.is_empty()
+    }
+
+    pub fn client_cert_password(&self) -> Option<String> {
+        self.client_cert_password.clone().or_else(|| {
+            self.client_cert_password_env.as_ref().and_then(|var| std::env::var(var).ok())
+        })
+    }
+}
+
+/// How a kernel is compiled: target architecture, cross prefix or LLVM,
+/// and compiler overrides. Applied to every make invocation.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ToolchainProfile {
+    /// Profile name, filled in from the table key.
+    #[serde(skip)]
+    pub name: String,
+    pub arch: Option<String>,
+    pub cross_compile: Option<String>,
+    /// `true` for `LLVM=1`, or a version suffix (`"-17"`) or prefix directory (`"/opt/llvm/bin/"`).
+    pub llvm: Option<LlvmSetting>,
+    pub cc: Option<String>,
+    pub ld: Option<String>,
+    /// Extra make variables for this profile, e.g. `KCFLAGS`.
+    pub make_vars: BTreeMap<String, String>,
+    /// Default out-of-tree build directory (`O=`) for this profile.
+    pub build_dir: Option<PathBuf>,
+    /// Compiler cache for this profile's builds.
+    pub cache: Option<CacheConfig>,
+}
+
+/// Distribution packages built after compiling. Binary package names follow
+/// the kernel release (`linux-image-<release>`, `kernel-<release>`), so they
+/// are named through `local_version` or `CONFIG_LOCALVERSION`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PackageConfig {
+    /// Formats built on every compile; `--package` overrides this.
+    pub formats: Vec<PackageFormat>,
+    /// Package revision: Debian versions become `<release>-<revision>`, RPMs
+    /// use it as their Release (numeric only).
+    pub revision: Option<String>,
+    /// `Name <email>`, recorded as the Debian maintainer and RPM packager.
+    pub maintainer: Option<String>,
+    /// Debian changelog distribution (`KDEB_CHANGELOG_DIST`).
+    pub distribution: Option<String>,
+    /// Debian source package name (`KDEB_SOURCENAME`).
+    pub source_name: Option<String>,
+    /// Suffix appended to the kernel release (`LOCALVERSION=`) in every make
+    /// stage, so the release and the binary package names agree.
+    pub local_version: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum LlvmSetting {
+    Enabled(bool),
+    Value(String),
+}
+
+impl LlvmSetting {
+    /// The `LLVM=` value passed to make, if LLVM is enabled.
+    pub fn make_value(&self) -> Option<String> {
+        match self {
+            LlvmSetting::Enabled(true) => Some("1".to_string()),
+            LlvmSetting::Enabled(false) => None,
+            LlvmSetting::Value(value) => Some(value.clone()),
+        }
+    }
+}
+
+/// Credentials for a single host. Secrets can be given inline or read from
+/// an environment variable so that they do not have to live in the file.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HostCredentials {
+    pub host: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_env: Option<String>,
+    pub token: Option<String>,
+    pub token_env: Option<String>,
+}
+
+impl fmt::Debug for HostCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostCredentials")
+            .field("host", &self.host)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("password_env", &self.password_env)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("token_env", &self.token_env)
+            .finish()
+    }
+}
+
+impl HostCredentials {
+    pub fn password(&self) -> Option<String> {
+        self.password
+            .clone()
+            .or_else(|| self.password_env.as_ref().and_then(|var| std::env::var(var).ok()))
+    }
+
+    pub fn token(&self) -> Option<String> {
+        self.token
+            .clone()
+            .or_else(|| self.token_env.as_ref().and_then(|var| std::env::var(var).ok()))
+    }
+}
+
+impl HttpConfig {
+    pub fn credentials_for(&self, host: &str) -> Option<&HostCredentials> {
+        self.credentials.iter().find(|c| c.host.eq_ignore_ascii_case(host))
+    }
+}
+
+impl KtpConfig {
+    /// Loads the configuration. An explicitly given path must exist; the
+    /// default location is optional and yields an empty config when absent.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Self::default_path() {
+                Some(path) if path.exists() => path,
+                path => return Ok(Self { path, ..Self::default() }),
+            },
+        };
+
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        let config: Self = toml::from_str(&text).with_context(|| format!("Invalid config file {:?}", path))?;
+        Ok(Self { path: Some(path), ..config })
+    }
+
+    pub fn toolchain(&self, name: &str) -> Result<ToolchainProfile> {
+        let mut profile = self.toolchain.get(name).cloned().with_context(|| {
+            let known: Vec<&str> = self.toolchain.keys().map(String::as_str).collect();
+            format!("Unknown toolchain profile '{}' (configured: {})", name, known.join(", "))
+        })?;
+        profile.name = name.to_string();
+        Ok(profile)
+    }
+
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("KTP_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(base.join("ktp").join("config.toml"))
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// The value of one symbol in a `.config` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue {
+    Yes,
+    Module,
+    /// `# CONFIG_X is not set`; also what an absent symbol means.
+    NotSet,
+    /// Strings (with their quotes), decimal and hex values, as written.
+    Value(String),
+}
+
+impl ConfigValue {
+    /// Parses the right-hand side of `CONFIG_X=...`. Bare words that are not
+    /// numbers are quoted, so `--set CONFIG_LOCALVERSION=-test` does what
+    /// one expects.
+    pub fn parse(raw: &str) -> Self {
+        match raw {
+            "y" => ConfigValue::Yes,
+            "m" => ConfigValue::Module,
+            "n" => ConfigValue::NotSet,
+            raw if raw.starts_with('"') || is_number(raw) => ConfigValue::Value(raw.to_string()),
+            raw => ConfigValue::Value(format!("\"{}\"", raw.replace('\\', "\\\\").replace('"', "\\\""))),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        *self != ConfigValue::NotSet
+    }
+}
+
+impl fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigValue::Yes => write!(f, "y"),
+            ConfigValue::Module => write!(f, "m"),
+            ConfigValue::NotSet => write!(f, "is not set"),
+            ConfigValue::Value(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+fn is_number(raw: &str) -> bool {
+    if let Some(hex) = raw.strip_prefix("0x").or_else(|| raw.strip_prefix("0X")) {
+        return !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit());
+    }
+    let digits = raw.strip_prefix('-').unwrap_or(raw);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Symbol names are kept without the `CONFIG_` prefix.
+pub fn symbol_name(name: &str) -> &str {
+    name.strip_prefix("CONFIG_").unwrap_or(name)
+}
+
+/// A parsed `.config` or config fragment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DotConfig {
+    pub symbols: BTreeMap<String, ConfigValue>,
+}
+
+/// A symbol whose value a fragment replaced, in the base config or an
+/// earlier fragment, as `merge_config.sh` reports it.
+#[derive(Debug, Clone)]
+pub struct Redefinition {
+    pub name: String,
+    pub previous: ConfigValue,
+    pub value: ConfigValue,
+}
+
+/// A requested option that `olddefconfig` did not keep.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub name: String,
+    pub requested: ConfigValue,
+    pub actual: ConfigValue,
+}
+
+impl DotConfig {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut config = DotConfig::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if let Some(rest) = line.strip_prefix("# CONFIG_") {
+                if let Some(name) = rest.strip_suffix(" is not set") {
+                    config.symbols.insert(name.to_string(), ConfigValue::NotSet);
+                }
+                continue;
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line
+                .strip_prefix("CONFIG_")
+                .and_then(|rest| rest.split_once('='))
+                .with_context(|| format!("line {}: expected CONFIG_NAME=value, got '{}'", number + 1, line))?;
+            config.symbols.insert(name.to_string(), ConfigValue::parse(value));
+        }
+        Ok(config)
+    }
+
+    /// Reads a `.config`, transparently decompressing gzip (`/proc/config.gz`).
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let text = if bytes.starts_with(&[0x1f, 0x8b]) {
+            let mut text = String::new();
+            flate2::read::MultiGzDecoder::new(bytes.as_slice())
+                .read_to_string(&mut text)
+                .with_context(|| format!("Failed to decompress {:?}", path))?;
+            text
+        } else {
+            String::from_utf8(bytes).with_context(|| format!("{:?} is not a text file", path))?
+        };
+        Self::parse(&text).with_context(|| format!("Invalid config file {:?}", path))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_string()).with_context(|| format!("Failed to write {:?}", path))
+    }
+
+    /// The value of `name` (with or without `CONFIG_`); absent symbols are not set.
+    pub fn get(&self, name: &str) -> &ConfigValue {
+        self.symbols.get(symbol_name(name)).unwrap_or(&ConfigValue::NotSet)
+    }
+
+    pub fn set(&mut self, name: &str, value: ConfigValue) -> Option<ConfigValue> {
+        self.symbols.insert(symbol_name(name).to_string(), value)
+    }
+
+    /// Applies `fragment` on top of this config, returning the symbols whose
+    /// value changed.
+    pub fn merge(&mut self, fragment: &DotConfig) -> Vec<Redefinition> {
+        let mut redefined = Vec::new();
+        for (name, value) in &fragment.symbols {
+            if let Some(previous) = self.symbols.insert(name.clone(), value.clone()) {
+                if previous != *value {
+                    redefined.push(Redefinition { name: name.clone(), previous, value: value.clone() });
+                }
+            }
+        }
+        redefined
+    }
+
+    /// Requested symbols whose value in `self` differs from `requested`.
+    pub fn mismatches(&self, requested: &DotConfig) -> Vec<Mismatch> {
+        requested
+            .symbols
+            .iter()
+            .filter(|(name, value)| self.get(name) != *value)
+            .map(|(name, value)| Mismatch {
+                name: name.clone(),
+                requested: value.clone(),
+                actual: self.get(name).clone(),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for DotConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.symbols {
+            match value {
+                ConfigValue::NotSet => writeln!(f, "# CONFIG_{} is not set", name)?,
+                value => writeln!(f, "CONFIG_{}={}", name, value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a `CONFIG_NAME=value` assignment from the command line.
+pub fn parse_assignment(s: &str) -> Result<(String, ConfigValue), String> {
+    match s.split_once('=') {
+        Some((name, value)) if is_symbol(symbol_name(name)) => {
+            Ok((symbol_name(name).to_string(), ConfigValue::parse(value)))
+        }
+        _ => Err(format!("expected CONFIG_NAME=value, got '{}'", s)),
+    }
+}
+
+/// Parses a symbol name to unset from the command line.
+pub fn parse_symbol(s: &str) -> Result<String, String> {
+    if is_symbol(symbol_name(s)) {
+        Ok(symbol_name(s).to_string())
+    } else {
+        Err(format!("'{}' is not a config symbol name", s))
+    }
+}
+
+fn is_symbol(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Differences between two configurations. Absent symbols count as not set.
+#[derive(Debug, Default, Serialize)]
+pub struct ConfigDiff {
+    pub added: BTreeMap<String, String>,
+    pub removed: BTreeMap<String, String>,
+    pub changed: BTreeMap<String, ValueChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValueChange {
+    pub old: String,
+    pub new: String,
+}
+
+impl ConfigDiff {
+    pub fn between(old: &DotConfig, new: &DotConfig) -> Self {
+        let mut diff = ConfigDiff::default();
+        let names: BTreeSet<&String> = old.symbols.keys().chain(new.symbols.keys()).collect();
+        for name in names {
+            let key = format!("CONFIG_{}", name);
+            match (old.get(name), new.get(name)) {
+                (a, b) if a == b => {}
+                (ConfigValue::NotSet, b) => {
+                    diff.added.insert(key, b.to_string());
+                }
+                (a, ConfigValue::NotSet) => {
+                    diff.removed.insert(key, a.to_string());
+                }
+                (a, b) => {
+                    diff.changed.insert(key, ValueChange { old: a.to_string(), new: b.to_string() });
+                }
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn print(&self) {
+        if self.is_empty() {
+            println!("No differences.");
+            return;
+        }
+        if !self.added.is_empty() {
+            println!("Added ({}):", self.added.len());
+            for (name, value) in &self.added {
+                println!("  + {}={}", name, value);
+            }
+        }
+        if !self.removed.is_empty() {
+            println!("Removed ({}):", self.removed.len());
+            for (name, value) in &self.removed {
+                println!("  - {}={}", name, value);
+            }
+        }
+        if !self.changed.is_empty() {
+            println!("Changed ({}):", self.changed.len());
+            for (name, change) in &self.changed {
+                println!("  ~ {} {} -> {}", name, change.old, change.new);
+            }
+        }
+    }
+}
+
+/// Resolves a config argument: a build or source directory means its `.config`.
+pub fn config_path(path: &Path) -> PathBuf {
+    if path.is_dir() {
+        path.join(".config")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_values_and_not_set_lines() {
+        let config = DotConfig::parse(
+            "#\n# Automatically generated\nCONFIG_A=y\nCONFIG_B=m\n# CONFIG_C is not set\nCONFIG_S=\"x y\"\nCONFIG_H=0x10\n",
+        )
+        .unwrap();
+        assert_eq!(config.get("A"), &ConfigValue::Yes);
+        assert_eq!(config.get("CONFIG_B"), &ConfigValue::Module);
+        assert_eq!(config.get("C"), &ConfigValue::NotSet);
+        assert!(config.symbols.contains_key("C"));
+        assert_eq!(config.get("S"), &ConfigValue::Value("\"x y\"".to_string()));
+        assert_eq!(config.get("H"), &ConfigValue::Value("0x10".to_string()));
+        assert_eq!(config.get("MISSING"), &ConfigValue::NotSet);
+        assert_eq!(DotConfig::parse(&config.to_string()).unwrap(), config);
+
+        assert!(DotConfig::parse("A=y\n").is_err());
+    }
+
+    #[test]
+    fn bare_words_are_quoted() {
+        assert_eq!(ConfigValue::parse("-test"), ConfigValue::Value("\"-test\"".to_string()));
+        assert_eq!(ConfigValue::parse("-1"), ConfigValue::Value("-1".to_string()));
+        assert_eq!(ConfigValue::parse("n"), ConfigValue::NotSet);
+    }
+
+    #[test]
+    fn merge_reports_changed_values_only() {
+        let mut base = DotConfig::parse("CONFIG_A=y\nCONFIG_B=m\n# CONFIG_C is not set\n").unwrap();
+        let fragment = DotConfig::parse("CONFIG_A=y\nCONFIG_B=y\nCONFIG_C=m\nCONFIG_D=y\n").unwrap();
+        let redefined = base.merge(&fragment);
+        let names: Vec<&str> = redefined.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["B", "C"]);
+        assert_eq!(redefined[1].previous, ConfigValue::NotSet);
+        assert_eq!(redefined[1].value, ConfigValue::Module);
+        assert_eq!(base.get("D"), &ConfigValue::Yes);
+    }
+
+    #[test]
+    fn mismatches_list_what_was_not_kept() {
+        let requested = DotConfig::parse("CONFIG_A=y\nCONFIG_B=y\n# CONFIG_C is not set\nCONFIG_D=m\n").unwrap();
+        let result = DotConfig::parse("CONFIG_A=y\nCONFIG_B=m\nCONFIG_X=y\n").unwrap();
+        let mismatches = result.mismatches(&requested);
+        let found: Vec<(&str, &ConfigValue)> = mismatches.iter().map(|m| (m.name.as_str(), &m.actual)).collect();
+        assert_eq!(found, [("B", &ConfigValue::Module), ("D", &ConfigValue::NotSet)]);
+    }
+
+    #[test]
+    fn diff_lists_added_removed_and_changed_symbols() {
+        let old = DotConfig::parse("CONFIG_A=y\nCONFIG_B=m\nCONFIG_C=y\n# CONFIG_D is not set\nCONFIG_E=n\n").unwrap();
+        let new = DotConfig::parse("CONFIG_A=y\nCONFIG_B=y\nCONFIG_F=\"x\"\n").unwrap();
+        let diff = ConfigDiff::between(&old, &new);
+
+        assert_eq!(diff.added.iter().collect::<Vec<_>>(), [(&"CONFIG_F".to_string(), &"\"x\"".to_string())]);
+        assert_eq!(diff.removed.iter().collect::<Vec<_>>(), [(&"CONFIG_C".to_string(), &"y".to_string())]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed["CONFIG_B"].old, "m");
+        assert_eq!(diff.changed["CONFIG_B"].new, "y");
+        // `=n`, `is not set` and absent all mean the same thing.
+        assert!(!diff.added.contains_key("CONFIG_D") && !diff.removed.contains_key("CONFIG_E"));
+        assert!(ConfigDiff::between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn diff_serializes_as_three_maps() {
+        let old = DotConfig::parse("CONFIG_A=m\nCONFIG_B=y\n").unwrap();
+        let new = DotConfig::parse("CONFIG_A=y\nCONFIG_C=m\n").unwrap();
+        let json = serde_json::to_value(ConfigDiff::between(&old, &new)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "added": { "CONFIG_C": "m" },
+                "removed": { "CONFIG_B": "y" },
+                "changed": { "CONFIG_A": { "old": "m", "new": "y" } },
+            })
+        );
+    }
+}
@@ -0,0 +1,427 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use anyhow::{Context, Result};
+use openssl::sha::Sha256;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Unpack a tarball while it is being downloaded instead of after.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// Leading path components dropped from every entry (`1` turns
+    /// `linux-6.9/Makefile` into `Makefile`).
+    pub strip_components: usize,
+    /// Also write the compressed archive next to the extracted tree.
+    pub keep_archive: bool,
+}
+
+/// Per-download settings shared by the HTTP, FTP and SSH backends.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    pub extract: Option<ExtractOptions>,
+    /// Expected SHA-256 of the downloaded (compressed) stream, in hex.
+    pub sha256: Option<String>,
+}
+
+pub struct DownloadSummary {
+    pub sha256: String,
+    pub bytes: u64,
+    /// Number of unpacked entries, when extracting.
+    pub extracted: Option<usize>,
+}
+
+/// Single-pass destination for a downloaded byte stream. Every chunk is
+/// hashed, optionally written to the archive file and optionally fed to a
+/// background tar extractor. Extraction goes to a staging directory that is
+/// only merged into the destination once the checksum has been verified.
+pub struct DownloadSink {
+    dest: PathBuf,
+    hasher: Sha256,
+    bytes: u64,
+    archive: Option<(PathBuf, tokio::fs::File)>,
+    extractor: Option<Extractor>,
+    expected_sha256: Option<String>,
+}
+
+struct Extractor {
+    tx: mpsc::Sender<Vec<u8>>,
+    worker: JoinHandle<Result<usize>>,
+    staging: PathBuf,
+}
+
+impl DownloadSink {
+    pub async fn create(dest: &Path, file_name: &str, opts: &DownloadOptions) -> Result<Self> {
+        tokio::fs::create_dir_all(dest).await?;
+        let extract = opts.extract.as_ref();
+
+        let archive = if extract.is_none_or(|e| e.keep_archive) {
+            let path = dest.join(file_name);
+            let file = tokio::fs::File::create(&path)
+                .await
+                .with_context(|| format!("Failed to create {:?}", path))?;
+            Some((path, file))
+        } else {
+            None
+        };
+
+        let extractor = match extract {
+            Some(opts) => {
+                let staging = dest.join(format!(".ktp-extract-{}", std::process::id()));
+                tokio::fs::create_dir_all(&staging).await?;
+                let (tx, rx) = mpsc::channel(16);
+                let strip = opts.strip_components;
+                let root = staging.clone();
+                let worker = tokio::task::spawn_blocking(move || unpack_stream(rx, &root, strip));
+                Some(Extractor { tx, worker, staging })
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            dest: dest.to_path_buf(),
+            hasher: Sha256::new(),
+            bytes: 0,
+            archive,
+            extractor,
+            expected_sha256: opts.sha256.as_deref().map(str::to_ascii_lowercase),
+        })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.hasher.update(chunk);
+        self.bytes += chunk.len() as u64;
+
+        if let Some((_, file)) = &mut self.archive {
+            file.write_all(chunk).await?;
+        }
+        if let Some(extractor) = &self.extractor {
+            if extractor.tx.send(chunk.to_vec()).await.is_err() {
+                // The extractor only hangs up early when it failed.
+                let extractor = self.extractor.take().expect("extractor present");
+                let err = extractor.join().await.err().unwrap_or_else(|| anyhow::anyhow!("Extractor stopped early"));
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Completes the download: waits for extraction, verifies the checksum
+    /// and moves the extracted tree into place.
+    pub async fn finish(mut self) -> Result<DownloadSummary> {
+        if let Some((_, file)) = &mut self.archive {
+            file.flush().await?;
+        }
+        let extracted = match self.extractor.take() {
+            Some(extractor) => Some((extractor.staging.clone(), extractor.join().await?)),
+            None => None,
+        };
+
+        let sha256 = hex_digest(&self.hasher.clone().finish());
+        if let Some(expected) = &self.expected_sha256 {
+            if expected != &sha256 {
+                if let Some((staging, _)) = &extracted {
+                    let _ = tokio::fs::remove_dir_all(staging).await;
+                }
+                if let Some((path, _)) = &self.archive {
+                    let _ = tokio::fs::remove_file(path).await;
+                }
+                anyhow::bail!("SHA-256 mismatch: expected {}, got {}", expected, sha256);
+            }
+            println!("SHA-256 verified: {}", sha256);
+        }
+
+        let extracted = match extracted {
+            Some((staging, count)) => {
+                let dest = self.dest.clone();
+                tokio::task::spawn_blocking(move || merge_into(&staging, &dest)).await??;
+                Some(count)
+            }
+            None => None,
+        };
+
+        Ok(DownloadSummary { sha256, bytes: self.bytes, extracted })
+    }
+
+    /// Finishes the sink after a transfer: on success see [`Self::finish`],
+    /// on failure the partial download is discarded and the error returned.
+    pub async fn complete<E: From<anyhow::Error>>(self, result: Result<(), E>) -> Result<DownloadSummary, E> {
+        match result {
+            Ok(()) => Ok(self.finish().await?),
+            Err(e) => {
+                self.abort().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Discards a failed download, including any partially extracted files.
+    pub async fn abort(mut self) {
+        if let Some(extractor) = self.extractor.take() {
+            let staging = extractor.staging.clone();
+            let _ = extractor.join().await;
+            let _ = tokio::fs::remove_dir_all(staging).await;
+        }
+        if let Some((path, file)) = self.archive.take() {
+            drop(file);
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+}
+
+impl Extractor {
+    async fn join(self) -> Result<usize> {
+        drop(self.tx);
+        let result = self.worker.await.context("Extractor task panicked")?;
+        if result.is_err() {
+            let _ = tokio::fs::remove_dir_all(&self.staging).await;
+        }
+        result
+    }
+}
+
+pub fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Blocking `Read` over the chunks sent by [`DownloadSink::write`].
+struct ChannelReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Detects the compression from the stream's magic bytes and unpacks the tar inside.
+fn unpack_stream(rx: mpsc::Receiver<Vec<u8>>, root: &Path, strip: usize) -> Result<usize> {
+    let mut reader = BufReader::with_capacity(1 << 16, ChannelReader { rx, chunk: Vec::new(), pos: 0 });
+    let magic = reader.fill_buf()?.to_vec();
+
+    let decoder: Box<dyn Read> = if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::bufread::MultiGzDecoder::new(reader))
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Box::new(xz2::bufread::XzDecoder::new_multi_decoder(reader))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::stream::read::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    };
+
+    let mut archive = tar::Archive::new(decoder);
+    let count = unpack_entries(&mut archive, root, strip)?;
+    // Drain trailing padding so the whole stream is decompressed and checked.
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    Ok(count)
+}
+
+fn unpack_entries<R: Read>(archive: &mut tar::Archive<R>, root: &Path, strip: usize) -> Result<usize> {
+    let root = root.canonicalize()?;
+    let mut count = 0;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        if kind.is_pax_global_extensions() || kind.is_pax_local_extensions() {
+            continue;
+        }
+
+        let path = entry.path()?.into_owned();
+        let Some(rel) = strip_path(&path, strip)? else {
+            continue;
+        };
+        let target = root.join(&rel);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+            if !parent.canonicalize()?.starts_with(&root) {
+                anyhow::bail!("Archive entry {:?} escapes the destination directory", path);
+            }
+        }
+
+        if kind.is_hard_link() {
+            let link = entry.link_name()?.context("Hard link entry without a target")?.into_owned();
+            let link_rel = strip_path(&link, strip)?
+                .with_context(|| format!("Hard link target {:?} is stripped away", link))?;
+            let source = root.join(link_rel);
+            // Only link to regular files this archive already put under `root`.
+            let inside = source.parent().and_then(|p| p.canonicalize().ok()).is_some_and(|p| p.starts_with(&root));
+            let regular = fs::symlink_metadata(&source).is_ok_and(|m| m.is_file());
+            if !inside || !regular {
+                anyhow::bail!("Hard link {:?} points outside the extracted files: {:?}", path, link);
+            }
+            fs::hard_link(source, &target)?;
+        } else {
+            entry
+                .unpack(&target)
+                .with_context(|| format!("Failed to unpack {:?}", path))?;
+        }
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Drops `strip` leading components; rejects absolute paths and `..`.
+fn strip_path(path: &Path, strip: usize) -> Result<Option<PathBuf>> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part),
+            Component::CurDir => {}
+            _ => anyhow::bail!("Refusing unsafe archive path {:?}", path),
+        }
+    }
+    if parts.len() <= strip {
+        return Ok(None);
+    }
+    Ok(Some(parts[strip..].iter().collect()))
+}
+
+/// Moves everything in `src` into `dst`, merging directories and replacing
+/// files, then removes `src`.
+fn merge_into(src: &Path, dst: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+        match fs::symlink_metadata(&target) {
+            Ok(meta) if meta.is_dir() && is_dir => merge_into(&entry.path(), &target)?,
+            Ok(meta) => {
+                if meta.is_dir() {
+                    fs::remove_dir_all(&target)?;
+                } else {
+                    fs::remove_file(&target)?;
+                }
+                fs::rename(entry.path(), &target)?;
+            }
+            Err(_) => fs::rename(entry.path(), &target)?,
+        }
+    }
+    fs::remove_dir(src)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(builder: &mut tar::Builder<Vec<u8>>, kind: tar::EntryType, path: &str, link: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        // `set_path` refuses `..`, so write the raw name like a hostile archive would.
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        if !link.is_empty() {
+            header.set_link_name(link).unwrap();
+        }
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    fn unpack(root: &Path, build: impl FnOnce(&mut tar::Builder<Vec<u8>>)) -> Result<usize> {
+        let mut builder = tar::Builder::new(Vec::new());
+        build(&mut builder);
+        let data = builder.into_inner().unwrap();
+        unpack_entries(&mut tar::Archive::new(data.as_slice()), root, 0)
+    }
+
+    #[test]
+    fn strip_path_rejects_parent_and_absolute_paths() {
+        assert_eq!(strip_path(Path::new("linux-6.9/Makefile"), 1).unwrap(), Some(PathBuf::from("Makefile")));
+        assert_eq!(strip_path(Path::new("./linux-6.9"), 1).unwrap(), None);
+        assert!(strip_path(Path::new("linux-6.9/../../etc/passwd"), 1).is_err());
+        assert!(strip_path(Path::new("/etc/passwd"), 0).is_err());
+    }
+
+    #[test]
+    fn entries_cannot_escape_through_a_symlinked_parent() {
+        let outside = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let target = outside.path().to_str().unwrap();
+
+        let err = unpack(root.path(), |b| {
+            entry(b, tar::EntryType::Symlink, "escape", target, b"");
+            entry(b, tar::EntryType::Regular, "escape/file", "", b"owned");
+        });
+        assert!(err.is_err());
+        assert!(!outside.path().join("file").exists());
+    }
+
+    #[test]
+    fn hard_links_cannot_point_outside_the_root() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret"), "secret").unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let target = outside.path().to_str().unwrap();
+
+        let err = unpack(root.path(), |b| {
+            entry(b, tar::EntryType::Symlink, "escape", target, b"");
+            entry(b, tar::EntryType::Link, "copy", "escape/secret", b"");
+        });
+        assert!(err.is_err());
+        assert!(!root.path().join("copy").exists());
+
+        let ok = unpack(root.path(), |b| {
+            entry(b, tar::EntryType::Regular, "a", "", b"data");
+            entry(b, tar::EntryType::Link, "b", "a", b"");
+        });
+        assert_eq!(ok.unwrap(), 2);
+        assert_eq!(fs::read_to_string(root.path().join("b")).unwrap(), "data");
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_discards_the_staging_directory() {
+        let dest = tempfile::tempdir().unwrap();
+        fs::write(dest.path().join("Makefile"), "old").unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        entry(&mut builder, tar::EntryType::Regular, "Makefile", "", b"new");
+        let data = builder.into_inner().unwrap();
+
+        let opts = DownloadOptions {
+            extract: Some(ExtractOptions::default()),
+            sha256: Some("00".repeat(32)),
+        };
+        let mut sink = DownloadSink::create(dest.path(), "linux.tar", &opts).await.unwrap();
+        sink.write(&data).await.unwrap();
+        assert!(sink.finish().await.is_err());
+
+        let names: Vec<_> = fs::read_dir(dest.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, ["Makefile"]);
+        assert_eq!(fs::read_to_string(dest.path().join("Makefile")).unwrap(), "old");
+    }
+
+    #[test]
+    fn merge_replaces_files_with_directories_and_back() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let staging = src.path().join("staging");
+        fs::create_dir_all(staging.join("was_file")).unwrap();
+        fs::write(staging.join("was_file/inner"), "inner").unwrap();
+        fs::write(staging.join("was_dir"), "file").unwrap();
+        fs::write(dst.path().join("was_file"), "old").unwrap();
+        fs::create_dir_all(dst.path().join("was_dir/sub")).unwrap();
+
+        merge_into(&staging, dst.path()).unwrap();
+        assert_eq!(fs::read_to_string(dst.path().join("was_file/inner")).unwrap(), "inner");
+        assert_eq!(fs::read_to_string(dst.path().join("was_dir")).unwrap(), "file");
+        assert!(!staging.exists());
+    }
+}
@@ -1,12 +1,19 @@
 use reqwest::Client;
-use std::path::{Path, PathBuf};
+use scraper::{Html, Selector};
+use std::collections::HashSet;
+use std::path::Path;
 use tokio::fs;
 use async_ftp::FtpStream;
 use ssh2::Session;
 use std::net::TcpStream;
 use std::time::Duration;
 use git2::{Repository, IndexAddOption, Cred, RemoteCallbacks, PushOptions, FetchOptions, AutotagOption};
+use git2::build::RepoBuilder;
 use anyhow::Result;
+use url::Url;
+
+use crate::config::TlsConfig;
+use crate::tls;
 
 #[derive(Debug, Clone)]
 pub enum EntryType {
@@ -39,16 +46,28 @@ pub struct PatchEntry {
 
 pub struct GitFetcher {
     client: Client,
+    tls: TlsConfig,
+}
+
+impl Default for GitFetcher {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GitFetcher {
     pub fn new() -> Self {
-        let client = Client::builder()
+        Self::with_tls(TlsConfig::default()).expect("Failed to build HTTP client")
+    }
+
+    /// Builds a fetcher whose HTTP scraping and libgit2 remotes honour the TLS config.
+    pub fn with_tls(tls: TlsConfig) -> Result<Self> {
+        let builder = Client::builder()
             .user_agent("ktp-agent/1.0")
-            .timeout(Duration::from_secs(15))
-            .build()
-            .expect("Failed to build HTTP client");
-        Self { client }
+            .timeout(Duration::from_secs(15));
+        let client = tls::configure_http(builder, &tls)?.build()?;
+        tls::configure_libgit2(&tls)?;
+        Ok(Self { client, tls })
     }
 
     pub async fn is_valid_git_source(&self, source: &str) -> Result<bool> {
@@ -101,6 +120,54 @@ impl GitFetcher {
         Ok(text)
     }
 
+    /// Parses an autoindex page into its direct children. Sort links,
+    /// parent links and anything outside `base` are skipped; directories
+    /// are recognised by their trailing slash.
+    pub fn parse_directory_index(base: &Url, html: &str) -> Vec<DirectoryEntry> {
+        let base = if base.path().ends_with('/') {
+            base.clone()
+        } else {
+            base.join("./").unwrap_or_else(|_| base.clone())
+        };
+        let document = Html::parse_document(html);
+        let selector = Selector::parse("a[href]").expect("valid selector");
+
+        let mut seen = HashSet::new();
+        let mut entries = Vec::new();
+        for link in document.select(&selector) {
+            let href = link.value().attr("href").unwrap_or_default();
+            if href.is_empty() || href.starts_with('?') || href.starts_with('#') {
+                continue;
+            }
+            let Ok(mut url) = base.join(href) else {
+                continue;
+            };
+            if url.scheme() != "http" && url.scheme() != "https" {
+                continue;
+            }
+            url.set_query(None);
+            url.set_fragment(None);
+
+            let Some(rest) = url.path().strip_prefix(base.path()) else {
+                continue;
+            };
+            let entry_type = if rest.ends_with('/') { EntryType::Directory } else { EntryType::File };
+            let segment = rest.trim_end_matches('/');
+            if segment.is_empty() || segment.contains('/') {
+                continue;
+            }
+            let name = percent_encoding::percent_decode_str(segment).decode_utf8_lossy().into_owned();
+            if name == "." || name == ".." || name.contains('/') || name.contains('\\') {
+                continue;
+            }
+
+            if seen.insert(url.to_string()) {
+                entries.push(DirectoryEntry { name, url: url.to_string(), entry_type });
+            }
+        }
+        entries
+    }
+
     pub async fn fetch_ftp_listing(&self, url: &str) -> Result<Vec<String>> {
         let url_parsed = url.parse::<url::Url>()?;
         let host = url_parsed.host_str().ok_or_else(|| anyhow::anyhow!("No host in FTP URL"))?;
@@ -187,24 +254,36 @@ pub fn fetch_scp_listing(&self, scp_url: &str, username: &str, password: &str) -
 
     // Git repository işlemleri için:
 
+    /// Uzak işlemler için kimlik bilgisi ve sertifika kontrolü callback'leri
+    fn remote_callbacks(&self) -> RemoteCallbacks<'_> {
+        let mut cb = RemoteCallbacks::new();
+        cb.credentials(|_url, username_from_url, _| {
+            Cred::ssh_key_from_agent(username_from_url.unwrap_or("git"))
+        });
+        if !self.tls.pins.is_empty() {
+            cb.certificate_check(tls::git_certificate_check(&self.tls.pins));
+        }
+        cb
+    }
+
     /// Repo aç veya klonla
-    pub fn open_or_clone_repo(url: &str, local_path: &Path) -> Result<Repository> {
+    pub fn open_or_clone_repo(&self, url: &str, local_path: &Path) -> Result<Repository> {
         if local_path.exists() && local_path.join(".git").exists() {
             Ok(Repository::open(local_path)?)
         } else {
-            Ok(Repository::clone(url, local_path)?)
+            tls::check_git_remote(&self.tls, url)?;
+            let mut fo = FetchOptions::new();
+            fo.remote_callbacks(self.remote_callbacks());
+            Ok(RepoBuilder::new().fetch_options(fo).clone(url, local_path)?)
         }
     }
 
     /// Fetch işlemi
-    pub fn fetch_repo(repo: &Repository, remote_name: &str) -> Result<()> {
+    pub fn fetch_repo(&self, repo: &Repository, remote_name: &str) -> Result<()> {
         let mut remote = repo.find_remote(remote_name)?;
-        let mut cb = RemoteCallbacks::new();
-        cb.credentials(|_url, username_from_url, _| {
-            Cred::ssh_key_from_agent(username_from_url.unwrap_or("git"))
-        });
+        tls::check_git_remote(&self.tls, remote.url().unwrap_or_default())?;
         let mut fo = FetchOptions::new();
-        fo.remote_callbacks(cb);
+        fo.remote_callbacks(self.remote_callbacks());
         fo.download_tags(AutotagOption::All);
         remote.fetch(&[] as &[&str], Some(&mut fo), None)?;
         Ok(())
@@ -235,22 +314,20 @@ pub fn fetch_scp_listing(&self, scp_url: &str, username: &str, password: &str) -
     }
 
     /// Push işlemi
-    pub fn git_push(repo: &Repository, remote_name: &str, branch: &str) -> Result<()> {
+    pub fn git_push(&self, repo: &Repository, remote_name: &str, branch: &str) -> Result<()> {
         let mut remote = repo.find_remote(remote_name)?;
-        let mut cb = RemoteCallbacks::new();
-        cb.credentials(|_url, username_from_url, _| {
-            Cred::ssh_key_from_agent(username_from_url.unwrap_or("git"))
-        });
+        tls::check_git_remote(&self.tls, remote.pushurl().or(remote.url()).unwrap_or_default())?;
         let mut push_opts = PushOptions::new();
-        push_opts.remote_callbacks(cb);
+        push_opts.remote_callbacks(self.remote_callbacks());
         remote.push(&[&format!("refs/heads/{}", branch)], Some(&mut push_opts))?;
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn run_git_operations(
         &self,
         source: &str,
-        local_path: &PathBuf,
+        local_path: &Path,
         commit_msg: &str,
         author: &str,
         email: &str,
@@ -258,12 +335,12 @@ pub fn fetch_scp_listing(&self, scp_url: &str, username: &str, password: &str) -
         branch: &str,
         do_push: bool,
     ) -> Result<()> {
-        let repo = Self::open_or_clone_repo(source, local_path)?;
-        Self::fetch_repo(&repo, remote)?;
+        let repo = self.open_or_clone_repo(source, local_path)?;
+        self.fetch_repo(&repo, remote)?;
         Self::git_add_all(&repo)?;
         Self::git_commit(&repo, commit_msg, author, email)?;
         if do_push {
-            Self::git_push(&repo, remote, branch)?;
+            self.git_push(&repo, remote, branch)?;
         }
         Ok(())
     }
@@ -0,0 +1,255 @@
+use std::path::PathBuf;
+
+use crate::dotconfig::DotConfig;
+use crate::toolchain;
+
+/// Package manager families the install hints are written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distro {
+    Debian,
+    Fedora,
+    Arch,
+    Suse,
+    Alpine,
+}
+
+impl Distro {
+    /// Reads `ID` and `ID_LIKE` from `/etc/os-release`.
+    pub fn detect() -> Option<Self> {
+        let text = std::fs::read_to_string("/etc/os-release").ok()?;
+        let mut ids = Vec::new();
+        for line in text.lines() {
+            if let Some(value) = line.strip_prefix("ID=").or_else(|| line.strip_prefix("ID_LIKE=")) {
+                ids.extend(value.trim_matches('"').split_whitespace().map(str::to_string));
+            }
+        }
+        ids.iter().find_map(|id| match id.as_str() {
+            "debian" | "ubuntu" => Some(Distro::Debian),
+            "fedora" | "rhel" | "centos" => Some(Distro::Fedora),
+            "arch" => Some(Distro::Arch),
+            "suse" | "opensuse" | "sles" => Some(Distro::Suse),
+            "alpine" => Some(Distro::Alpine),
+            _ => None,
+        })
+    }
+
+    pub fn install_command(self) -> &'static str {
+        match self {
+            Distro::Debian => "apt install",
+            Distro::Fedora => "dnf install",
+            Distro::Arch => "pacman -S",
+            Distro::Suse => "zypper install",
+            Distro::Alpine => "apk add",
+        }
+    }
+}
+
+enum Probe {
+    Program(&'static str),
+    /// A header below one of the system include directories.
+    Header(&'static str),
+}
+
+/// A host dependency of the kernel build.
+struct Requirement {
+    name: &'static str,
+    probe: Probe,
+    /// Config symbols that need it (any of them); empty means always.
+    needed_by: &'static [&'static str],
+    /// Package names, in `Distro` order.
+    packages: [&'static str; 5],
+}
+
+const REQUIREMENTS: &[Requirement] = &[
+    Requirement { name: "make", probe: Probe::Program("make"), needed_by: &[], packages: ["make"; 5] },
+    Requirement { name: "flex", probe: Probe::Program("flex"), needed_by: &[], packages: ["flex"; 5] },
+    Requirement { name: "bison", probe: Probe::Program("bison"), needed_by: &[], packages: ["bison"; 5] },
+    Requirement { name: "bc", probe: Probe::Program("bc"), needed_by: &[], packages: ["bc"; 5] },
+    Requirement { name: "perl", probe: Probe::Program("perl"), needed_by: &[], packages: ["perl"; 5] },
+    Requirement {
+        name: "libelf headers",
+        probe: Probe::Header("libelf.h"),
+        needed_by: &["OBJTOOL", "STACK_VALIDATION", "DEBUG_INFO_BTF", "UNWINDER_ORC"],
+        packages: ["libelf-dev", "elfutils-libelf-devel", "libelf", "libelf-devel", "elfutils-dev"],
+    },
+    Requirement {
+        name: "OpenSSL headers",
+        probe: Probe::Header("openssl/opensslv.h"),
+        needed_by: &["MODULE_SIG", "SYSTEM_TRUSTED_KEYRING", "SYSTEM_DATA_VERIFICATION"],
+        packages: ["libssl-dev", "openssl-devel", "openssl", "libopenssl-devel", "openssl-dev"],
+    },
+    Requirement {
+        name: "pahole",
+        probe: Probe::Program("pahole"),
+        needed_by: &["DEBUG_INFO_BTF"],
+        packages: ["dwarves", "dwarves", "pahole", "dwarves", "pahole"],
+    },
+    Requirement {
+        name: "rustc",
+        probe: Probe::Program("rustc"),
+        needed_by: &["RUST"],
+        packages: ["rustc", "rust", "rust", "rust", "rust"],
+    },
+    Requirement {
+        name: "bindgen",
+        probe: Probe::Program("bindgen"),
+        needed_by: &["RUST"],
+        packages: ["bindgen", "bindgen-cli", "rust-bindgen", "rust-bindgen", "rust-bindgen"],
+    },
+    Requirement {
+        name: "cpio",
+        probe: Probe::Program("cpio"),
+        needed_by: &["IKHEADERS"],
+        packages: ["cpio"; 5],
+    },
+    Requirement {
+        name: "zstd",
+        probe: Probe::Program("zstd"),
+        needed_by: &["KERNEL_ZSTD", "MODULE_COMPRESS_ZSTD"],
+        packages: ["zstd"; 5],
+    },
+    Requirement {
+        name: "xz",
+        probe: Probe::Program("xz"),
+        needed_by: &["KERNEL_XZ", "MODULE_COMPRESS_XZ"],
+        packages: ["xz-utils", "xz", "xz", "xz", "xz"],
+    },
+    Requirement {
+        name: "lz4",
+        probe: Probe::Program("lz4"),
+        needed_by: &["KERNEL_LZ4"],
+        packages: ["lz4", "lz4", "lz4", "lz4", "lz4"],
+    },
+];
+
+/// Whether a dependency has to be present for this build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Need {
+    Required,
+    /// Config-dependent, but no `.config` was available to decide.
+    Maybe,
+    NotNeeded,
+}
+
+#[derive(Debug, Clone)]
+pub struct DependencyReport {
+    pub name: &'static str,
+    pub need: Need,
+    /// The config symbols that make it necessary.
+    pub needed_by: Vec<&'static str>,
+    pub path: Option<PathBuf>,
+    pub version: Option<String>,
+    pub package: Option<&'static str>,
+}
+
+impl DependencyReport {
+    pub fn is_missing(&self) -> bool {
+        self.path.is_none()
+    }
+
+    pub fn print(&self) {
+        let why = match self.need {
+            _ if self.needed_by.is_empty() => String::new(),
+            Need::Maybe => format!(" (needed with CONFIG_{})", self.needed_by.join(" or CONFIG_")),
+            _ => format!(" (CONFIG_{})", self.needed_by.join(", CONFIG_")),
+        };
+        match (&self.path, self.need) {
+            (Some(path), _) => println!(
+                "  ok       {:<16} {:?}{}",
+                self.name,
+                path,
+                self.version.as_deref().map(|v| format!(": {}", v)).unwrap_or_default()
+            ),
+            (None, Need::Required) => println!("  MISSING  {}{}", self.name, why),
+            (None, Need::Maybe) => println!("  missing  {}{}", self.name, why),
+            (None, Need::NotNeeded) => println!("  -        {} (not needed by this config)", self.name),
+        }
+    }
+}
+
+/// Checks every host dependency against `config`. Without a config, the
+/// config-dependent ones are reported as `Need::Maybe`.
+pub async fn check(config: Option<&DotConfig>, distro: Option<Distro>) -> Vec<DependencyReport> {
+    let mut reports = Vec::new();
+    for requirement in REQUIREMENTS {
+        let (need, needed_by) = match config {
+            _ if requirement.needed_by.is_empty() => (Need::Required, Vec::new()),
+            Some(config) => {
+                let enabled: Vec<&'static str> =
+                    requirement.needed_by.iter().copied().filter(|symbol| config.get(symbol).is_set()).collect();
+                (if enabled.is_empty() { Need::NotNeeded } else { Need::Required }, enabled)
+            }
+            None => (Need::Maybe, requirement.needed_by.to_vec()),
+        };
+        let (path, version) = match requirement.probe {
+            Probe::Program(program) => match toolchain::find_in_path(program) {
+                Some(path) => {
+                    let version = toolchain::tool_version(&path).await;
+                    (Some(path), version)
+                }
+                None => (None, None),
+            },
+            Probe::Header(header) => (find_header(header), None),
+        };
+        reports.push(DependencyReport {
+            name: requirement.name,
+            need,
+            needed_by,
+            path,
+            version,
+            package: distro.map(|distro| requirement.packages[distro as usize]),
+        });
+    }
+    reports
+}
+
+/// Looks for a header in the include directories host programs are
+/// compiled against: `CPATH`/`C_INCLUDE_PATH`, then the system ones.
+fn find_header(header: &str) -> Option<PathBuf> {
+    let mut dirs: Vec<PathBuf> = ["CPATH", "C_INCLUDE_PATH"]
+        .iter()
+        .filter_map(std::env::var_os)
+        .flat_map(|value| std::env::split_paths(&value).collect::<Vec<_>>())
+        .collect();
+    dirs.extend(["/usr/local/include", "/usr/include"].iter().map(PathBuf::from));
+    dirs.into_iter().map(|dir| dir.join(header)).find(|path| path.is_file())
+}
+
+/// The install command for everything in `missing` that has a package hint.
+pub fn install_hint(missing: &[&DependencyReport], distro: Option<Distro>) -> Option<String> {
+    let distro = distro?;
+    let mut packages: Vec<&str> = missing.iter().filter_map(|report| report.package).collect();
+    packages.sort();
+    packages.dedup();
+    (!packages.is_empty()).then(|| format!("{} {}", distro.install_command(), packages.join(" ")))
+}
+
+/// CI systems set `CI`; there missing dependencies fail the run instead of warning.
+pub fn in_ci() -> bool {
+    std::env::var("CI").is_ok_and(|value| !value.is_empty() && value != "0" && value != "false")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn need<'a>(reports: &'a [DependencyReport], name: &str) -> (Need, &'a [&'static str]) {
+        let report = reports.iter().find(|r| r.name == name).unwrap();
+        (report.need, &report.needed_by)
+    }
+
+    #[tokio::test]
+    async fn check_decides_the_need_from_the_config() {
+        let config = DotConfig::parse("CONFIG_DEBUG_INFO_BTF=y\nCONFIG_UNWINDER_ORC=y\n# CONFIG_RUST is not set\n").unwrap();
+        let reports = check(Some(&config), Some(Distro::Fedora)).await;
+        assert_eq!(need(&reports, "make"), (Need::Required, &[][..]));
+        assert_eq!(need(&reports, "libelf headers"), (Need::Required, &["DEBUG_INFO_BTF", "UNWINDER_ORC"][..]));
+        assert_eq!(need(&reports, "pahole"), (Need::Required, &["DEBUG_INFO_BTF"][..]));
+        assert_eq!(need(&reports, "rustc"), (Need::NotNeeded, &[][..]));
+        assert_eq!(reports.iter().find(|r| r.name == "pahole").unwrap().package, Some("dwarves"));
+
+        let reports = check(None, None).await;
+        assert_eq!(need(&reports, "rustc"), (Need::Maybe, &["RUST"][..]));
+        assert!(reports.iter().all(|r| r.package.is_none()));
+    }
+}
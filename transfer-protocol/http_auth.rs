@@ -0,0 +1,390 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use reqwest::header::LOCATION;
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use url::{Origin, Url};
+
+use crate::config::HttpConfig;
+
+const MAX_REDIRECTS: usize = 10;
+
+/// Authentication for the HTTP backend. Secrets are never printed: `Debug`
+/// is redacted and URLs are logged through [`redact_url`].
+#[derive(Clone, Default)]
+pub enum HttpAuth {
+    #[default]
+    None,
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+    /// Look the host up in a netrc file (`~/.netrc` when no path is given).
+    Netrc(Option<PathBuf>),
+}
+
+impl fmt::Debug for HttpAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpAuth::None => write!(f, "None"),
+            HttpAuth::Basic { username, .. } => write!(f, "Basic({}:<redacted>)", username),
+            HttpAuth::Bearer(_) => write!(f, "Bearer(<redacted>)"),
+            HttpAuth::Netrc(path) => write!(f, "Netrc({:?})", path),
+        }
+    }
+}
+
+impl HttpAuth {
+    pub fn from_credentials(username: Option<String>, password: Option<String>) -> Self {
+        match username {
+            Some(username) => HttpAuth::Basic { username, password },
+            None => HttpAuth::None,
+        }
+    }
+
+    /// Reads `KTP_HTTP_TOKEN`, or `KTP_HTTP_USER` and `KTP_HTTP_PASSWORD`.
+    pub fn from_env() -> Self {
+        if let Ok(token) = std::env::var("KTP_HTTP_TOKEN") {
+            return HttpAuth::Bearer(token);
+        }
+        Self::from_credentials(
+            std::env::var("KTP_HTTP_USER").ok(),
+            std::env::var("KTP_HTTP_PASSWORD").ok(),
+        )
+    }
+
+    /// Picks the credentials for `url`, in order of precedence: `explicit`
+    /// (from the command line), user info embedded in the URL, the
+    /// environment, the `[[http.credentials]]` entry for the host and
+    /// finally netrc when enabled in the config. A username without a
+    /// password is completed from the environment or config when possible.
+    pub fn resolve(url: &str, explicit: HttpAuth, config: &HttpConfig) -> Result<Self> {
+        let parsed = Url::parse(url)?;
+        let host = parsed.host_str().unwrap_or_default();
+        let host_creds = config.credentials_for(host);
+
+        let mut auth = explicit;
+        if let HttpAuth::None = auth {
+            if !parsed.username().is_empty() {
+                auth = HttpAuth::Basic {
+                    username: percent_decode(parsed.username()),
+                    password: parsed.password().map(percent_decode),
+                };
+            }
+        }
+        if let HttpAuth::None = auth {
+            auth = Self::from_env();
+        }
+        if let (HttpAuth::None, Some(creds)) = (&auth, host_creds) {
+            auth = match (creds.token(), &creds.username) {
+                (Some(token), _) => HttpAuth::Bearer(token),
+                (None, Some(username)) => HttpAuth::Basic {
+                    username: username.clone(),
+                    password: creds.password(),
+                },
+                (None, None) => HttpAuth::None,
+            };
+        }
+        if let HttpAuth::None = auth {
+            if config.netrc || config.netrc_file.is_some() {
+                auth = HttpAuth::Netrc(config.netrc_file.clone());
+            }
+        }
+
+        if let HttpAuth::Basic { username, password: password @ None } = &mut auth {
+            *password = std::env::var("KTP_HTTP_PASSWORD").ok().or_else(|| {
+                host_creds
+                    .filter(|c| c.username.as_deref().is_none_or(|u| u == username))
+                    .and_then(|c| c.password())
+            });
+        }
+
+        Ok(auth)
+    }
+
+    /// True for Basic auth that still lacks a password and should be prompted for.
+    pub fn needs_password(&self) -> bool {
+        matches!(self, HttpAuth::Basic { password: None, .. })
+    }
+
+    pub fn with_password(self, password: String) -> Self {
+        match self {
+            HttpAuth::Basic { username, .. } => HttpAuth::Basic { username, password: Some(password) },
+            other => other,
+        }
+    }
+
+    /// These credentials if `url` has the origin (scheme, host and port)
+    /// they were given for, otherwise none. Mirrors follow links from index
+    /// pages, which may point to plain HTTP or to another server.
+    pub fn for_origin(&self, origin: &Origin, url: &Url) -> HttpAuth {
+        if url.origin() == *origin {
+            self.clone()
+        } else {
+            HttpAuth::None
+        }
+    }
+
+    /// Resolves netrc lookups for `url` into concrete Basic credentials.
+    fn for_url(&self, url: &Url) -> Result<HttpAuth> {
+        match self {
+            HttpAuth::Netrc(path) => {
+                let path = match path {
+                    Some(path) => path.clone(),
+                    None => match std::env::var_os("NETRC") {
+                        Some(path) => PathBuf::from(path),
+                        None => match std::env::var_os("HOME") {
+                            Some(home) => PathBuf::from(home).join(".netrc"),
+                            None => return Ok(HttpAuth::None),
+                        },
+                    },
+                };
+                let host = url.host_str().unwrap_or_default();
+                // A login without a password is for FTP-style prompting,
+                // which HTTP has no use for; send nothing rather than an
+                // empty password.
+                Ok(match netrc_lookup(&path, host)? {
+                    Some((username, Some(password))) => HttpAuth::Basic { username, password: Some(password) },
+                    _ => HttpAuth::None,
+                })
+            }
+            other => Ok(other.clone()),
+        }
+    }
+
+    fn apply(&self, req: RequestBuilder) -> RequestBuilder {
+        match self {
+            HttpAuth::Basic { username, password } => req.basic_auth(username, password.as_ref()),
+            HttpAuth::Bearer(token) => req.bearer_auth(token),
+            HttpAuth::None | HttpAuth::Netrc(_) => req,
+        }
+    }
+}
+
+/// Builds a client that leaves redirects to [`authorized_get`].
+pub fn client_builder() -> reqwest::ClientBuilder {
+    Client::builder().redirect(reqwest::redirect::Policy::none())
+}
+
+/// Sends a GET request, see [`authorized_request`].
+pub async fn authorized_get(client: &Client, url: &str, auth: &HttpAuth) -> Result<Response> {
+    authorized_request(client, Method::GET, url, auth).await
+}
+
+/// Sends a request, following redirects by hand so that the
+/// `Authorization` header is only ever sent to the origin of `url`. Once a
+/// redirect leaves that origin (scheme, host and port), credentials are
+/// dropped for the rest of the chain. The method changes on redirects the
+/// way reqwest and browsers change it, see [`redirect_method`].
+pub async fn authorized_request(client: &Client, mut method: Method, url: &str, auth: &HttpAuth) -> Result<Response> {
+    let mut current = Url::parse(url)?;
+    let _ = current.set_username("");
+    let _ = current.set_password(None);
+    let origin = current.origin();
+    let auth = auth.for_url(&current)?;
+    let mut send_auth = true;
+
+    for _ in 0..=MAX_REDIRECTS {
+        if send_auth && current.origin() != origin {
+            send_auth = false;
+            if !matches!(auth, HttpAuth::None) {
+                println!("Redirected to {}; credentials will not be forwarded.", redact_url(current.as_str()));
+            }
+        }
+
+        let mut req = client.request(method.clone(), current.clone());
+        if send_auth {
+            req = auth.apply(req);
+        }
+        let resp = req.send().await?;
+
+        let location = match resp.headers().get(LOCATION) {
+            Some(location) if resp.status().is_redirection() => location.to_str()?,
+            _ => return Ok(resp),
+        };
+        current = current.join(location)?;
+        method = redirect_method(resp.status(), method);
+    }
+
+    anyhow::bail!("Too many redirects while fetching {}", redact_url(url))
+}
+
+/// The method for the request following a redirect: a 303 turns anything
+/// but HEAD into a GET, and so does a 301 or 302 after a POST. Requests
+/// sent here carry no body, so there is none to drop.
+fn redirect_method(status: StatusCode, method: Method) -> Method {
+    match status {
+        StatusCode::SEE_OTHER if method != Method::HEAD => Method::GET,
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND if method == Method::POST => Method::GET,
+        _ => method,
+    }
+}
+
+/// Strips user info from a URL before it is shown to the user.
+pub fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) if !parsed.username().is_empty() || parsed.password().is_some() => {
+            let _ = parsed.set_username("");
+            let _ = parsed.set_password(None);
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    percent_encoding::percent_decode_str(s).decode_utf8_lossy().into_owned()
+}
+
+/// Returns `(login, password)` for `host` from a netrc file, falling back
+/// to the `default` entry.
+fn netrc_lookup(path: &Path, host: &str) -> Result<Option<(String, Option<String>)>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read netrc file {:?}", path)),
+    };
+
+    let mut tokens = Vec::new();
+    let mut in_macdef = false;
+    for line in text.lines() {
+        if in_macdef {
+            in_macdef = !line.trim().is_empty();
+            continue;
+        }
+        for word in line.split_whitespace() {
+            if word.starts_with('#') {
+                break;
+            }
+            if word == "macdef" {
+                in_macdef = true;
+                break;
+            }
+            tokens.push(word);
+        }
+    }
+
+    // (machine, login, password); a `None` machine is the default entry.
+    let mut entries: Vec<(Option<&str>, Option<&str>, Option<&str>)> = Vec::new();
+    let mut iter = tokens.into_iter();
+    while let Some(token) = iter.next() {
+        match token {
+            "machine" => entries.push((iter.next(), None, None)),
+            "default" => entries.push((None, None, None)),
+            "login" => {
+                if let (Some(entry), Some(v)) = (entries.last_mut(), iter.next()) {
+                    entry.1 = Some(v);
+                }
+            }
+            "password" => {
+                if let (Some(entry), Some(v)) = (entries.last_mut(), iter.next()) {
+                    entry.2 = Some(v);
+                }
+            }
+            "account" => {
+                iter.next();
+            }
+            _ => {}
+        }
+    }
+
+    let entry = entries
+        .iter()
+        .find(|(machine, _, _)| machine.is_some_and(|m| m.eq_ignore_ascii_case(host)))
+        .or_else(|| entries.iter().find(|(machine, _, _)| machine.is_none()));
+
+    Ok(entry.and_then(|(_, login, password)| {
+        login.map(|login| (login.to_string(), password.map(str::to_string)))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer() -> HttpAuth {
+        HttpAuth::Bearer("secret".to_string())
+    }
+
+    #[test]
+    fn for_origin_keeps_credentials_on_the_same_origin() {
+        let origin = Url::parse("https://example.org/pub/").unwrap().origin();
+        let url = Url::parse("https://example.org/pub/linux.tar.xz").unwrap();
+        assert!(matches!(bearer().for_origin(&origin, &url), HttpAuth::Bearer(_)));
+    }
+
+    #[test]
+    fn for_origin_drops_credentials_on_scheme_downgrade() {
+        let origin = Url::parse("https://example.org/pub/").unwrap().origin();
+        let url = Url::parse("http://example.org/pub/linux.tar.xz").unwrap();
+        assert!(matches!(bearer().for_origin(&origin, &url), HttpAuth::None));
+    }
+
+    #[test]
+    fn for_origin_drops_credentials_on_other_host_or_port() {
+        let origin = Url::parse("https://example.org/pub/").unwrap().origin();
+        for url in ["https://mirror.example.net/pub/a", "https://example.org:8443/pub/a"] {
+            let url = Url::parse(url).unwrap();
+            assert!(matches!(bearer().for_origin(&origin, &url), HttpAuth::None), "{}", url);
+        }
+    }
+
+    fn netrc(text: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".netrc");
+        std::fs::write(&path, text).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn netrc_finds_the_host_entry_before_the_default() {
+        let (_dir, path) = netrc(
+            "# comment\nmachine other.org login o password op\n\
+             machine Example.COM login alice password s3cret account acct\n\
+             default login anon password guest\n",
+        );
+        assert_eq!(netrc_lookup(&path, "example.com").unwrap(), Some(("alice".to_string(), Some("s3cret".to_string()))));
+        assert_eq!(netrc_lookup(&path, "unknown.org").unwrap(), Some(("anon".to_string(), Some("guest".to_string()))));
+    }
+
+    #[test]
+    fn netrc_skips_macros_and_handles_one_line_entries() {
+        let (_dir, path) = netrc(
+            "macdef init\nmachine evil.org login mallory password x\n\nmachine a.org login bob\nmachine b.org login carol password p\n",
+        );
+        assert_eq!(netrc_lookup(&path, "evil.org").unwrap(), None);
+        assert_eq!(netrc_lookup(&path, "a.org").unwrap(), Some(("bob".to_string(), None)));
+        assert_eq!(netrc_lookup(&path, "b.org").unwrap(), Some(("carol".to_string(), Some("p".to_string()))));
+    }
+
+    #[test]
+    fn missing_netrc_means_no_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(netrc_lookup(&dir.path().join(".netrc"), "example.com").unwrap(), None);
+    }
+
+    #[test]
+    fn netrc_login_without_a_password_sends_nothing() {
+        let (_dir, path) = netrc("machine a.org login bob\nmachine b.org login carol password p\n");
+        let auth = HttpAuth::Netrc(Some(path));
+        assert!(matches!(auth.for_url(&Url::parse("https://a.org/x").unwrap()).unwrap(), HttpAuth::None));
+        assert!(matches!(
+            auth.for_url(&Url::parse("https://b.org/x").unwrap()).unwrap(),
+            HttpAuth::Basic { password: Some(_), .. }
+        ));
+    }
+
+    #[test]
+    fn redirects_change_the_method_like_reqwest() {
+        assert_eq!(redirect_method(StatusCode::SEE_OTHER, Method::GET), Method::GET);
+        assert_eq!(redirect_method(StatusCode::SEE_OTHER, Method::POST), Method::GET);
+        assert_eq!(redirect_method(StatusCode::SEE_OTHER, Method::PUT), Method::GET);
+        assert_eq!(redirect_method(StatusCode::SEE_OTHER, Method::HEAD), Method::HEAD);
+        assert_eq!(redirect_method(StatusCode::FOUND, Method::POST), Method::GET);
+        assert_eq!(redirect_method(StatusCode::MOVED_PERMANENTLY, Method::POST), Method::GET);
+        assert_eq!(redirect_method(StatusCode::FOUND, Method::HEAD), Method::HEAD);
+        assert_eq!(redirect_method(StatusCode::TEMPORARY_REDIRECT, Method::POST), Method::POST);
+        assert_eq!(redirect_method(StatusCode::PERMANENT_REDIRECT, Method::PUT), Method::PUT);
+    }
+}
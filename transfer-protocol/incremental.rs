@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::artifacts::{self, SourceInfo};
+use crate::kbuild::BuildOptions;
+use crate::toolchain;
+
+/// Record of the last successful build, kept in the output directory.
+pub const BUILD_RECORD: &str = ".ktp-build.json";
+
+/// How much of the previous build is thrown away before compiling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CleanLevel {
+    None,
+    Clean,
+    Mrproper,
+    Distclean,
+}
+
+impl CleanLevel {
+    pub fn target(self) -> Option<&'static str> {
+        match self {
+            CleanLevel::None => None,
+            CleanLevel::Clean => Some("clean"),
+            CleanLevel::Mrproper => Some("mrproper"),
+            CleanLevel::Distclean => Some("distclean"),
+        }
+    }
+}
+
+pub fn parse_clean_level(s: &str) -> Result<CleanLevel, String> {
+    match s {
+        "none" => Ok(CleanLevel::None),
+        "clean" => Ok(CleanLevel::Clean),
+        "mrproper" => Ok(CleanLevel::Mrproper),
+        "distclean" => Ok(CleanLevel::Distclean),
+        other => Err(format!("unknown clean level '{}' (expected none, clean, mrproper or distclean)", other)),
+    }
+}
+
+/// What a build was made from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildRecord {
+    /// `VERSION.PATCHLEVEL.SUBLEVEL` plus `EXTRAVERSION` from the top Makefile.
+    pub kernel_version: Option<String>,
+    /// Git commit or downloaded tarball hash of the source tree.
+    pub source: Option<String>,
+    pub config_sha256: Option<String>,
+    /// ARCH, CROSS_COMPILE, LLVM, CC and LD as passed to make.
+    pub toolchain: BTreeMap<String, String>,
+    /// `--version` of the compiler.
+    pub compiler: Option<String>,
+}
+
+impl BuildRecord {
+    /// Describes the tree as it would be built now.
+    pub async fn current(kernel_path: &Path, build: &BuildOptions) -> Self {
+        let profile = build.effective_toolchain();
+        let compiler = toolchain::check_tools(&profile)
+            .await
+            .into_iter()
+            .find(|tool| tool.role == "compiler")
+            .and_then(|tool| tool.version);
+        let source = SourceInfo::detect(kernel_path);
+        let config = build.output_dir(kernel_path).join(".config");
+        BuildRecord {
+            kernel_version: kernel_version(kernel_path),
+            source: source.commit.or(source.tarball_sha256),
+            config_sha256: config.is_file().then(|| artifacts::sha256_file(&config).ok()).flatten(),
+            toolchain: profile.make_vars().into_iter().collect(),
+            compiler,
+        }
+    }
+
+    pub fn load(output_dir: &Path) -> Option<Self> {
+        let text = std::fs::read_to_string(output_dir.join(BUILD_RECORD)).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    pub fn save(&self, output_dir: &Path) -> Result<()> {
+        let path = output_dir.join(BUILD_RECORD);
+        std::fs::write(&path, serde_json::to_string_pretty(self)?).with_context(|| format!("Failed to write {:?}", path))
+    }
+}
+
+/// Picks the clean level for building `current` over the previous build in
+/// `output_dir`, with the reason. Kbuild tracks source, config and command
+/// line changes itself, so only changes it cannot see cost a clean: another
+/// target architecture or cross toolchain needs `mrproper`, a new compiler
+/// or kernel version `clean`.
+pub fn decide(previous: Option<&BuildRecord>, current: &BuildRecord, output_dir: &Path) -> (CleanLevel, String) {
+    let Some(previous) = previous else {
+        if output_dir.join("vmlinux").exists() || output_dir.join("include/config").exists() {
+            return (CleanLevel::Clean, "build output without a record of how it was built".to_string());
+        }
+        return (CleanLevel::None, "nothing built yet".to_string());
+    };
+
+    for var in ["ARCH", "CROSS_COMPILE", "LLVM"] {
+        let (old, new) = (previous.toolchain.get(var), current.toolchain.get(var));
+        if old != new {
+            return (
+                CleanLevel::Mrproper,
+                format!("{} changed from {} to {}", var, show(old), show(new)),
+            );
+        }
+    }
+    if previous.compiler != current.compiler || previous.toolchain != current.toolchain {
+        return (CleanLevel::Clean, "the compiler changed".to_string());
+    }
+    if previous.kernel_version != current.kernel_version {
+        return (
+            CleanLevel::Clean,
+            format!(
+                "kernel version changed from {} to {}",
+                show(previous.kernel_version.as_ref()),
+                show(current.kernel_version.as_ref())
+            ),
+        );
+    }
+
+    let mut changed = Vec::new();
+    if previous.source != current.source {
+        changed.push("source revision");
+    }
+    if previous.config_sha256 != current.config_sha256 {
+        changed.push("configuration");
+    }
+    if changed.is_empty() {
+        (CleanLevel::None, "nothing changed since the last build".to_string())
+    } else {
+        (CleanLevel::None, format!("{} changed; Kbuild rebuilds what is affected", changed.join(" and ")))
+    }
+}
+
+fn show(value: Option<&String>) -> &str {
+    value.map_or("unset", String::as_str)
+}
+
+/// The kernel version from the variables at the top of the source Makefile.
+pub fn kernel_version(kernel_path: &Path) -> Option<String> {
+    let text = std::fs::read_to_string(kernel_path.join("Makefile")).ok()?;
+    let mut vars = BTreeMap::new();
+    for line in text.lines().take(10) {
+        if let Some((name, value)) = line.split_once('=') {
+            vars.insert(name.trim(), value.trim());
+        }
+    }
+    let (version, patchlevel) = (vars.get("VERSION")?, vars.get("PATCHLEVEL")?);
+    let sublevel = vars.get("SUBLEVEL").copied().unwrap_or("0");
+    let extra = vars.get("EXTRAVERSION").copied().unwrap_or("");
+    Some(format!("{}.{}.{}{}", version, patchlevel, sublevel, extra))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(arch: &str, compiler: &str, version: &str) -> BuildRecord {
+        BuildRecord {
+            kernel_version: Some(version.to_string()),
+            source: Some("a1b2c3".to_string()),
+            config_sha256: Some("00".to_string()),
+            toolchain: BTreeMap::from([("ARCH".to_string(), arch.to_string())]),
+            compiler: Some(compiler.to_string()),
+        }
+    }
+
+    #[test]
+    fn decide_cleans_only_for_what_kbuild_cannot_track() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let previous = record("x86", "gcc 13", "6.9.0");
+        assert_eq!(decide(None, &previous, dir).0, CleanLevel::None);
+        std::fs::write(dir.join("vmlinux"), "").unwrap();
+        assert_eq!(decide(None, &previous, dir).0, CleanLevel::Clean);
+
+        let (level, reason) = decide(Some(&previous), &record("arm64", "gcc 13", "6.9.0"), dir);
+        assert_eq!(level, CleanLevel::Mrproper);
+        assert_eq!(reason, "ARCH changed from x86 to arm64");
+        let mut cross = previous.clone();
+        cross.toolchain.insert("CROSS_COMPILE".to_string(), "aarch64-linux-gnu-".to_string());
+        assert_eq!(decide(Some(&previous), &cross, dir).0, CleanLevel::Mrproper);
+
+        assert_eq!(decide(Some(&previous), &record("x86", "gcc 14", "6.9.0"), dir).0, CleanLevel::Clean);
+        assert_eq!(decide(Some(&previous), &record("x86", "gcc 13", "6.10.0"), dir).0, CleanLevel::Clean);
+
+        let mut edited = previous.clone();
+        edited.config_sha256 = Some("11".to_string());
+        edited.source = Some("d4e5f6".to_string());
+        let (level, reason) = decide(Some(&previous), &edited, dir);
+        assert_eq!(level, CleanLevel::None);
+        assert_eq!(reason, "source revision and configuration changed; Kbuild rebuilds what is affected");
+        assert_eq!(decide(Some(&previous), &previous, dir).0, CleanLevel::None);
+    }
+
+    #[test]
+    fn kernel_version_reads_the_top_makefile() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        assert_eq!(kernel_version(dir), None);
+        std::fs::write(
+            dir.join("Makefile"),
+            "# SPDX-License-Identifier: GPL-2.0\nVERSION = 6\nPATCHLEVEL = 10\nSUBLEVEL = 3\nEXTRAVERSION = -rc2\nNAME = Baby Opossum Posse\n",
+        )
+        .unwrap();
+        assert_eq!(kernel_version(dir).as_deref(), Some("6.10.3-rc2"));
+        std::fs::write(dir.join("Makefile"), "VERSION = 6\nPATCHLEVEL = 9\n").unwrap();
+        assert_eq!(kernel_version(dir).as_deref(), Some("6.9.0"));
+    }
+}
@@ -0,0 +1,355 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use tokio::process::Command as TokioCommand;
+
+use crate::artifacts;
+use crate::toolchain;
+
+/// Settings of the install stage.
+#[derive(Debug, Clone)]
+pub struct InstallOptions {
+    /// Root the kernel is installed into; `/` for the running system.
+    pub root: PathBuf,
+    /// Generate an initramfs when a generator is available.
+    pub initramfs: bool,
+    pub boot_entry: BootEntry,
+    /// Kernel command line for the boot entry; defaults to
+    /// `<root>/etc/kernel/cmdline`, or `/proc/cmdline` when installing to `/`.
+    pub cmdline: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootEntry {
+    /// BLS if `<root>/boot/loader` exists, otherwise grub if it is installed.
+    Auto,
+    /// A Boot Loader Specification entry, as read by systemd-boot.
+    Bls,
+    /// A `/etc/grub.d` snippet picked up by `grub-mkconfig`.
+    Grub,
+    None,
+}
+
+pub fn parse_boot_entry(s: &str) -> Result<BootEntry, String> {
+    match s {
+        "auto" => Ok(BootEntry::Auto),
+        "bls" | "systemd-boot" => Ok(BootEntry::Bls),
+        "grub" => Ok(BootEntry::Grub),
+        "none" => Ok(BootEntry::None),
+        other => Err(format!("unknown boot entry kind '{}' (expected auto, bls, grub or none)", other)),
+    }
+}
+
+impl InstallOptions {
+    pub fn is_host_root(&self) -> bool {
+        self.root == Path::new("/")
+    }
+
+    pub fn boot_dir(&self) -> PathBuf {
+        self.root.join("boot")
+    }
+
+    /// The boot entry kind to write, resolving `Auto` against the root.
+    pub fn boot_entry_kind(&self) -> BootEntry {
+        match self.boot_entry {
+            BootEntry::Auto if self.root.join("boot/loader").is_dir() => BootEntry::Bls,
+            BootEntry::Auto if self.root.join("etc/grub.d").is_dir() => BootEntry::Grub,
+            BootEntry::Auto => BootEntry::None,
+            kind => kind,
+        }
+    }
+
+    pub fn cmdline(&self) -> Option<String> {
+        if let Some(cmdline) = &self.cmdline {
+            return Some(cmdline.clone());
+        }
+        if let Ok(text) = fs::read_to_string(self.root.join("etc/kernel/cmdline")) {
+            return Some(text.trim().to_string());
+        }
+        if !self.is_host_root() {
+            return None;
+        }
+        fs::read_to_string("/proc/cmdline").ok().map(|text| without_loader_args(&text))
+    }
+
+    /// Where `<root>/boot` is as seen from the root of its own filesystem,
+    /// which is how grub names files: `/boot` when it shares the root's
+    /// filesystem, `/` when it is a separate boot partition.
+    pub fn boot_path_on_its_filesystem(&self) -> PathBuf {
+        let root = fs::canonicalize(&self.root).unwrap_or_else(|_| self.root.clone());
+        let boot = fs::canonicalize(self.boot_dir()).unwrap_or_else(|_| root.join("boot"));
+        let mount = mount_point(&boot, &root);
+        Path::new("/").join(boot.strip_prefix(mount).unwrap_or(&boot))
+    }
+}
+
+/// Drops what the current boot loader added to `/proc/cmdline` for the
+/// running kernel.
+fn without_loader_args(cmdline: &str) -> String {
+    let args: Vec<&str> = cmdline
+        .split_whitespace()
+        .filter(|arg| !arg.starts_with("BOOT_IMAGE=") && !arg.starts_with("initrd="))
+        .collect();
+    args.join(" ")
+}
+
+/// The topmost directory above `path`, up to `stop`, on the same
+/// filesystem, like grub's `make_system_path_relative_to_its_root`.
+fn mount_point<'a>(path: &'a Path, stop: &Path) -> &'a Path {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(dev) = fs::metadata(path).map(|meta| meta.dev()) else {
+        return path;
+    };
+    let mut mount = path;
+    while mount != stop {
+        match mount.parent() {
+            Some(parent) if fs::metadata(parent).is_ok_and(|meta| meta.dev() == dev) => mount = parent,
+            _ => break,
+        }
+    }
+    mount
+}
+
+/// Files the install stage placed in `<root>/boot`.
+#[derive(Debug, Clone)]
+pub struct InstalledKernel {
+    pub image: PathBuf,
+    pub system_map: PathBuf,
+    pub config: PathBuf,
+    pub initramfs: Option<PathBuf>,
+}
+
+/// Copies the boot image, `System.map` and `.config` into `<root>/boot`
+/// under the names `make install` uses, and the staged modules (if any)
+/// into `<root>/lib/modules/<release>`.
+pub fn install_files(
+    output_dir: &Path,
+    srcarch: &str,
+    release: &str,
+    staged_modules: Option<&Path>,
+    options: &InstallOptions,
+) -> Result<InstalledKernel> {
+    let boot = options.boot_dir();
+    fs::create_dir_all(&boot).with_context(|| format!("Failed to create {:?}", boot))?;
+    let image = artifacts::boot_image(output_dir, srcarch)
+        .with_context(|| format!("No boot image found in {:?}", output_dir.join("arch").join(srcarch).join("boot")))?;
+
+    let installed = InstalledKernel {
+        image: boot.join(format!("vmlinuz-{}", release)),
+        system_map: boot.join(format!("System.map-{}", release)),
+        config: boot.join(format!("config-{}", release)),
+        initramfs: None,
+    };
+    for (from, to) in [
+        (image, &installed.image),
+        (output_dir.join("System.map"), &installed.system_map),
+        (output_dir.join(".config"), &installed.config),
+    ] {
+        fs::copy(&from, to).with_context(|| format!("Failed to copy {:?} to {:?}", from, to))?;
+    }
+
+    if let Some(modules) = staged_modules {
+        let target = options.root.join("lib/modules").join(release);
+        if target.exists() {
+            fs::remove_dir_all(&target).with_context(|| format!("Failed to replace {:?}", target))?;
+        }
+        copy_tree(modules, &target)?;
+    }
+    Ok(installed)
+}
+
+fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).with_context(|| format!("Failed to create {:?}", to))?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else if file_type.is_dir() {
+            copy_tree(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target).with_context(|| format!("Failed to copy {:?}", entry.path()))?;
+        }
+    }
+    Ok(())
+}
+
+/// An initramfs generator found on the host.
+#[derive(Debug, Clone)]
+pub struct InitramfsGenerator {
+    pub name: &'static str,
+    pub path: PathBuf,
+}
+
+impl InitramfsGenerator {
+    /// dracut, mkinitcpio or update-initramfs, in that order.
+    pub fn detect() -> Option<Self> {
+        ["dracut", "mkinitcpio", "update-initramfs"]
+            .iter()
+            .find_map(|name| toolchain::find_system_tool(name).map(|path| InitramfsGenerator { name, path }))
+    }
+
+    /// The image this generator writes for `release`, named the way the
+    /// distribution's own tooling does.
+    pub fn output(&self, boot: &Path, release: &str) -> PathBuf {
+        match self.name {
+            "update-initramfs" => boot.join(format!("initrd.img-{}", release)),
+            _ => boot.join(format!("initramfs-{}.img", release)),
+        }
+    }
+
+    /// The command generating the initramfs for `release`, reading modules
+    /// from `<root>/lib/modules`. update-initramfs only knows the host's
+    /// module directory, so it is unusable for other roots.
+    pub fn command(&self, release: &str, options: &InstallOptions) -> Result<TokioCommand> {
+        let output = self.output(&options.boot_dir(), release);
+        let modules = options.root.join("lib/modules").join(release);
+        let mut cmd = TokioCommand::new(&self.path);
+        match self.name {
+            "dracut" => {
+                cmd.arg("--force").arg("--kver").arg(release).arg("--kmoddir").arg(&modules).arg(&output);
+            }
+            "mkinitcpio" => {
+                cmd.arg("-k").arg(release).arg("-r").arg(&options.root).arg("-g").arg(&output);
+            }
+            _ if options.is_host_root() => {
+                cmd.arg("-c").arg("-k").arg(release).arg("-b").arg(options.boot_dir());
+            }
+            _ => anyhow::bail!("update-initramfs cannot build an initramfs for root {:?}", options.root),
+        }
+        Ok(cmd)
+    }
+}
+
+/// Writes a boot entry for the installed kernel, returning its path.
+/// Paths inside the entry are relative to `<root>/boot`, which BLS expects
+/// to be the boot partition; the grub snippet searches for the kernel by
+/// its path on the filesystem holding `<root>/boot`.
+pub fn write_boot_entry(
+    kind: BootEntry,
+    options: &InstallOptions,
+    release: &str,
+    installed: &InstalledKernel,
+) -> Result<Option<PathBuf>> {
+    let file_name = |path: &Path| path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let cmdline = options.cmdline().unwrap_or_default();
+    let title = format!("Linux {} (KTP)", release);
+
+    let (path, text) = match kind {
+        BootEntry::Bls => {
+            let mut text = format!(
+                "title   {}\nversion {}\nlinux   /{}\n",
+                title,
+                release,
+                file_name(&installed.image)
+            );
+            if let Some(initramfs) = &installed.initramfs {
+                text.push_str(&format!("initrd  /{}\n", file_name(initramfs)));
+            }
+            text.push_str(&format!("options {}\n", cmdline));
+            (options.boot_dir().join("loader/entries").join(format!("ktp-{}.conf", release)), text)
+        }
+        BootEntry::Grub => {
+            // Same layout as grub's 40_custom: the script prints itself minus the header.
+            let boot = options.boot_path_on_its_filesystem();
+            let image = boot.join(file_name(&installed.image)).display().to_string();
+            let mut text = format!(
+                "#!/bin/sh\nexec tail -n +3 $0\nmenuentry '{}' {{\n\tsearch --no-floppy --set=root --file {}\n\tlinux {} {}\n",
+                title, image, image, cmdline
+            );
+            if let Some(initramfs) = &installed.initramfs {
+                text.push_str(&format!("\tinitrd {}\n", boot.join(file_name(initramfs)).display()));
+            }
+            text.push_str("}\n");
+            (options.root.join("etc/grub.d").join(format!("42_ktp-{}", release)), text)
+        }
+        BootEntry::Auto | BootEntry::None => return Ok(None),
+    };
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}", parent))?;
+    }
+    fs::write(&path, text).with_context(|| format!("Failed to write {:?}", path))?;
+    if kind == BootEntry::Grub {
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn staging_root(cmdline: Option<&str>) -> (tempfile::TempDir, InstallOptions, InstalledKernel) {
+        let tmp = tempfile::tempdir().unwrap();
+        let boot = tmp.path().join("boot");
+        fs::create_dir_all(&boot).unwrap();
+        let options = InstallOptions {
+            root: tmp.path().to_path_buf(),
+            initramfs: true,
+            boot_entry: BootEntry::Auto,
+            cmdline: cmdline.map(str::to_string),
+        };
+        let installed = InstalledKernel {
+            image: boot.join("vmlinuz-6.9.0-acme"),
+            system_map: boot.join("System.map-6.9.0-acme"),
+            config: boot.join("config-6.9.0-acme"),
+            initramfs: Some(boot.join("initramfs-6.9.0-acme.img")),
+        };
+        (tmp, options, installed)
+    }
+
+    #[test]
+    fn bls_entries_name_files_on_the_boot_partition() {
+        let (_tmp, options, installed) = staging_root(Some("root=/dev/sda2 ro"));
+        let path = write_boot_entry(BootEntry::Bls, &options, "6.9.0-acme", &installed).unwrap().unwrap();
+        assert_eq!(path, options.root.join("boot/loader/entries/ktp-6.9.0-acme.conf"));
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            "title   Linux 6.9.0-acme (KTP)\nversion 6.9.0-acme\nlinux   /vmlinuz-6.9.0-acme\n\
+             initrd  /initramfs-6.9.0-acme.img\noptions root=/dev/sda2 ro\n"
+        );
+    }
+
+    #[test]
+    fn grub_snippets_name_files_on_the_boot_filesystem() {
+        let (_tmp, options, installed) = staging_root(None);
+        fs::create_dir_all(options.root.join("etc/kernel")).unwrap();
+        fs::write(options.root.join("etc/kernel/cmdline"), "quiet\n").unwrap();
+        // `<root>/boot` shares the root's filesystem here.
+        assert_eq!(options.boot_path_on_its_filesystem(), Path::new("/boot"));
+
+        let path = write_boot_entry(BootEntry::Grub, &options, "6.9.0-acme", &installed).unwrap().unwrap();
+        assert_eq!(path, options.root.join("etc/grub.d/42_ktp-6.9.0-acme"));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "#!/bin/sh\nexec tail -n +3 $0\nmenuentry 'Linux 6.9.0-acme (KTP)' {\n\
+             \tsearch --no-floppy --set=root --file /boot/vmlinuz-6.9.0-acme\n\
+             \tlinux /boot/vmlinuz-6.9.0-acme quiet\n\
+             \tinitrd /boot/initramfs-6.9.0-acme.img\n}\n"
+        );
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o755);
+    }
+
+    #[test]
+    fn mount_point_stops_at_the_root() {
+        let (_tmp, options, _) = staging_root(None);
+        let root = fs::canonicalize(&options.root).unwrap();
+        let boot = root.join("boot");
+        assert_eq!(mount_point(&boot, &root), root);
+        assert_eq!(mount_point(&boot, &boot), boot);
+    }
+
+    #[test]
+    fn cmdline_drops_what_the_boot_loader_added() {
+        assert_eq!(
+            without_loader_args("BOOT_IMAGE=/vmlinuz-6.8 root=UUID=1234 ro initrd=\\initrd.img quiet\n"),
+            "root=UUID=1234 ro quiet"
+        );
+        let (_tmp, options, _) = staging_root(None);
+        assert_eq!(options.cmdline(), None);
+    }
+}
@@ -0,0 +1,603 @@
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use tokio::process::Command as TokioCommand;
+
+use crate::compiler_cache::{CacheConfig, CacheTool};
+use crate::config::{LlvmSetting, PackageConfig, ToolchainProfile};
+use crate::dotconfig::ConfigValue;
+use crate::incremental::CleanLevel;
+use crate::install::InstallOptions;
+use crate::modules::ModuleCompression;
+use crate::packaging;
+use crate::sandbox::{self, SandboxOptions};
+
+/// Settings shared by every `make` invocation on a kernel tree.
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    /// Parallel jobs (`-j`); `None` uses the CPUs available to this process.
+    pub jobs: Option<usize>,
+    /// Extra `NAME=value` variables passed to make.
+    pub make_vars: Vec<(String, String)>,
+    /// Targets built by `compile_kernel` (`bzImage`, `modules`, `dtbs`, ...);
+    /// empty builds the default target.
+    pub targets: Vec<String>,
+    /// Pass `V=1` so make prints full command lines.
+    pub verbose: bool,
+    /// Cross-compilation profile whose variables go on every make command.
+    pub toolchain: Option<ToolchainProfile>,
+    /// Out-of-tree build directory (`O=`). Configuration, objects and images
+    /// land here and the source tree is left untouched.
+    pub build_dir: Option<PathBuf>,
+    /// How `kconfig_interface` produces `.config`; `None` picks
+    /// `menuconfig` on a terminal and a non-interactive mode otherwise.
+    pub config_mode: Option<ConfigMode>,
+    /// Config fragments merged over the base configuration, in order.
+    pub config_fragments: Vec<PathBuf>,
+    /// Individual `--set`/`--unset` edits, applied after the fragments.
+    pub config_edits: Vec<(String, ConfigValue)>,
+    /// Where build logs go; defaults to `ktp-logs` in the output directory.
+    pub log_dir: Option<PathBuf>,
+    /// Diagnostics file, log directory or build directory whose warnings
+    /// new builds are compared against; defaults to the previous run.
+    pub warning_baseline: Option<PathBuf>,
+    /// Fail the build when it has warnings the baseline does not.
+    pub no_new_warnings: bool,
+    /// Root of the versioned artifact directories; defaults to `artifacts`
+    /// in the output directory.
+    pub artifact_dir: Option<PathBuf>,
+    /// Skip collecting artifacts after compiling.
+    pub no_artifacts: bool,
+    /// Skip `modules_install` and the module bundle.
+    pub no_modules: bool,
+    /// Install modules with `INSTALL_MOD_STRIP=1`.
+    pub strip_modules: bool,
+    /// Compress installed modules that Kbuild left uncompressed.
+    pub module_compression: Option<ModuleCompression>,
+    /// Distribution packages to build and their metadata.
+    pub package: PackageConfig,
+    /// Install the kernel into a root after building.
+    pub install: Option<InstallOptions>,
+    /// Clean level before compiling; `None` decides from what changed since
+    /// the previous build.
+    pub clean: Option<CleanLevel>,
+    /// ccache/sccache wrapping `CC` and `HOSTCC`.
+    pub compiler_cache: Option<CacheConfig>,
+    /// Pin timestamps, user, host and paths so that builds are reproducible.
+    pub reproducible: bool,
+    /// Run make stages in an unprivileged namespace sandbox with a
+    /// read-only source tree and no network.
+    pub sandbox: Option<SandboxOptions>,
+}
+
+impl BuildOptions {
+    pub fn has_config_edits(&self) -> bool {
+        !self.config_fragments.is_empty() || !self.config_edits.is_empty()
+    }
+
+    pub fn jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(available_cpus)
+    }
+
+    /// A `make` command in `kernel_path` carrying the job count, verbosity,
+    /// toolchain and variables. Callers add targets. Variables from the
+    /// command line come last so they override the profile.
+    pub fn make_command(&self, kernel_path: &Path) -> TokioCommand {
+        let mut cmd = TokioCommand::new("make");
+        cmd.current_dir(kernel_path).arg(format!("-j{}", self.jobs()));
+        if let Some(dir) = &self.build_dir {
+            cmd.arg(format!("O={}", dir.display()));
+        }
+        if self.verbose {
+            cmd.arg("V=1");
+        }
+        if let Some(profile) = &self.toolchain {
+            for (name, value) in profile.make_vars() {
+                cmd.arg(format!("{}={}", name, value));
+            }
+        }
+        if let Some(cache) = &self.compiler_cache {
+            let toolchain = self.effective_toolchain();
+            let compiler = toolchain
+                .required_tools()
+                .into_iter()
+                .find(|(role, _)| *role == "compiler")
+                .map(|(_, name)| name)
+                .unwrap_or_default();
+            let program = cache.tool.program();
+            cmd.arg(format!("CC={} {}", program, compiler))
+                .arg(format!("HOSTCC={} {}", program, toolchain.host_compiler()))
+                .envs(cache.env());
+        }
+        // The build timestamp ends up in compiled objects; pinning it lets
+        // rebuilds of the same tree hit the cache and reproduce bit for bit.
+        if self.compiler_cache.is_some() || self.reproducible {
+            if let Some(epoch) = source_date_epoch(kernel_path) {
+                if self.make_var("KBUILD_BUILD_TIMESTAMP").is_none() {
+                    cmd.arg(format!("KBUILD_BUILD_TIMESTAMP={}", format_timestamp(epoch)));
+                }
+                if self.reproducible {
+                    cmd.env("SOURCE_DATE_EPOCH", epoch.to_string());
+                }
+            }
+        }
+        if self.reproducible {
+            cmd.arg("KBUILD_BUILD_USER=ktp").arg("KBUILD_BUILD_HOST=ktp").arg("KBUILD_BUILD_VERSION=1");
+        }
+        if let Some(local_version) = &self.package.local_version {
+            cmd.arg(format!("LOCALVERSION={}", local_version));
+        }
+        for (name, value) in &self.make_vars {
+            cmd.arg(format!("{}={}", name, value));
+        }
+        if self.reproducible {
+            // After the other variables, extending their flags rather than replacing them.
+            let (c_maps, rust_maps) = self.prefix_maps(kernel_path);
+            for (name, maps) in [("KCFLAGS", &c_maps), ("KAFLAGS", &c_maps), ("KRUSTFLAGS", &rust_maps)] {
+                let flags: Vec<String> = self.make_var(name).into_iter().chain(maps.iter().cloned()).collect();
+                cmd.arg(format!("{}={}", name, flags.join(" ")));
+            }
+        }
+        self.confine(&mut cmd, kernel_path);
+        cmd
+    }
+
+    /// Runs `cmd` in the build sandbox, if there is one, the way make
+    /// stages run.
+    pub fn confine(&self, cmd: &mut TokioCommand, kernel_path: &Path) {
+        if self.sandbox.is_some() {
+            cmd.env("TMPDIR", self.output_dir(kernel_path).join(sandbox::SANDBOX_TMP));
+            sandbox::confine(cmd, &self.writable_dirs(kernel_path), &[absolute_source(kernel_path)]);
+        }
+    }
+
+    /// Directories a sandboxed make may write to: the build and artifact
+    /// directories, the compiler cache and any extra ones, resolved the way
+    /// `prepare` created them.
+    fn writable_dirs(&self, kernel_path: &Path) -> Vec<PathBuf> {
+        let mut dirs = vec![self.output_dir(kernel_path), self.artifact_root(kernel_path)];
+        dirs.extend(self.compiler_cache.as_ref().and_then(|cache| cache.dir.clone()));
+        dirs.extend(self.sandbox.iter().flat_map(|sandbox| sandbox.writable.iter().cloned()));
+        dirs.into_iter().map(|dir| std::fs::canonicalize(&dir).unwrap_or(dir)).collect()
+    }
+
+    /// The value make sees for `name` from the command line or the profile.
+    fn make_var(&self, name: &str) -> Option<String> {
+        self.make_vars
+            .iter()
+            .rev()
+            .find(|(var, _)| var == name)
+            .map(|(_, value)| value.clone())
+            .or_else(|| self.toolchain.as_ref().and_then(|profile| profile.make_vars.get(name).cloned()))
+    }
+
+    /// Compiler flags mapping the source and build directories out of debug
+    /// info and `__FILE__`, for C/assembler and for Rust.
+    fn prefix_maps(&self, kernel_path: &Path) -> (Vec<String>, Vec<String>) {
+        let mut dirs = vec![std::path::absolute(kernel_path).unwrap_or_else(|_| kernel_path.to_path_buf())];
+        dirs.extend(self.build_dir.clone());
+        let c = dirs.iter().map(|dir| format!("-ffile-prefix-map={}/=", dir.display())).collect();
+        let rust = dirs.iter().map(|dir| format!("--remap-path-prefix={}/=", dir.display())).collect();
+        (c, rust)
+    }
+
+    /// Where `.config` and build output live: the build directory if one is
+    /// set, otherwise the source tree.
+    pub fn output_dir(&self, kernel_path: &Path) -> PathBuf {
+        self.build_dir.clone().unwrap_or_else(|| kernel_path.to_path_buf())
+    }
+
+    pub fn artifact_root(&self, kernel_path: &Path) -> PathBuf {
+        self.artifact_dir.clone().unwrap_or_else(|| self.output_dir(kernel_path).join("artifacts"))
+    }
+
+    /// The toolchain make actually uses: the selected profile (or the host
+    /// compiler) with `--make-var` overrides of ARCH, CROSS_COMPILE, LLVM, CC
+    /// and LD applied.
+    pub fn effective_toolchain(&self) -> ToolchainProfile {
+        let mut profile = self
+            .toolchain
+            .clone()
+            .unwrap_or_else(|| ToolchainProfile { name: "host".to_string(), ..Default::default() });
+        for (name, value) in &self.make_vars {
+            match name.as_str() {
+                "ARCH" => profile.arch = Some(value.clone()),
+                "CROSS_COMPILE" => profile.cross_compile = Some(value.clone()),
+                "LLVM" => profile.llvm = Some(LlvmSetting::Value(value.clone())),
+                "CC" => profile.cc = Some(value.clone()),
+                "LD" => profile.ld = Some(value.clone()),
+                _ => {}
+            }
+        }
+        profile
+    }
+
+    /// The `arch/<srcarch>` directory the build targets.
+    pub fn srcarch(&self) -> String {
+        crate::kconfig::srcarch(self.effective_toolchain().arch.as_deref())
+    }
+
+    /// Staging root that `modules_install` writes to for `release`.
+    pub fn staging_dir(&self, kernel_path: &Path, release: &str) -> PathBuf {
+        self.output_dir(kernel_path).join("ktp-staging").join(release)
+    }
+
+    /// Whether the requested targets build modules at all.
+    pub fn builds_modules(&self) -> bool {
+        self.targets.is_empty() || self.targets.iter().any(|t| t == "modules" || t == "all")
+    }
+
+    pub fn log_dir(&self, kernel_path: &Path) -> PathBuf {
+        self.log_dir.clone().unwrap_or_else(|| self.output_dir(kernel_path).join("ktp-logs"))
+    }
+
+    /// Creates the build directory. Kbuild refuses `O=` builds from a source
+    /// tree that has been configured in place, installing to `/` needs root
+    /// and the sandbox needs a separate build directory it can set up, so
+    /// all of these are reported up front.
+    pub async fn prepare(&self, kernel_path: &Path) -> Result<()> {
+        if let Some(install) = &self.install {
+            if install.is_host_root() && unsafe { libc::geteuid() } != 0 {
+                anyhow::bail!("Installing into / needs root; use --root to install into a staging directory");
+            }
+        }
+        let Some(dir) = &self.build_dir else {
+            if self.sandbox.is_some() {
+                anyhow::bail!("--sandbox needs --build-dir: the source tree is read-only inside the sandbox");
+            }
+            return Ok(());
+        };
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create build directory {:?}", dir))?;
+        if kernel_path.join(".config").exists() || kernel_path.join("include/config").exists() {
+            anyhow::bail!(
+                "Source tree {:?} has an in-tree configuration; run 'make mrproper' there before building in {:?}",
+                kernel_path,
+                dir
+            );
+        }
+        if self.sandbox.is_some() {
+            self.prepare_sandbox(kernel_path).await?;
+        }
+        Ok(())
+    }
+
+    async fn prepare_sandbox(&self, kernel_path: &Path) -> Result<()> {
+        if !cfg!(target_os = "linux") {
+            anyhow::bail!("--sandbox is only supported on Linux");
+        }
+        if matches!(&self.compiler_cache, Some(cache) if cache.tool == CacheTool::Sccache) {
+            anyhow::bail!("sccache talks to its server over the network, which the sandbox blocks; use ccache");
+        }
+        if matches!(&self.compiler_cache, Some(cache) if cache.dir.is_none()) {
+            anyhow::bail!("The sandbox needs the compiler cache directory set explicitly ([cache] dir)");
+        }
+        let mut dirs = vec![self.artifact_root(kernel_path), self.output_dir(kernel_path).join(sandbox::SANDBOX_TMP)];
+        dirs.extend(self.compiler_cache.as_ref().and_then(|cache| cache.dir.clone()));
+        dirs.extend(self.sandbox.iter().flat_map(|sandbox| sandbox.writable.iter().cloned()));
+        for dir in &dirs {
+            tokio::fs::create_dir_all(dir).await.with_context(|| format!("Failed to create {:?}", dir))?;
+        }
+        sandbox::probe(&self.writable_dirs(kernel_path), &[absolute_source(kernel_path)]).await
+    }
+}
+
+/// CPUs this process may use. The standard library accounts for the CPU
+/// affinity mask and for cgroup v1/v2 CPU quotas, so containers with a CPU
+/// limit do not oversubscribe.
+pub fn available_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+fn absolute_source(kernel_path: &Path) -> PathBuf {
+    std::fs::canonicalize(kernel_path).unwrap_or_else(|_| kernel_path.to_path_buf())
+}
+
+/// Seconds since the epoch the source tree dates from: the HEAD commit time
+/// of a Git tree, otherwise the modification time of the top Makefile.
+pub fn source_date_epoch(kernel_path: &Path) -> Option<u64> {
+    if let Ok(repo) = git2::Repository::open(kernel_path) {
+        if let Ok(commit) = repo.head().and_then(|head| head.peel_to_commit()) {
+            return u64::try_from(commit.time().seconds()).ok();
+        }
+    }
+    let modified = std::fs::metadata(kernel_path.join("Makefile")).ok()?.modified().ok()?;
+    modified.duration_since(std::time::UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+/// `epoch` as `LC_ALL=C date -u` prints it, which is what Kbuild uses for
+/// the build timestamp by default and what `date -d` parses back.
+pub fn format_timestamp(epoch: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let days = epoch / 86400;
+    let secs = epoch % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{} {} {:>2} {:02}:{:02}:{:02} UTC {}",
+        DAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        year
+    )
+}
+
+/// `path` with `.` and `..` resolved lexically, for paths that may not
+/// exist yet or that name files as the compiler saw them.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Whether anything has been compiled in `output_dir`, judged by object
+/// files. `scripts` and `tools` are skipped, since `make clean` keeps the
+/// host programs there, and so is KTP's deb staging build.
+pub fn has_objects(output_dir: &Path) -> bool {
+    let mut dirs = vec![output_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => {
+                    let skipped = dir == output_dir
+                        && ["scripts", "tools", packaging::DEB_STAGING].iter().any(|name| entry.file_name() == *name);
+                    if !skipped {
+                        dirs.push(path);
+                    }
+                }
+                Ok(kind) if kind.is_file() && path.extension().is_some_and(|ext| ext == "o") => return true,
+                _ => {}
+            }
+        }
+    }
+    false
+}
+
+/// Parses a `NAME=value` make variable from the command line.
+pub fn parse_make_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() && !name.contains(char::is_whitespace) => {
+            Ok((name.to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected NAME=value, got '{}'", s)),
+    }
+}
+
+/// How the kernel configuration stage produces `.config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigMode {
+    /// An interactive frontend: `menuconfig`, `nconfig` or `xconfig`.
+    Interactive(String),
+    /// `defconfig` for the architecture, or a named `*_defconfig`.
+    Defconfig(Option<String>),
+    Olddefconfig,
+    Localmodconfig,
+    Tinyconfig,
+    /// Copy an existing `.config` into place, then run `olddefconfig`.
+    CopyFrom(PathBuf),
+    /// Leave the configuration as it is.
+    Skip,
+}
+
+impl ConfigMode {
+    /// `menuconfig` when a user is at the terminal. Headless runs keep an
+    /// existing `.config` up to date, or start from the architecture's
+    /// `defconfig`.
+    pub fn detect(output_dir: &Path) -> Self {
+        if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+            ConfigMode::Interactive("menuconfig".to_string())
+        } else if output_dir.join(".config").exists() {
+            ConfigMode::Olddefconfig
+        } else {
+            ConfigMode::Defconfig(None)
+        }
+    }
+
+    pub fn is_interactive(&self) -> bool {
+        matches!(self, ConfigMode::Interactive(_))
+    }
+
+    /// The make target that runs this mode, if any.
+    pub fn target(&self) -> Option<String> {
+        match self {
+            ConfigMode::Interactive(frontend) => Some(frontend.clone()),
+            ConfigMode::Defconfig(name) => Some(name.clone().unwrap_or_else(|| "defconfig".to_string())),
+            ConfigMode::Olddefconfig | ConfigMode::CopyFrom(_) => Some("olddefconfig".to_string()),
+            ConfigMode::Localmodconfig => Some("localmodconfig".to_string()),
+            ConfigMode::Tinyconfig => Some("tinyconfig".to_string()),
+            ConfigMode::Skip => None,
+        }
+    }
+}
+
+/// Parses a configuration mode from the command line. Anything that looks
+/// like a path is taken as a `.config` to copy in.
+pub fn parse_config_mode(s: &str) -> Result<ConfigMode, String> {
+    Ok(match s {
+        "menuconfig" | "nconfig" | "xconfig" => ConfigMode::Interactive(s.to_string()),
+        "defconfig" => ConfigMode::Defconfig(None),
+        "olddefconfig" => ConfigMode::Olddefconfig,
+        "localmodconfig" => ConfigMode::Localmodconfig,
+        "tinyconfig" => ConfigMode::Tinyconfig,
+        "skip" | "none" => ConfigMode::Skip,
+        name if name.ends_with("_defconfig") && !name.contains('/') => ConfigMode::Defconfig(Some(name.to_string())),
+        path if path.contains('/') || path.ends_with(".config") => ConfigMode::CopyFrom(PathBuf::from(path)),
+        other => {
+            return Err(format!(
+                "unknown config mode '{}' (expected menuconfig, nconfig, xconfig, defconfig, <name>_defconfig, \
+                 olddefconfig, localmodconfig, tinyconfig, skip or a path to a .config)",
+                other
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(cmd: &TokioCommand) -> Vec<String> {
+        cmd.as_std().get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn make_command_puts_jobs_first_and_make_vars_last() {
+        let opts = BuildOptions {
+            jobs: Some(4),
+            make_vars: vec![("KCFLAGS".to_string(), "-O1".to_string()), ("W".to_string(), "1".to_string())],
+            verbose: true,
+            ..Default::default()
+        };
+        assert_eq!(args(&opts.make_command(Path::new("."))), ["-j4", "V=1", "KCFLAGS=-O1", "W=1"]);
+    }
+
+    #[test]
+    fn command_line_make_vars_follow_the_profile() {
+        let profile = ToolchainProfile {
+            name: "arm64".to_string(),
+            arch: Some("arm64".to_string()),
+            cross_compile: Some("aarch64-linux-gnu-".to_string()),
+            make_vars: [("KCFLAGS".to_string(), "-O2".to_string())].into(),
+            ..Default::default()
+        };
+        let opts = BuildOptions {
+            jobs: Some(2),
+            make_vars: vec![("ARCH".to_string(), "riscv".to_string())],
+            toolchain: Some(profile),
+            ..Default::default()
+        };
+        assert_eq!(
+            args(&opts.make_command(Path::new("."))),
+            ["-j2", "ARCH=arm64", "CROSS_COMPILE=aarch64-linux-gnu-", "KCFLAGS=-O2", "ARCH=riscv"]
+        );
+    }
+
+    #[test]
+    fn build_dir_follows_the_job_count() {
+        let opts = BuildOptions {
+            jobs: Some(8),
+            build_dir: Some(PathBuf::from("/tmp/out")),
+            make_vars: vec![("W".to_string(), "1".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(args(&opts.make_command(Path::new("."))), ["-j8", "O=/tmp/out", "W=1"]);
+    }
+
+    #[test]
+    fn effective_toolchain_applies_make_var_overrides() {
+        let host = BuildOptions::default().effective_toolchain();
+        assert_eq!(host.name, "host");
+        assert_eq!(host.arch, None);
+
+        let opts = BuildOptions {
+            make_vars: vec![
+                ("ARCH".to_string(), "riscv".to_string()),
+                ("CROSS_COMPILE".to_string(), "riscv64-linux-gnu-".to_string()),
+                ("CC".to_string(), "gcc-13".to_string()),
+                ("KCFLAGS".to_string(), "-O2".to_string()),
+            ],
+            toolchain: Some(ToolchainProfile {
+                name: "arm64".to_string(),
+                arch: Some("arm64".to_string()),
+                ld: Some("ld.bfd".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let profile = opts.effective_toolchain();
+        assert_eq!(profile.name, "arm64");
+        assert_eq!(profile.arch.as_deref(), Some("riscv"));
+        assert_eq!(profile.cross_compile.as_deref(), Some("riscv64-linux-gnu-"));
+        assert_eq!(profile.cc.as_deref(), Some("gcc-13"));
+        assert_eq!(profile.ld.as_deref(), Some("ld.bfd"));
+        assert!(profile.make_vars.is_empty());
+    }
+
+    #[test]
+    fn parse_make_var_needs_a_name_and_equals_sign() {
+        assert_eq!(parse_make_var("CC=clang").unwrap(), ("CC".to_string(), "clang".to_string()));
+        assert_eq!(parse_make_var("KCFLAGS=-O2 -g").unwrap(), ("KCFLAGS".to_string(), "-O2 -g".to_string()));
+        assert_eq!(parse_make_var("EMPTY=").unwrap(), ("EMPTY".to_string(), String::new()));
+        assert!(parse_make_var("CC").is_err());
+        assert!(parse_make_var("=clang").is_err());
+        assert!(parse_make_var("MY VAR=1").is_err());
+    }
+
+    #[test]
+    fn parse_config_mode_tells_targets_from_paths() {
+        assert_eq!(parse_config_mode("defconfig").unwrap(), ConfigMode::Defconfig(None));
+        assert_eq!(
+            parse_config_mode("x86_64_defconfig").unwrap(),
+            ConfigMode::Defconfig(Some("x86_64_defconfig".to_string()))
+        );
+        assert_eq!(parse_config_mode("./my.config").unwrap(), ConfigMode::CopyFrom(PathBuf::from("./my.config")));
+        assert_eq!(parse_config_mode("my.config").unwrap(), ConfigMode::CopyFrom(PathBuf::from("my.config")));
+        assert_eq!(parse_config_mode("foo/bar").unwrap(), ConfigMode::CopyFrom(PathBuf::from("foo/bar")));
+        assert_eq!(
+            parse_config_mode("configs/x86_64_defconfig").unwrap(),
+            ConfigMode::CopyFrom(PathBuf::from("configs/x86_64_defconfig"))
+        );
+        assert_eq!(parse_config_mode("none").unwrap(), ConfigMode::Skip);
+        assert!(parse_config_mode("foo").is_err());
+    }
+
+    #[test]
+    fn format_timestamp_matches_date_u() {
+        // Expected values from `LC_ALL=C date -u -d @<epoch>`.
+        assert_eq!(format_timestamp(0), "Thu Jan  1 00:00:00 UTC 1970");
+        assert_eq!(format_timestamp(951782400), "Tue Feb 29 00:00:00 UTC 2000");
+        assert_eq!(format_timestamp(1709164800), "Thu Feb 29 00:00:00 UTC 2024");
+        assert_eq!(format_timestamp(1709251199), "Thu Feb 29 23:59:59 UTC 2024");
+        assert_eq!(format_timestamp(1717171717), "Fri May 31 16:08:37 UTC 2024");
+        // 2100 is not a leap year.
+        assert_eq!(format_timestamp(4107542400), "Mon Mar  1 00:00:00 UTC 2100");
+    }
+
+    #[test]
+    fn normalize_path_resolves_dot_and_dot_dot() {
+        assert_eq!(normalize_path(Path::new("/src/linux/../build/./x")), PathBuf::from("/src/build/x"));
+        assert_eq!(normalize_path(Path::new("/src/linux/.")), PathBuf::from("/src/linux"));
+        assert_eq!(normalize_path(Path::new("/../..")), PathBuf::from("/"));
+    }
+
+    #[test]
+    fn has_objects_ignores_what_make_clean_keeps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::create_dir_all(dir.join("scripts/mod")).unwrap();
+        std::fs::create_dir_all(dir.join("kernel")).unwrap();
+        std::fs::write(dir.join("scripts/mod/modpost.o"), "").unwrap();
+        std::fs::write(dir.join("kernel/fork.c"), "").unwrap();
+        assert!(!has_objects(dir));
+
+        std::fs::write(dir.join("kernel/fork.o"), "").unwrap();
+        assert!(has_objects(dir));
+    }
+}
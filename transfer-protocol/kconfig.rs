@@ -0,0 +1,1001 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+
+use crate::dotconfig::{ConfigValue, DotConfig};
+
+/// A Kconfig dependency expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Symbol(String),
+    /// `y`, `m`, `n`, quoted strings, numbers and unevaluated `$(...)` macros.
+    Const(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            CompareOp::Eq => "=",
+            CompareOp::Ne => "!=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Symbol(name) | Expr::Const(name) => write!(f, "{}", name),
+            Expr::Not(inner) => match **inner {
+                Expr::Symbol(_) | Expr::Const(_) => write!(f, "!{}", inner),
+                _ => write!(f, "!({})", inner),
+            },
+            Expr::And(a, b) => {
+                write_operand(f, a, true)?;
+                write!(f, " && ")?;
+                write_operand(f, b, true)
+            }
+            Expr::Or(a, b) => write!(f, "{} || {}", a, b),
+            Expr::Compare(op, a, b) => write!(f, "{}{}{}", a, op.as_str(), b),
+        }
+    }
+}
+
+fn write_operand(f: &mut fmt::Formatter<'_>, expr: &Expr, in_and: bool) -> fmt::Result {
+    match expr {
+        Expr::Or(..) if in_and => write!(f, "({})", expr),
+        _ => write!(f, "{}", expr),
+    }
+}
+
+/// Tristate values as Kconfig computes with them: n = 0, m = 1, y = 2.
+pub type Tristate = u8;
+
+fn tristate_name(value: Tristate) -> &'static str {
+    match value {
+        2 => "y",
+        1 => "m",
+        _ => "n",
+    }
+}
+
+impl Expr {
+    fn and(a: Expr, b: Expr) -> Expr {
+        Expr::And(Box::new(a), Box::new(b))
+    }
+
+    /// Evaluates the expression against the symbol values of a `.config`.
+    pub fn eval(&self, config: &DotConfig) -> Tristate {
+        match self {
+            Expr::Symbol(name) => match config.get(name) {
+                ConfigValue::Yes => 2,
+                ConfigValue::Module => 1,
+                _ => 0,
+            },
+            Expr::Const(value) => match value.as_str() {
+                "y" => 2,
+                "m" => 1,
+                _ => 0,
+            },
+            Expr::Not(inner) => 2 - inner.eval(config),
+            Expr::And(a, b) => a.eval(config).min(b.eval(config)),
+            Expr::Or(a, b) => a.eval(config).max(b.eval(config)),
+            Expr::Compare(op, a, b) => {
+                let (a, b) = (a.string_value(config), b.string_value(config));
+                let ordering = match (parse_number(&a), parse_number(&b)) {
+                    (Some(x), Some(y)) => x.cmp(&y),
+                    _ => a.cmp(&b),
+                };
+                let result = match op {
+                    CompareOp::Eq => ordering.is_eq(),
+                    CompareOp::Ne => ordering.is_ne(),
+                    CompareOp::Lt => ordering.is_lt(),
+                    CompareOp::Le => ordering.is_le(),
+                    CompareOp::Gt => ordering.is_gt(),
+                    CompareOp::Ge => ordering.is_ge(),
+                };
+                if result { 2 } else { 0 }
+            }
+        }
+    }
+
+    fn string_value(&self, config: &DotConfig) -> String {
+        match self {
+            Expr::Symbol(name) => match config.get(name) {
+                ConfigValue::Value(value) => value.trim_matches('"').to_string(),
+                _ => tristate_name(self.eval(config)).to_string(),
+            },
+            Expr::Const(value) => value.trim_matches('"').to_string(),
+            other => tristate_name(other.eval(config)).to_string(),
+        }
+    }
+
+    /// The expression with the current value of each symbol, menuconfig
+    /// style: `PCI [=y] && NET [=n]`.
+    pub fn annotated(&self, config: &DotConfig) -> String {
+        match self {
+            Expr::Symbol(name) => format!("{} [={}]", name, symbol_display(config, name)),
+            Expr::Const(value) => value.clone(),
+            Expr::Not(inner) => match **inner {
+                Expr::Symbol(_) | Expr::Const(_) => format!("!{}", inner.annotated(config)),
+                _ => format!("!({})", inner.annotated(config)),
+            },
+            Expr::And(a, b) => {
+                let wrap = |e: &Expr| match e {
+                    Expr::Or(..) => format!("({})", e.annotated(config)),
+                    _ => e.annotated(config),
+                };
+                format!("{} && {}", wrap(a), wrap(b))
+            }
+            Expr::Or(a, b) => format!("{} || {}", a.annotated(config), b.annotated(config)),
+            Expr::Compare(op, a, b) => format!("{}{}{}", a.annotated(config), op.as_str(), b.annotated(config)),
+        }
+    }
+
+    /// The parts of an unmet expression that have to change for it to be
+    /// at least `needed`.
+    fn unmet(&self, config: &DotConfig, needed: Tristate, out: &mut Vec<Requirement>) {
+        if self.eval(config) >= needed {
+            return;
+        }
+        match self {
+            Expr::And(a, b) => {
+                a.unmet(config, needed, out);
+                b.unmet(config, needed, out);
+            }
+            Expr::Symbol(name) => out.push(Requirement::Enable(name.clone())),
+            Expr::Not(inner) => match &**inner {
+                Expr::Symbol(name) => out.push(Requirement::Disable(name.clone())),
+                _ => out.push(Requirement::Expression(self.clone())),
+            },
+            Expr::Or(..) => {
+                let mut alternatives = Vec::new();
+                self.alternatives(&mut alternatives);
+                out.push(Requirement::OneOf(alternatives));
+            }
+            _ => out.push(Requirement::Expression(self.clone())),
+        }
+    }
+
+    fn alternatives(&self, out: &mut Vec<Expr>) {
+        match self {
+            Expr::Or(a, b) => {
+                a.alternatives(out);
+                b.alternatives(out);
+            }
+            other => out.push(other.clone()),
+        }
+    }
+}
+
+fn parse_number(value: &str) -> Option<i64> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn symbol_display(config: &DotConfig, name: &str) -> String {
+    match config.get(name) {
+        ConfigValue::NotSet => "n".to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// Something that must change for an unmet dependency to be satisfied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requirement {
+    Enable(String),
+    Disable(String),
+    OneOf(Vec<Expr>),
+    Expression(Expr),
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Requirement::Enable(name) => write!(f, "enable CONFIG_{}", name),
+            Requirement::Disable(name) => write!(f, "disable CONFIG_{}", name),
+            Requirement::OneOf(options) => {
+                let options: Vec<String> = options.iter().map(|e| e.to_string()).collect();
+                write!(f, "satisfy one of: {}", options.join(", "))
+            }
+            Requirement::Expression(expr) => write!(f, "make {} true", expr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolType {
+    Bool,
+    Tristate,
+    String,
+    Int,
+    Hex,
+}
+
+impl SymbolType {
+    fn from_keyword(keyword: &str) -> Option<Self> {
+        Some(match keyword {
+            "bool" | "boolean" | "def_bool" => SymbolType::Bool,
+            "tristate" | "def_tristate" => SymbolType::Tristate,
+            "string" => SymbolType::String,
+            "int" => SymbolType::Int,
+            "hex" => SymbolType::Hex,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for SymbolType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SymbolType::Bool => "bool",
+            SymbolType::Tristate => "tristate",
+            SymbolType::String => "string",
+            SymbolType::Int => "int",
+            SymbolType::Hex => "hex",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One `config`/`menuconfig` block. A symbol may be defined several times.
+#[derive(Debug, Clone, Default)]
+pub struct Definition {
+    pub file: PathBuf,
+    pub line: usize,
+    pub kind: Option<SymbolType>,
+    pub prompt: Option<String>,
+    /// `depends on` of the entry combined with enclosing `if`, `menu` and `choice` blocks.
+    pub depends: Option<Expr>,
+    /// Condition on the prompt (`bool "Foo" if BAR`).
+    pub prompt_if: Option<Expr>,
+    pub selects: Vec<(String, Option<Expr>)>,
+    pub implies: Vec<(String, Option<Expr>)>,
+    pub defaults: Vec<(Expr, Option<Expr>)>,
+    /// Prompt of the enclosing `choice`, if any.
+    pub choice: Option<String>,
+    pub help: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Symbol {
+    pub name: String,
+    pub definitions: Vec<Definition>,
+}
+
+impl Symbol {
+    pub fn kind(&self) -> Option<SymbolType> {
+        self.definitions.iter().find_map(|d| d.kind)
+    }
+
+    pub fn prompt(&self) -> Option<&str> {
+        self.definitions.iter().find_map(|d| d.prompt.as_deref())
+    }
+
+    /// The combined dependencies; a symbol defined more than once is
+    /// visible when any definition's dependencies hold.
+    pub fn depends(&self) -> Option<Expr> {
+        let mut result: Option<Expr> = None;
+        for definition in &self.definitions {
+            let depends = definition.depends.clone()?;
+            result = Some(match result {
+                Some(previous) => Expr::Or(Box::new(previous), Box::new(depends)),
+                None => depends,
+            });
+        }
+        result
+    }
+}
+
+/// A reverse dependency: `by` selects or implies the symbol.
+#[derive(Debug, Clone)]
+pub struct ReverseDependency {
+    pub by: String,
+    pub condition: Option<Expr>,
+}
+
+/// The symbols of a kernel tree, parsed from its top-level `Kconfig` and
+/// everything it sources.
+#[derive(Debug, Default)]
+pub struct KconfigTree {
+    pub symbols: BTreeMap<String, Symbol>,
+    /// `source` statements whose file could not be found.
+    pub missing_sources: Vec<PathBuf>,
+}
+
+impl KconfigTree {
+    /// Parses `<srctree>/Kconfig`. `srcarch` substitutes `$(SRCARCH)` in
+    /// `source` statements.
+    pub fn parse(srctree: &Path, srcarch: &str) -> Result<Self> {
+        let mut parser = Parser {
+            srctree: srctree.to_path_buf(),
+            vars: BTreeMap::from([
+                ("SRCARCH".to_string(), srcarch.to_string()),
+                ("srctree".to_string(), srctree.display().to_string()),
+            ]),
+            tree: KconfigTree::default(),
+            frames: Vec::new(),
+            entry: None,
+            depth: 0,
+        };
+        parser.parse_file(&srctree.join("Kconfig"))?;
+        parser.finish_entry();
+        Ok(parser.tree)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.get(crate::dotconfig::symbol_name(name))
+    }
+
+    pub fn selected_by(&self, name: &str) -> Vec<ReverseDependency> {
+        self.reverse(name, |d| &d.selects)
+    }
+
+    pub fn implied_by(&self, name: &str) -> Vec<ReverseDependency> {
+        self.reverse(name, |d| &d.implies)
+    }
+
+    fn reverse(&self, name: &str, edges: impl Fn(&Definition) -> &Vec<(String, Option<Expr>)>) -> Vec<ReverseDependency> {
+        let name = crate::dotconfig::symbol_name(name);
+        let mut result = Vec::new();
+        for symbol in self.symbols.values() {
+            for definition in &symbol.definitions {
+                for (target, condition) in edges(definition) {
+                    if target == name {
+                        result.push(ReverseDependency { by: symbol.name.clone(), condition: condition.clone() });
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Symbols whose name or prompt contains `pattern`, case-insensitively,
+    /// like menuconfig's `/` search.
+    pub fn search(&self, pattern: &str) -> Vec<&Symbol> {
+        let pattern = crate::dotconfig::symbol_name(pattern).to_lowercase();
+        self.symbols
+            .values()
+            .filter(|symbol| {
+                symbol.name.to_lowercase().contains(&pattern)
+                    || symbol.prompt().is_some_and(|p| p.to_lowercase().contains(&pattern))
+            })
+            .collect()
+    }
+
+    /// Prints what menuconfig shows for a symbol in its search results.
+    pub fn describe(&self, symbol: &Symbol, config: &DotConfig) {
+        println!(
+            "Symbol: {} [={}]",
+            symbol.name,
+            symbol_display(config, &symbol.name)
+        );
+        if let Some(kind) = symbol.kind() {
+            println!("Type  : {}", kind);
+        }
+        for definition in &symbol.definitions {
+            if let Some(prompt) = &definition.prompt {
+                println!("Prompt: {}", prompt);
+            }
+            println!("  Defined at {}:{}", definition.file.display(), definition.line);
+            if let Some(choice) = &definition.choice {
+                println!("  Choice: {}", choice);
+            }
+            if let Some(depends) = &definition.depends {
+                println!("  Depends on: {}", depends.annotated(config));
+            }
+            if let Some(prompt_if) = &definition.prompt_if {
+                println!("  Visible if: {}", prompt_if.annotated(config));
+            }
+        }
+        let selects: BTreeSet<&String> = symbol.definitions.iter().flat_map(|d| d.selects.iter().map(|(s, _)| s)).collect();
+        if !selects.is_empty() {
+            let selects: Vec<String> = selects.iter().map(|s| format!("{} [={}]", s, symbol_display(config, s))).collect();
+            println!("Selects: {}", selects.join(" && "));
+        }
+        for (label, reverse) in [("Selected by", self.selected_by(&symbol.name)), ("Implied by", self.implied_by(&symbol.name))] {
+            if reverse.is_empty() {
+                continue;
+            }
+            println!("{}:", label);
+            for dependency in reverse {
+                let mut expr = Expr::Symbol(dependency.by);
+                if let Some(condition) = dependency.condition {
+                    expr = Expr::and(expr, condition);
+                }
+                println!("  - {}", expr.annotated(config));
+            }
+        }
+        println!();
+    }
+
+    /// Explains the value of `name` in `config`: whether its dependencies
+    /// hold, what selects it and, when it cannot be enabled, which symbols
+    /// would have to be enabled (or disabled) first.
+    pub fn explain(&self, name: &str, config: &DotConfig) -> Result<()> {
+        let symbol = self
+            .get(name)
+            .with_context(|| format!("CONFIG_{} is not defined by any Kconfig file", crate::dotconfig::symbol_name(name)))?;
+        self.describe(symbol, config);
+
+        let value = config.get(&symbol.name);
+        let depends = symbol.depends();
+        let deps_value = depends.as_ref().map_or(2, |d| d.eval(config));
+        let selected: Vec<ReverseDependency> = self
+            .selected_by(&symbol.name)
+            .into_iter()
+            .filter(|r| Expr::Symbol(r.by.clone()).eval(config) > 0 && r.condition.as_ref().is_none_or(|c| c.eval(config) > 0))
+            .collect();
+
+        match value {
+            ConfigValue::NotSet => println!("CONFIG_{} is not set.", symbol.name),
+            value => println!("CONFIG_{} is set to {}.", symbol.name, value),
+        }
+        for reverse in &selected {
+            println!("It is forced on by CONFIG_{} (select).", reverse.by);
+        }
+
+        if deps_value == 0 {
+            println!("Its dependencies are not met:");
+            let mut seen = BTreeSet::from([symbol.name.clone()]);
+            self.print_requirements(depends.as_ref().expect("unmet dependencies exist"), config, 2, 1, &mut seen);
+        } else if deps_value == 1 && symbol.kind() == Some(SymbolType::Tristate) {
+            println!("Its dependencies evaluate to m, so it can be built as a module only.");
+        } else if !value.is_set() {
+            let visible = symbol.definitions.iter().any(|d| d.prompt.is_some());
+            if visible {
+                println!("Its dependencies are met; enable it with --set CONFIG_{}=y.", symbol.name);
+            } else {
+                println!("It has no prompt; it is only enabled by a default or by a symbol that selects it.");
+            }
+        }
+        Ok(())
+    }
+
+    fn print_requirements(&self, expr: &Expr, config: &DotConfig, needed: Tristate, depth: usize, seen: &mut BTreeSet<String>) {
+        let mut requirements = Vec::new();
+        expr.unmet(config, needed, &mut requirements);
+        for requirement in requirements {
+            // Follow the chain: a symbol that has to be enabled may itself
+            // have unmet dependencies.
+            if let Requirement::Enable(name) = &requirement {
+                if !seen.insert(name.clone()) {
+                    println!("{}- {} (see above)", "  ".repeat(depth), requirement);
+                    continue;
+                }
+                println!("{}- {}", "  ".repeat(depth), requirement);
+                if let Some(depends) = self.get(name).and_then(|s| s.depends()) {
+                    if depends.eval(config) == 0 && depth < 8 {
+                        self.print_requirements(&depends, config, 2, depth + 1, seen);
+                    }
+                }
+            } else {
+                println!("{}- {}", "  ".repeat(depth), requirement);
+            }
+        }
+    }
+}
+
+/// Kernel source architecture directory for an `ARCH` value, or for the host
+/// when none is given.
+pub fn srcarch(arch: Option<&str>) -> String {
+    let arch = arch.unwrap_or(match std::env::consts::ARCH {
+        "x86_64" | "x86" => "x86",
+        "aarch64" => "arm64",
+        "riscv64" => "riscv",
+        "powerpc64" | "powerpc" => "powerpc",
+        "s390x" => "s390",
+        "loongarch64" => "loongarch",
+        "mips" | "mips64" => "mips",
+        other => other,
+    });
+    match arch {
+        "i386" | "x86_64" => "x86",
+        "sparc32" | "sparc64" => "sparc",
+        "parisc64" => "parisc",
+        "sh64" => "sh",
+        other => other,
+    }
+    .to_string()
+}
+
+enum Entry {
+    Config(String, Box<Definition>),
+    /// `menu` and `choice` attributes go to the block's frame.
+    Block,
+    /// `comment` attributes only affect the comment itself.
+    Comment,
+}
+
+struct Frame {
+    keyword: &'static str,
+    conditions: Vec<Expr>,
+    choice: Option<String>,
+}
+
+struct Parser {
+    srctree: PathBuf,
+    vars: BTreeMap<String, String>,
+    tree: KconfigTree,
+    frames: Vec<Frame>,
+    entry: Option<Entry>,
+    depth: usize,
+}
+
+impl Parser {
+    fn parse_file(&mut self, path: &Path) -> Result<()> {
+        if self.depth > 64 {
+            anyhow::bail!("Kconfig sources nest too deeply at {:?}", path);
+        }
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let display = path.strip_prefix(&self.srctree).unwrap_or(path).to_path_buf();
+        let lines: Vec<&str> = text.lines().collect();
+
+        let mut i = 0;
+        while i < lines.len() {
+            let number = i + 1;
+            let mut line = lines[i].to_string();
+            while line.ends_with('\\') && i + 1 < lines.len() {
+                line.pop();
+                i += 1;
+                line.push_str(lines[i]);
+            }
+            i += 1;
+
+            let tokens = tokenize(&line).with_context(|| format!("{}:{}: cannot parse '{}'", display.display(), number, line.trim()))?;
+            let Some(keyword) = tokens.first().map(|t| t.text.clone()) else {
+                continue;
+            };
+
+            if keyword == "help" || keyword == "---help---" {
+                let (help, next) = read_help(&lines, i);
+                if let Some(Entry::Config(_, definition)) = &mut self.entry {
+                    definition.help = Some(help);
+                }
+                i = next;
+                continue;
+            }
+
+            self.statement(&keyword, &tokens[1..], &display, number, path)
+                .with_context(|| format!("{}:{}: cannot parse '{}'", display.display(), number, line.trim()))?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, keyword: &str, args: &[Token], file: &Path, line: usize, path: &Path) -> Result<()> {
+        match keyword {
+            "config" | "menuconfig" => {
+                self.finish_entry();
+                let name = word(args, 0)?;
+                let choice = self.frames.iter().rev().find_map(|f| f.choice.clone());
+                self.entry = Some(Entry::Config(
+                    name,
+                    Box::new(Definition { file: file.to_path_buf(), line, choice, ..Default::default() }),
+                ));
+            }
+            "choice" => {
+                self.finish_entry();
+                self.frames.push(Frame { keyword: "choice", conditions: Vec::new(), choice: Some(String::from("<choice>")) });
+                self.entry = Some(Entry::Block);
+            }
+            "menu" => {
+                self.finish_entry();
+                self.frames.push(Frame { keyword: "menu", conditions: Vec::new(), choice: None });
+                self.entry = Some(Entry::Block);
+            }
+            "if" => {
+                self.finish_entry();
+                let condition = parse_expr(args)?;
+                self.frames.push(Frame { keyword: "if", conditions: vec![condition], choice: None });
+            }
+            "endchoice" | "endmenu" | "endif" => {
+                self.finish_entry();
+                let expected = &keyword[3..];
+                match self.frames.pop() {
+                    Some(frame) if frame.keyword == expected => {}
+                    _ => anyhow::bail!("unexpected '{}'", keyword),
+                }
+            }
+            "comment" => {
+                self.finish_entry();
+                self.entry = Some(Entry::Comment);
+            }
+            "mainmenu" => self.finish_entry(),
+            "source" | "rsource" | "osource" | "orsource" => {
+                self.finish_entry();
+                let target = self.expand(&word(args, 0)?);
+                let resolved = if keyword.contains("rsource") {
+                    path.parent().unwrap_or(Path::new(".")).join(target)
+                } else {
+                    self.srctree.join(target)
+                };
+                if resolved.is_file() {
+                    self.depth += 1;
+                    let result = self.parse_file(&resolved);
+                    self.depth -= 1;
+                    result?;
+                    // An entry left open at the end of a sourced file ends there.
+                    self.finish_entry();
+                } else if !keyword.starts_with('o') {
+                    self.tree.missing_sources.push(resolved);
+                }
+            }
+            _ => self.attribute(keyword, args)?,
+        }
+        Ok(())
+    }
+
+    fn attribute(&mut self, keyword: &str, args: &[Token]) -> Result<()> {
+        let (args, condition) = split_if(args)?;
+        match (&mut self.entry, keyword) {
+            (Some(Entry::Block), "depends") => {
+                let expr = parse_expr(strip_on(args))?;
+                if let Some(frame) = self.frames.last_mut() {
+                    frame.conditions.push(expr);
+                }
+            }
+            (Some(Entry::Block), "prompt") => {
+                if let Some(frame) = self.frames.last_mut().filter(|f| f.keyword == "choice") {
+                    frame.choice = args.first().map(|t| t.text.clone());
+                }
+            }
+            (Some(Entry::Block), _) => {}
+            (Some(Entry::Config(_, definition)), keyword) => {
+                if let Some(kind) = SymbolType::from_keyword(keyword) {
+                    definition.kind = Some(kind);
+                    if keyword.starts_with("def_") {
+                        definition.defaults.push((parse_expr(args)?, condition));
+                    } else if let Some(prompt) = args.first() {
+                        definition.prompt = Some(prompt.text.clone());
+                        definition.prompt_if = condition;
+                    }
+                    return Ok(());
+                }
+                match keyword {
+                    "prompt" => {
+                        definition.prompt = args.first().map(|t| t.text.clone());
+                        definition.prompt_if = condition;
+                    }
+                    "depends" => {
+                        let expr = parse_expr(strip_on(args))?;
+                        definition.depends = Some(match definition.depends.take() {
+                            Some(previous) => Expr::and(previous, expr),
+                            None => expr,
+                        });
+                    }
+                    "select" => definition.selects.push((word(args, 0)?, condition)),
+                    "imply" => definition.implies.push((word(args, 0)?, condition)),
+                    "default" => definition.defaults.push((parse_expr(args)?, condition)),
+                    // range, option, modules, transitional, visible, ...
+                    _ => {}
+                }
+            }
+            // Attributes outside any entry (e.g. after `if`) are ignored.
+            (Some(Entry::Comment), _) | (None, _) => {}
+        }
+        Ok(())
+    }
+
+    /// Closes the current entry, folding the enclosing block conditions into
+    /// its dependencies.
+    fn finish_entry(&mut self) {
+        let Some(Entry::Config(name, mut definition)) = self.entry.take() else {
+            return;
+        };
+        let mut depends = definition.depends.take();
+        for condition in self.frames.iter().rev().flat_map(|f| f.conditions.iter().rev()) {
+            depends = Some(match depends {
+                Some(expr) => Expr::and(condition.clone(), expr),
+                None => condition.clone(),
+            });
+        }
+        definition.depends = depends;
+        let symbol = self.tree.symbols.entry(name.clone()).or_insert_with(|| Symbol { name, definitions: Vec::new() });
+        symbol.definitions.push(*definition);
+    }
+
+    fn expand(&self, text: &str) -> String {
+        let mut result = text.to_string();
+        for (name, value) in &self.vars {
+            result = result.replace(&format!("$({})", name), value);
+        }
+        result
+    }
+}
+
+/// Collects an indented help text starting at `start`; returns it and the
+/// index of the first line after it.
+fn read_help(lines: &[&str], start: usize) -> (String, usize) {
+    let indent_of = |line: &str| line.len() - line.trim_start().len();
+    let mut i = start;
+    while i < lines.len() && lines[i].trim().is_empty() {
+        i += 1;
+    }
+    let Some(first) = lines.get(i) else {
+        return (String::new(), i);
+    };
+    let indent = indent_of(&first.replace('\t', "        "));
+    let mut text = Vec::new();
+    while i < lines.len() {
+        let line = lines[i].replace('\t', "        ");
+        if !line.trim().is_empty() && indent_of(&line) < indent {
+            break;
+        }
+        text.push(line.get(indent..).unwrap_or("").trim_end().to_string());
+        i += 1;
+    }
+    while text.last().is_some_and(|l| l.is_empty()) {
+        text.pop();
+    }
+    (text.join("\n"), i)
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' {
+            break;
+        } else if c == '"' || c == '\'' {
+            let mut text = String::new();
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' && i + 1 < chars.len() {
+                    i += 1;
+                }
+                text.push(chars[i]);
+                i += 1;
+            }
+            if i == chars.len() {
+                anyhow::bail!("unterminated string");
+            }
+            i += 1;
+            tokens.push(Token { text, quoted: true });
+        } else if let Some(op) = ["&&", "||", "!=", "<=", ">="].iter().find(|op| line_starts_with(&chars, i, op)) {
+            tokens.push(Token { text: op.to_string(), quoted: false });
+            i += 2;
+        } else if "()!=<>".contains(c) {
+            tokens.push(Token { text: c.to_string(), quoted: false });
+            i += 1;
+        } else {
+            let start = i;
+            let mut nesting = 0;
+            while i < chars.len() {
+                let c = chars[i];
+                if c == '$' && chars.get(i + 1) == Some(&'(') {
+                    nesting += 1;
+                    i += 2;
+                    continue;
+                }
+                if nesting > 0 {
+                    if c == '(' {
+                        nesting += 1;
+                    } else if c == ')' {
+                        nesting -= 1;
+                    }
+                } else if c.is_whitespace() || "()!=<>&|\"'#".contains(c) {
+                    break;
+                }
+                i += 1;
+            }
+            tokens.push(Token { text: chars[start..i].iter().collect(), quoted: false });
+        }
+    }
+    Ok(tokens)
+}
+
+fn line_starts_with(chars: &[char], at: usize, op: &str) -> bool {
+    op.chars().enumerate().all(|(k, c)| chars.get(at + k) == Some(&c))
+}
+
+fn word(args: &[Token], index: usize) -> Result<String> {
+    args.get(index).map(|t| t.text.clone()).context("missing argument")
+}
+
+fn strip_on(args: &[Token]) -> &[Token] {
+    match args.first() {
+        Some(t) if !t.quoted && t.text == "on" => &args[1..],
+        _ => args,
+    }
+}
+
+/// Splits a trailing `if <expr>` off an attribute.
+fn split_if(args: &[Token]) -> Result<(&[Token], Option<Expr>)> {
+    match args.iter().position(|t| !t.quoted && t.text == "if") {
+        Some(index) => Ok((&args[..index], Some(parse_expr(&args[index + 1..])?))),
+        None => Ok((args, None)),
+    }
+}
+
+fn parse_expr(tokens: &[Token]) -> Result<Expr> {
+    let mut pos = 0;
+    let expr = parse_or(tokens, &mut pos)?;
+    if pos != tokens.len() {
+        anyhow::bail!("unexpected '{}' in expression", tokens[pos].text);
+    }
+    Ok(expr)
+}
+
+fn is_op(tokens: &[Token], pos: usize, op: &str) -> bool {
+    tokens.get(pos).is_some_and(|t| !t.quoted && t.text == op)
+}
+
+fn parse_or(tokens: &[Token], pos: &mut usize) -> Result<Expr> {
+    let mut expr = parse_and(tokens, pos)?;
+    while is_op(tokens, *pos, "||") {
+        *pos += 1;
+        expr = Expr::Or(Box::new(expr), Box::new(parse_and(tokens, pos)?));
+    }
+    Ok(expr)
+}
+
+fn parse_and(tokens: &[Token], pos: &mut usize) -> Result<Expr> {
+    let mut expr = parse_unary(tokens, pos)?;
+    while is_op(tokens, *pos, "&&") {
+        *pos += 1;
+        expr = Expr::and(expr, parse_unary(tokens, pos)?);
+    }
+    Ok(expr)
+}
+
+fn parse_unary(tokens: &[Token], pos: &mut usize) -> Result<Expr> {
+    if is_op(tokens, *pos, "!") {
+        *pos += 1;
+        return Ok(Expr::Not(Box::new(parse_unary(tokens, pos)?)));
+    }
+    if is_op(tokens, *pos, "(") {
+        *pos += 1;
+        let expr = parse_or(tokens, pos)?;
+        if !is_op(tokens, *pos, ")") {
+            anyhow::bail!("missing ')' in expression");
+        }
+        *pos += 1;
+        return Ok(expr);
+    }
+    let left = parse_atom(tokens, pos)?;
+    let op = match tokens.get(*pos).filter(|t| !t.quoted).map(|t| t.text.as_str()) {
+        Some("=") => CompareOp::Eq,
+        Some("!=") => CompareOp::Ne,
+        Some("<") => CompareOp::Lt,
+        Some("<=") => CompareOp::Le,
+        Some(">") => CompareOp::Gt,
+        Some(">=") => CompareOp::Ge,
+        _ => return Ok(left),
+    };
+    *pos += 1;
+    let right = parse_atom(tokens, pos)?;
+    Ok(Expr::Compare(op, Box::new(left), Box::new(right)))
+}
+
+fn parse_atom(tokens: &[Token], pos: &mut usize) -> Result<Expr> {
+    let token = tokens.get(*pos).context("expression ends early")?;
+    *pos += 1;
+    let text = token.text.clone();
+    Ok(if token.quoted {
+        Expr::Const(format!("\"{}\"", text))
+    } else if matches!(text.as_str(), "y" | "m" | "n") || text.starts_with("$(") || parse_number(&text).is_some() {
+        Expr::Const(text)
+    } else if text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Expr::Symbol(text)
+    } else {
+        anyhow::bail!("unexpected '{}' in expression", text)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(files: &[(&str, &str)]) -> (tempfile::TempDir, KconfigTree) {
+        let dir = tempfile::tempdir().unwrap();
+        for (file, text) in files {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        let tree = KconfigTree::parse(dir.path(), "x86").unwrap();
+        (dir, tree)
+    }
+
+    #[test]
+    fn block_conditions_fold_into_depends() {
+        let (_, tree) = tree(
+            &[(
+                "Kconfig",
+                "if NET\nmenu \"Drivers\"\n\tdepends on PCI\nconfig FOO\n\ttristate \"Foo\"\n\tdepends on BAR || BAZ\nendmenu\nendif\n",
+            )],
+        );
+        let foo = tree.get("CONFIG_FOO").unwrap();
+        assert_eq!(foo.kind(), Some(SymbolType::Tristate));
+        assert_eq!(foo.depends().unwrap().to_string(), "NET && PCI && (BAR || BAZ)");
+
+        let config = DotConfig::parse("CONFIG_NET=y\nCONFIG_PCI=y\nCONFIG_BAZ=m\n").unwrap();
+        assert_eq!(foo.depends().unwrap().eval(&config), 1);
+        let config = DotConfig::parse("CONFIG_PCI=y\nCONFIG_BAR=y\n").unwrap();
+        assert_eq!(foo.depends().unwrap().eval(&config), 0);
+    }
+
+    #[test]
+    fn selects_and_implies_are_found_in_reverse() {
+        let (_, tree) = tree(
+            &[(
+                "Kconfig",
+                "config A\n\tbool \"A\"\n\tselect C if B\n\timply D\nconfig C\n\tbool\nconfig D\n\tbool \"D\"\n",
+            )],
+        );
+        let selected = tree.selected_by("C");
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].by, "A");
+        assert_eq!(selected[0].condition, Some(Expr::Symbol("B".to_string())));
+        assert_eq!(tree.implied_by("D")[0].by, "A");
+        assert!(tree.selected_by("D").is_empty());
+    }
+
+    #[test]
+    fn source_resolves_against_the_tree_and_the_file() {
+        let (dir, tree) = tree(
+            &[
+                ("Kconfig", "source \"arch/$(SRCARCH)/Kconfig\"\nsource \"missing/Kconfig\"\nosource \"optional/Kconfig\"\n"),
+                ("arch/x86/Kconfig", "config X86\n\tdef_bool y\nrsource \"sub/Kconfig\"\n"),
+                ("arch/x86/sub/Kconfig", "config X86_SUB\n\tbool \"Sub\"\n"),
+            ],
+        );
+        assert!(tree.get("X86").is_some());
+        let sub = tree.get("X86_SUB").unwrap();
+        assert_eq!(sub.definitions[0].file, Path::new("arch/x86/sub/Kconfig"));
+        assert_eq!(tree.missing_sources, vec![dir.path().join("missing/Kconfig")]);
+    }
+
+    #[test]
+    fn srctree_macro_expands_in_source() {
+        let (_, tree) = tree(
+            &[("Kconfig", "source \"$(srctree)/lib/Kconfig\"\n"), ("lib/Kconfig", "config LIB\n\tbool\n")],
+        );
+        assert!(tree.get("LIB").is_some());
+        assert!(tree.missing_sources.is_empty());
+    }
+
+    #[test]
+    fn compare_and_macro_constants_parse() {
+        let (_, tree) = tree(
+            &[("Kconfig", "config CC\n\tdef_bool $(success,true)\nconfig X\n\tint \"X\"\n\tdepends on NR >= 0x10 && !CC\n")],
+        );
+        let cc = &tree.get("CC").unwrap().definitions[0];
+        assert_eq!(cc.defaults[0].0, Expr::Const("$(success,true)".to_string()));
+
+        let depends = tree.get("X").unwrap().depends().unwrap();
+        assert_eq!(depends.to_string(), "NR>=0x10 && !CC");
+        assert_eq!(depends.eval(&DotConfig::parse("CONFIG_NR=16\n").unwrap()), 2);
+        assert_eq!(depends.eval(&DotConfig::parse("CONFIG_NR=15\n").unwrap()), 0);
+    }
+}
@@ -0,0 +1,407 @@
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use openssl::pkey::{Id, PKey};
+use openssl::sign::Verifier;
+use serde::Deserialize;
+
+use crate::extract::hex_digest;
+
+/// Copy of the last `KTP.mk` that was allowed to run, kept in the output
+/// directory so that a changed one can be shown as a diff.
+pub const APPROVED_COPY: &str = ".ktp-mk.approved";
+
+/// When a `KTP.mk` found in a tree may be executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustPolicy {
+    /// Never run it.
+    Deny,
+    /// Run it if its SHA-256 is on the allowlist, otherwise show what it
+    /// runs and ask; denied without a terminal.
+    #[default]
+    Prompt,
+    /// Run it if its SHA-256 is on the local allowlist.
+    Allowlist,
+    /// Run it if `KTP.mk.sig` is a valid signature by a trusted key.
+    Signed,
+}
+
+impl TrustPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            TrustPolicy::Deny => "deny",
+            TrustPolicy::Prompt => "prompt",
+            TrustPolicy::Allowlist => "allowlist",
+            TrustPolicy::Signed => "signed",
+        }
+    }
+}
+
+pub fn parse_trust_policy(s: &str) -> Result<TrustPolicy, String> {
+    match s {
+        "deny" => Ok(TrustPolicy::Deny),
+        "prompt" => Ok(TrustPolicy::Prompt),
+        "allowlist" => Ok(TrustPolicy::Allowlist),
+        "signed" => Ok(TrustPolicy::Signed),
+        other => Err(format!("unknown KTP.mk policy '{}' (expected deny, prompt, allowlist or signed)", other)),
+    }
+}
+
+/// The `[ktp_mk]` section.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct KtpMkConfig {
+    pub policy: TrustPolicy,
+    /// File of allowed SHA-256 hashes, one per line as `sha256sum` prints
+    /// them; defaults to `ktp-mk.allow` next to the config file.
+    pub allowlist: Option<PathBuf>,
+    /// PEM public keys (RSA, EC or Ed25519) whose signatures are trusted.
+    pub trusted_keys: Vec<PathBuf>,
+}
+
+impl KtpMkConfig {
+    /// The configured allowlist, or `ktp-mk.allow` next to `config_path`,
+    /// the config file that was loaded (`KtpConfig::path`).
+    pub fn allowlist_path(&self, config_path: Option<&Path>) -> Option<PathBuf> {
+        self.allowlist
+            .clone()
+            .or_else(|| config_path.and_then(Path::parent).map(|dir| dir.join("ktp-mk.allow")))
+    }
+}
+
+/// The outcome of applying the policy to one file.
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub allowed: bool,
+    pub sha256: String,
+    pub reason: String,
+    /// The contents the decision was made on.
+    pub contents: String,
+}
+
+/// Decides whether the file at `path` (a `KTP.mk`, or a `KTP.toml` with
+/// hooks) may run, showing what it would run first: a diff against
+/// `previous`, the last approved copy, or else what `summarize` prints.
+/// `allowlist` is the resolved [`KtpMkConfig::allowlist_path`].
+pub fn evaluate(
+    config: &KtpMkConfig,
+    allowlist: Option<&Path>,
+    path: &Path,
+    previous: Option<&str>,
+    summarize: fn(&str),
+) -> Result<Decision> {
+    let name = path.file_name().map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned());
+    let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let sha256 = hex_digest(&openssl::sha::sha256(text.as_bytes()));
+    let decision =
+        |allowed: bool, reason: String| Decision { allowed, sha256: sha256.clone(), reason, contents: text.clone() };
+
+    println!("{} policy: {}; {:?} has SHA-256 {}", name, config.policy.name(), path, sha256);
+    if config.policy != TrustPolicy::Deny {
+        show_changes(&name, &text, previous, summarize);
+        // Included makefiles are read from the tree when make runs, so
+        // neither the hash nor the approved copy would cover them.
+        let includes = if path.extension().is_some_and(|ext| ext == "mk") { includes(&text) } else { Vec::new() };
+        if !includes.is_empty() {
+            return Ok(decision(
+                false,
+                format!("{} includes {}, which its approval would not cover", name, includes.join(", ")),
+            ));
+        }
+    }
+
+    Ok(match config.policy {
+        TrustPolicy::Deny => decision(false, format!("the policy denies running {}", name)),
+        TrustPolicy::Allowlist => {
+            let allowlist = allowlist.context("No allowlist configured ([ktp_mk] allowlist)")?;
+            if allowlisted(allowlist, &sha256)? {
+                decision(true, format!("hash is on the allowlist {:?}", allowlist))
+            } else {
+                println!("To allow this version: echo '{}  {}' >> {:?}", sha256, name, allowlist);
+                decision(false, format!("hash is not on the allowlist {:?}", allowlist))
+            }
+        }
+        TrustPolicy::Signed => {
+            let signature = path.with_file_name(format!("{}.sig", name));
+            match verify_signature(text.as_bytes(), &signature, &config.trusted_keys)? {
+                Some(key) => decision(true, format!("signed by trusted key {:?}", key)),
+                None if !signature.exists() => decision(false, format!("no signature at {:?}", signature)),
+                None => decision(false, "the signature does not match any trusted key".to_string()),
+            }
+        }
+        TrustPolicy::Prompt => {
+            // Versions approved with "always" earlier are not asked about again.
+            if let Some(allowlist) = allowlist {
+                if allowlisted(allowlist, &sha256)? {
+                    return Ok(decision(true, format!("hash is on the allowlist {:?}", allowlist)));
+                }
+            }
+            if !std::io::stdin().is_terminal() {
+                return Ok(decision(false, "no terminal to ask for confirmation".to_string()));
+            }
+            print!("Run this {}? [y]es / [N]o / [a]lways allow this version: ", name);
+            std::io::stdout().flush()?;
+            let mut answer = String::new();
+            std::io::stdin().lock().read_line(&mut answer)?;
+            match answer.trim().to_ascii_lowercase().as_str() {
+                "y" | "yes" => decision(true, "confirmed at the prompt".to_string()),
+                "a" | "always" => {
+                    let allowlist = allowlist.context("No allowlist configured ([ktp_mk] allowlist)")?;
+                    add_to_allowlist(allowlist, &sha256, path)?;
+                    decision(true, format!("confirmed at the prompt and added to {:?}", allowlist))
+                }
+                _ => decision(false, "declined at the prompt".to_string()),
+            }
+        }
+    })
+}
+
+/// Prints a diff against the last approved copy, or a summary when there
+/// is none to compare with.
+fn show_changes(name: &str, text: &str, previous: Option<&str>, summarize: fn(&str)) {
+    match previous {
+        Some(previous) if previous == text => println!("{} is unchanged since it was last approved.", name),
+        Some(previous) => {
+            println!("{} changed since it was last approved:", name);
+            for line in diff(previous, text) {
+                println!("  {}", line);
+            }
+            print_notable(text);
+        }
+        None => summarize(text),
+    }
+}
+
+/// Rules and the commands their recipes run, plus anything that reaches
+/// outside the build: includes, `$(shell)`, network tools, privilege
+/// escalation and recursive deletes.
+pub fn print_summary(text: &str) {
+    println!("KTP.mk will run:");
+    let mut target: Option<&str> = None;
+    for (number, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if let Some(recipe) = line.strip_prefix('\t') {
+            if target.is_some() {
+                println!("  {:>4}      {}", number + 1, recipe.trim());
+            }
+        } else if let Some(file) = include_directive(trimmed) {
+            println!("  {:>4}  includes {}", number + 1, file);
+        } else if let Some(name) = rule_target(trimmed) {
+            target = Some(name);
+            println!("  {:>4}  {}:", number + 1, name);
+        } else {
+            target = None;
+        }
+    }
+    print_notable(text);
+}
+
+/// The files named by an `include`, `-include` or `sinclude` line.
+fn include_directive(line: &str) -> Option<&str> {
+    ["include ", "-include ", "sinclude "].iter().find_map(|p| line.strip_prefix(p)).map(str::trim)
+}
+
+/// `file (line N)` for every include directive outside a recipe.
+fn includes(text: &str) -> Vec<String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.starts_with('\t'))
+        .filter_map(|(number, line)| include_directive(line.trim()).map(|file| format!("{} (line {})", file, number + 1)))
+        .collect()
+}
+
+/// Lines that reach outside the build, with what each does.
+pub fn print_notable(text: &str) {
+    const NOTABLE: &[(&str, &str)] = &[
+        ("$(shell", "runs a command while the makefile is read"),
+        ("curl ", "network access"),
+        ("wget ", "network access"),
+        ("git clone", "network access"),
+        ("git fetch", "network access"),
+        ("scp ", "network access"),
+        ("ssh ", "network access"),
+        ("rsync ", "file transfer"),
+        ("sudo ", "privilege escalation"),
+        ("doas ", "privilege escalation"),
+        ("rm -rf", "recursive delete"),
+        ("rm -fr", "recursive delete"),
+        ("| sh", "pipes into a shell"),
+        ("| bash", "pipes into a shell"),
+        ("eval ", "evaluates generated code"),
+    ];
+    let mut found = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        for (needle, what) in NOTABLE {
+            if line.contains(needle) {
+                found.push(format!("line {}: {} ('{}')", number + 1, what, needle.trim()));
+            }
+        }
+    }
+    if !found.is_empty() {
+        println!("Notable:");
+        for item in found {
+            println!("  {}", item);
+        }
+    }
+}
+
+/// The first target of a rule line, or `None` for assignments and special
+/// targets such as `.PHONY`.
+fn rule_target(line: &str) -> Option<&str> {
+    let (targets, rest) = line.split_once(':')?;
+    // `:=`, `::=` and `:::=` assign; `all:: x` is a double-colon rule.
+    if rest.trim_start_matches(':').starts_with('=') || targets.contains('=') {
+        return None;
+    }
+    targets.split_whitespace().next().filter(|name| !name.starts_with('.'))
+}
+
+/// Line diff of `old` and `new` (longest common subsequence), with only
+/// the changed lines, prefixed `-` and `+` and numbered as in each file.
+pub fn diff(old: &str, new: &str) -> Vec<String> {
+    let (a, b): (Vec<&str>, Vec<&str>) = (old.lines().collect(), new.lines().collect());
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j, mut out) = (0, 0, Vec::new());
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(format!("-{:>4} {}", i + 1, a[i]));
+            i += 1;
+        } else {
+            out.push(format!("+{:>4} {}", j + 1, b[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+fn allowlisted(allowlist: &Path, sha256: &str) -> Result<bool> {
+    let text = match std::fs::read_to_string(allowlist) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", allowlist)),
+    };
+    Ok(text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        .any(|hash| hash.eq_ignore_ascii_case(sha256)))
+}
+
+fn add_to_allowlist(allowlist: &Path, sha256: &str, path: &Path) -> Result<()> {
+    if let Some(dir) = allowlist.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(allowlist)
+        .with_context(|| format!("Failed to open {:?}", allowlist))?;
+    writeln!(file, "{}  {}", sha256, path.display())?;
+    Ok(())
+}
+
+/// The trusted key `signature` verifies `data` with, if any. Signatures are
+/// raw or base64, as `openssl dgst -sha256 -sign` (or `openssl pkeyutl
+/// -sign -rawin` for Ed25519) writes them.
+fn verify_signature(data: &[u8], signature: &Path, keys: &[PathBuf]) -> Result<Option<PathBuf>> {
+    if keys.is_empty() {
+        anyhow::bail!("The signed KTP.mk policy needs trusted keys ([ktp_mk] trusted_keys)");
+    }
+    let Ok(raw) = std::fs::read(signature) else {
+        return Ok(None);
+    };
+    let text: String = String::from_utf8_lossy(&raw).split_whitespace().collect();
+    let decoded = openssl::base64::decode_block(&text).ok();
+
+    for key_path in keys {
+        let pem = std::fs::read(key_path).with_context(|| format!("Failed to read trusted key {:?}", key_path))?;
+        let key = PKey::public_key_from_pem(&pem).with_context(|| format!("Invalid public key {:?}", key_path))?;
+        for candidate in std::iter::once(&raw).chain(decoded.as_ref()) {
+            let mut verifier = if key.id() == Id::ED25519 {
+                Verifier::new_without_digest(&key)?
+            } else {
+                Verifier::new(openssl::hash::MessageDigest::sha256(), &key)?
+            };
+            if verifier.verify_oneshot(candidate, data).unwrap_or(false) {
+                return Ok(Some(key_path.clone()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rule_target_skips_assignments_and_special_targets() {
+        assert_eq!(rule_target("all: vmlinux modules"), Some("all"));
+        assert_eq!(rule_target("prepare sync:"), Some("prepare"));
+        assert_eq!(rule_target("%.o: %.c"), Some("%.o"));
+        assert_eq!(rule_target("install:: all"), Some("install"));
+        assert_eq!(rule_target("CC := clang"), None);
+        assert_eq!(rule_target("CC ::= clang"), None);
+        assert_eq!(rule_target("URL = https://example.com/x"), None);
+        assert_eq!(rule_target(".PHONY: all"), None);
+        assert_eq!(rule_target("no colon here"), None);
+    }
+
+    #[test]
+    fn diff_lists_changed_lines_with_their_numbers() {
+        let old = "all:\n\tmake -C linux\n\techo done\n";
+        let new = "all:\n\tcurl -s https://x | sh\n\tmake -C linux\n\techo done\n";
+        assert_eq!(diff(old, new), vec!["+   2 \tcurl -s https://x | sh"]);
+        assert_eq!(diff(new, old), vec!["-   2 \tcurl -s https://x | sh"]);
+        assert_eq!(diff("a\nb\n", "a\nc\n"), vec!["-   2 b", "+   2 c"]);
+        assert!(diff(old, old).is_empty());
+        assert_eq!(diff("", "x"), vec!["+   1 x"]);
+    }
+
+    #[test]
+    fn prompt_allows_versions_approved_with_always() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let ktp_mk = dir.join("KTP.mk");
+        std::fs::write(&ktp_mk, "all:\n\techo hi\n").unwrap();
+        // The default allowlist sits next to the loaded config file.
+        let allowlist = KtpMkConfig::default().allowlist_path(Some(&dir.join("config.toml"))).unwrap();
+        assert_eq!(allowlist, dir.join("ktp-mk.allow"));
+        let sha256 = hex_digest(&openssl::sha::sha256(b"all:\n\techo hi\n"));
+        // What answering "a" at the prompt records.
+        add_to_allowlist(&allowlist, &sha256, &ktp_mk).unwrap();
+
+        let decision = evaluate(&KtpMkConfig::default(), Some(&allowlist), &ktp_mk, None, print_summary).unwrap();
+        assert!(decision.allowed, "{}", decision.reason);
+        assert!(decision.reason.contains("allowlist"), "{}", decision.reason);
+    }
+
+    #[test]
+    fn includes_are_refused_outside_the_deny_policy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let ktp_mk = tmp.path().join("KTP.mk");
+        let text = "-include local.mk\nall:\n\tinclude-what-you-use x.c\n";
+        std::fs::write(&ktp_mk, text).unwrap();
+        assert_eq!(includes(text), ["local.mk (line 1)"]);
+
+        let allowlist = tmp.path().join("ktp-mk.allow");
+        let sha256 = hex_digest(&openssl::sha::sha256(text.as_bytes()));
+        add_to_allowlist(&allowlist, &sha256, &ktp_mk).unwrap();
+        let config = KtpMkConfig { policy: TrustPolicy::Allowlist, ..Default::default() };
+        let decision = evaluate(&config, Some(&allowlist), &ktp_mk, None, print_summary).unwrap();
+        assert!(!decision.allowed);
+        assert!(decision.reason.contains("local.mk (line 1)"), "{}", decision.reason);
+    }
+}
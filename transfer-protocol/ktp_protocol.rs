@@ -1,12 +1,36 @@
 use tokio::sync::Mutex;
-use tokio::fs::{self, File};
-use std::path::PathBuf;
+use tokio::fs;
+use std::path::{Path, PathBuf};
 use tokio::process::Command as TokioCommand;
-use tokio::io::{AsyncWriteExt, BufReader, AsyncReadExt};
+use tokio::io::{BufReader, AsyncReadExt};
 use futures_util::StreamExt;
 use std::error::Error;
+use std::process::ExitStatus;
 use std::sync::Arc;
+use std::collections::{HashSet, VecDeque};
 use async_ftp::FtpError;
+use url::Url;
+
+use crate::artifacts::{self, Manifest, SourceInfo, ToolVersion};
+use crate::buildlog::{self, BuildLog, WarningSet};
+use crate::compiler_cache;
+use crate::config::KtpConfig;
+use crate::dotconfig::{ConfigValue, DotConfig};
+use crate::hostdeps;
+use crate::incremental::{self, BuildRecord, CleanLevel};
+use crate::install::{self, BootEntry, InitramfsGenerator, InstallOptions};
+use crate::ktp_mk::{self, Decision};
+use crate::ktp_toml::{self, TreeManifest};
+use crate::modules;
+use crate::packaging::{self, PackageFormat};
+use crate::extract::{DownloadOptions, DownloadSink, DownloadSummary};
+use crate::gitfetcher::{EntryType, GitFetcher};
+use crate::http_auth::{self, HttpAuth};
+use crate::kbuild::{self, BuildOptions, ConfigMode};
+use crate::mirror::MirrorOptions;
+use crate::preflight::{self, SpaceEstimate};
+use crate::tls;
+use crate::toolchain;
 
 pub enum TransferProtocol {
     SSH,
@@ -22,37 +46,93 @@ pub struct TransferOptions {
     pub auto_compile: bool,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// HTTP authentication; when `None`, `username`/`password` are used as Basic auth.
+    pub http_auth: HttpAuth,
+    /// Recursively mirror an HTTP directory index instead of fetching a single file.
+    pub mirror: Option<MirrorOptions>,
+    /// Checksum and streaming extraction settings for single-file transfers.
+    pub download: DownloadOptions,
+    /// Check free space, inodes and filesystem features before transferring.
+    pub preflight: bool,
+    /// Job count, make variables and targets for the build stages.
+    pub build: BuildOptions,
+}
+
+pub struct KtpController {
+    config: KtpConfig,
+    /// Output of the make stages of this run; opened by the first stage.
+    build_log: Mutex<Option<BuildLog>>,
 }
 
-pub struct KtpController;
+impl Default for KtpController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl KtpController {
     pub fn new() -> Self {
-        Self
+        Self::with_config(KtpConfig::default())
+    }
+
+    pub fn with_config(config: KtpConfig) -> Self {
+        Self { config, build_log: Mutex::new(None) }
+    }
+
+    pub fn config(&self) -> &KtpConfig {
+        &self.config
     }
 
     pub async fn transfer_kernel(&self, opts: TransferOptions) -> Result<(), Box<dyn Error>> {
         self.validate_url(&opts.protocol, &opts.source_url)?;
 
+        if opts.preflight {
+            self.preflight(&opts).await?;
+        }
+
         match opts.protocol {
             TransferProtocol::SSH | TransferProtocol::Cloud => {
-                self.transfer_scp(&opts.source_url, &opts.destination_path, opts.username.clone()).await?;
+                if opts.download.extract.is_some() || opts.download.sha256.is_some() {
+                    self.transfer_ssh_stream(&opts.source_url, &opts.destination_path, opts.username.clone(), &opts.download).await?;
+                } else {
+                    self.transfer_scp(&opts.source_url, &opts.destination_path, opts.username.clone()).await?;
+                }
             }
             TransferProtocol::HTTP => {
-                self.transfer_http(&opts.source_url, &opts.destination_path).await?;
+                let auth = Self::http_auth(&opts);
+                match &opts.mirror {
+                    Some(mirror) => self.transfer_http_mirror(&opts.source_url, &opts.destination_path, &auth, mirror).await?,
+                    None => self.transfer_http(&opts.source_url, &opts.destination_path, &auth, &opts.download).await?,
+                }
             }
             TransferProtocol::FTP => {
-                self.transfer_ftp(&opts.source_url, &opts.destination_path, opts.username.clone(), opts.password.clone()).await?;
+                self.transfer_ftp(&opts.source_url, &opts.destination_path, opts.username.clone(), opts.password.clone(), &opts.download).await?;
             }
         }
 
-        if self.ktp_mk_exists(&opts.destination_path).await? {
-            println!("KTP.mk detected. Starting automatic installation...");
-            self.run_ktp_mk(&opts.destination_path).await?;
+        if TreeManifest::exists(&opts.destination_path) {
+            return self.run_manifest(&opts.destination_path, &opts.build, opts.auto_compile, opts.preflight).await;
+        }
+        let ktp_mk = if self.ktp_mk_exists(&opts.destination_path).await? {
+            println!("KTP.mk detected.");
+            Some(self.authorize_ktp_mk(&opts.destination_path, &opts.build).await?)
+        } else {
+            None
+        };
+        if let Some(decision) = ktp_mk.as_ref().filter(|decision| decision.allowed) {
+            println!("Starting automatic installation...");
+            self.execute_ktp_mk(&opts.destination_path, &opts.build, decision).await?;
         } else if opts.auto_compile {
-            self.clean_kernel(&opts.destination_path).await?;
-            self.kconfig_interface(&opts.destination_path).await?;
-            self.compile_kernel(&opts.destination_path).await?;
+            if ktp_mk.is_some() {
+                println!("Warning: KTP.mk was not run; building the tree the regular way instead.");
+            }
+            self.clean_kernel(&opts.destination_path, &opts.build).await?;
+            self.kconfig_interface(&opts.destination_path, &opts.build).await?;
+            if opts.preflight {
+                self.check_host_dependencies(&opts.destination_path, &opts.build).await?;
+            }
+            self.compile_kernel(&opts.destination_path, &opts.build).await?;
+            self.post_build(&opts.destination_path, &opts.build).await?;
         }
 
         Ok(())
@@ -80,21 +160,276 @@ impl KtpController {
         Ok(())
     }
 
-    async fn ktp_mk_exists(&self, kernel_path: &PathBuf) -> Result<bool, Box<dyn Error>> {
+    /// Estimates how much the transfer, extraction and build will need and
+    /// checks the destination filesystem against it before downloading.
+    async fn preflight(&self, opts: &TransferOptions) -> Result<(), Box<dyn Error>> {
+        let file_name = opts.source_url.rsplit('/').next().unwrap_or_default().to_string();
+        let archive = match self.remote_size(opts).await {
+            Ok(size) => size,
+            Err(e) => {
+                println!("Preflight: could not determine the download size ({}).", e);
+                None
+            }
+        };
+
+        let mut estimate = SpaceEstimate { archive, ..Default::default() };
+        if let Some(extract) = &opts.download.extract {
+            estimate.extracted = archive.map(|size| preflight::estimate_extracted_size(&file_name, size));
+            if !extract.keep_archive {
+                estimate.archive = None;
+            }
+        }
+        let mut build_estimate = None;
+        if opts.auto_compile {
+            let config = opts.build.output_dir(&opts.destination_path).join(".config");
+            build_estimate = Some(preflight::estimate_build_size(config.exists().then_some(config.as_path())));
+        }
+
+        // With an out-of-tree build directory the build output may live on
+        // another filesystem, so it is checked on its own.
+        let report = match &opts.build.build_dir {
+            Some(build_dir) if opts.auto_compile => {
+                let mut report = preflight::check(&opts.destination_path, &estimate, false)?;
+                let build_only = SpaceEstimate { build: build_estimate, ..Default::default() };
+                let build_report = preflight::check(build_dir, &build_only, true)?;
+                report.errors.extend(build_report.errors);
+                report.warnings.extend(build_report.warnings);
+                report
+            }
+            _ => {
+                estimate.build = build_estimate;
+                preflight::check(&opts.destination_path, &estimate, opts.auto_compile)?
+            }
+        };
+        report.print();
+        if !report.errors.is_empty() {
+            return Err("Preflight checks failed; rerun with --skip-preflight to ignore them".into());
+        }
+        if opts.auto_compile {
+            self.check_toolchain(&opts.build).await?;
+            self.check_host_dependencies(&opts.destination_path, &opts.build).await?;
+        }
+        Ok(())
+    }
+
+    /// Verifies that the compiler and binutils of the selected toolchain
+    /// profile are on `PATH` and prints their versions.
+    pub async fn check_toolchain(&self, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        let Some(profile) = &build.toolchain else {
+            return Ok(());
+        };
+        println!("Checking toolchain profile {}", profile.describe());
+
+        let mut missing = Vec::new();
+        for tool in toolchain::check_tools(profile).await {
+            match &tool.path {
+                Some(path) => println!(
+                    "  {:<9} {:?}: {}",
+                    tool.role,
+                    path,
+                    tool.version.as_deref().unwrap_or("unknown version")
+                ),
+                None => {
+                    println!("  {:<9} {} not found", tool.role, tool.name);
+                    missing.push(tool.name);
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(format!(
+                "Toolchain profile '{}' is missing {} on PATH",
+                profile.name,
+                missing.join(", ")
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// `ktp doctor`: the toolchain and every host dependency of the build,
+    /// judged against the `.config` of `kernel_path` when there is one, with
+    /// package hints for this distribution. Fails in CI when something the
+    /// build needs is missing.
+    pub async fn doctor(&self, kernel_path: Option<&Path>, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        let profile = build.effective_toolchain();
+        println!("Toolchain {}", profile.describe());
+        let mut missing_tools = Vec::new();
+        for tool in toolchain::check_tools(&profile).await {
+            match &tool.path {
+                Some(path) => println!(
+                    "  ok       {:<16} {:?}: {}",
+                    tool.name,
+                    path,
+                    tool.version.as_deref().unwrap_or("unknown version")
+                ),
+                None => {
+                    println!("  MISSING  {} ({})", tool.name, tool.role);
+                    missing_tools.push(tool.name);
+                }
+            }
+        }
+
+        let config_path = kernel_path
+            .map(|path| build.output_dir(path).join(".config"))
+            .or_else(|| build.build_dir.as_ref().map(|dir| dir.join(".config")))
+            .filter(|path| path.is_file());
+        let config = config_path.as_deref().map(DotConfig::load).transpose()?;
+        match &config_path {
+            Some(path) => println!("Host dependencies for {:?}", path),
+            None => println!("Host dependencies (no .config given; config-dependent ones are shown as optional)"),
+        }
+        let distro = hostdeps::Distro::detect();
+        let reports = hostdeps::check(config.as_ref(), distro).await;
+        for report in &reports {
+            report.print();
+        }
+
+        let missing: Vec<&hostdeps::DependencyReport> =
+            reports.iter().filter(|r| r.is_missing() && r.need != hostdeps::Need::NotNeeded).collect();
+        if let Some(hint) = hostdeps::install_hint(&missing, distro) {
+            println!("Install with: {}", hint);
+        }
+        let required = missing.iter().filter(|r| r.need == hostdeps::Need::Required).count() + missing_tools.len();
+        if required == 0 {
+            println!("Everything the build needs was found.");
+        } else if hostdeps::in_ci() {
+            return Err(format!("{} required build dependencies are missing", required).into());
+        }
+        Ok(())
+    }
+
+    /// Pre-build check of the host dependencies, quiet when everything is
+    /// present. Missing ones fail the run in CI and are warned about otherwise,
+    /// since header detection cannot see custom sysroots.
+    pub async fn check_host_dependencies(&self, kernel_path: &Path, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        let config_path = build.output_dir(kernel_path).join(".config");
+        let config = config_path.is_file().then(|| DotConfig::load(&config_path)).transpose()?;
+        let distro = hostdeps::Distro::detect();
+        let reports = hostdeps::check(config.as_ref(), distro).await;
+        let missing: Vec<&hostdeps::DependencyReport> =
+            reports.iter().filter(|r| r.is_missing() && r.need == hostdeps::Need::Required).collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        println!("Missing host dependencies:");
+        for report in &missing {
+            report.print();
+        }
+        if let Some(hint) = hostdeps::install_hint(&missing, distro) {
+            println!("Install with: {}", hint);
+        }
+        let names: Vec<&str> = missing.iter().map(|r| r.name).collect();
+        if hostdeps::in_ci() {
+            return Err(format!("Missing build dependencies: {}", names.join(", ")).into());
+        }
+        println!("Warning: the build will likely fail without {}; see 'ktp doctor'.", names.join(", "));
+        Ok(())
+    }
+
+    /// Size of the file to download, from Content-Length or the FTP `SIZE` command.
+    async fn remote_size(&self, opts: &TransferOptions) -> Result<Option<u64>, Box<dyn Error>> {
+        match opts.protocol {
+            TransferProtocol::HTTP if opts.mirror.is_none() => {
+                let auth = Self::http_auth(opts);
+                let resp = http_auth::authorized_request(&self.http_client()?, reqwest::Method::HEAD, &opts.source_url, &auth).await?;
+                if !resp.status().is_success() {
+                    return Ok(None);
+                }
+                Ok(resp
+                    .headers()
+                    .get(reqwest::header::CONTENT_LENGTH)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok()))
+            }
+            TransferProtocol::FTP => {
+                let parsed_url = url::Url::parse(&opts.source_url)?;
+                let host = parsed_url.host_str().ok_or("FTP host not found")?;
+                let port = parsed_url.port_or_known_default().unwrap_or(21);
+                let user = opts.username.clone().unwrap_or_else(|| "anonymous".to_string());
+                let pass = opts.password.clone().unwrap_or_else(|| "anonymous".to_string());
+
+                let mut ftp_stream = async_ftp::FtpStream::connect((host, port)).await?;
+                ftp_stream.login(&user, &pass).await?;
+                let size = ftp_stream.size(parsed_url.path().trim_start_matches('/')).await?;
+                let _ = ftp_stream.quit().await;
+                Ok(size.map(|size| size as u64))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn ktp_mk_exists(&self, kernel_path: &Path) -> Result<bool, Box<dyn Error>> {
         let ktp_mk_path = kernel_path.join("KTP.mk");
         Ok(tokio::fs::metadata(&ktp_mk_path).await.is_ok())
     }
 
-    pub async fn run_ktp_mk(&self, kernel_path: &PathBuf) -> Result<(), Box<dyn Error>> {
-        let ktp_mk_path = kernel_path.join("KTP.mk");
-        println!("Executing KTP.mk at: {:?}", ktp_mk_path);
+    pub async fn run_ktp_mk(&self, kernel_path: &Path, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        let decision = self.authorize_ktp_mk(kernel_path, build).await?;
+        if !decision.allowed {
+            return Err(format!("KTP.mk was not run: {}", decision.reason).into());
+        }
+        self.execute_ktp_mk(kernel_path, build, &decision).await
+    }
 
-        let status = TokioCommand::new("make")
-            .arg("-f")
-            .arg(&ktp_mk_path)
-            .current_dir(kernel_path)
-            .status()
+    /// Applies the `[ktp_mk]` trust policy to the tree's KTP.mk. An allowed
+    /// KTP.mk is copied into the output directory; that copy is what runs,
+    /// and what the next one is diffed against.
+    async fn authorize_ktp_mk(&self, kernel_path: &Path, build: &BuildOptions) -> Result<Decision, Box<dyn Error>> {
+        self.authorize(kernel_path, build, "KTP.mk", ktp_mk::APPROVED_COPY, ktp_mk::print_summary).await
+    }
+
+    /// Applies the `[ktp_mk]` trust policy to `file_name` in the tree and
+    /// records the decision in the run log. The allowed contents are saved
+    /// as `approved_copy` in the output directory.
+    async fn authorize(
+        &self,
+        kernel_path: &Path,
+        build: &BuildOptions,
+        file_name: &str,
+        approved_copy: &str,
+        summarize: fn(&str),
+    ) -> Result<Decision, Box<dyn Error>> {
+        build.prepare(kernel_path).await?;
+        let approved = build.output_dir(kernel_path).join(approved_copy);
+        let previous = fs::read_to_string(&approved).await.ok();
+        let decision = {
+            let config = self.config.ktp_mk.clone();
+            let allowlist = config.allowlist_path(self.config.path.as_deref());
+            let path = kernel_path.join(file_name);
+            tokio::task::spawn_blocking(move || {
+                ktp_mk::evaluate(&config, allowlist.as_deref(), &path, previous.as_deref(), summarize)
+            })
+            .await??
+        };
+
+        let verdict = if decision.allowed { "allowed" } else { "denied" };
+        println!("{} {}: {}.", file_name, verdict, decision.reason);
+        self.open_build_log(kernel_path, build).await?;
+        if let Some(log) = self.build_log.lock().await.as_mut() {
+            let policy = self.config.ktp_mk.policy.name();
+            log.note(&format!(
+                "{} {} {} by policy {}: {}",
+                file_name, decision.sha256, verdict, policy, decision.reason
+            ))
             .await?;
+        }
+        if decision.allowed {
+            fs::write(&approved, &decision.contents).await?;
+        }
+        Ok(decision)
+    }
+
+    async fn execute_ktp_mk(&self, kernel_path: &Path, build: &BuildOptions, decision: &Decision) -> Result<(), Box<dyn Error>> {
+        // The approved copy, so that edits made after the review do not run.
+        let ktp_mk_path = std::path::absolute(build.output_dir(kernel_path).join(ktp_mk::APPROVED_COPY))?;
+        println!("Executing KTP.mk ({}) from {:?}", decision.sha256, kernel_path);
+
+        let mut cmd = build.make_command(kernel_path);
+        cmd.arg("-f").arg(&ktp_mk_path);
+        let status = self.run_make(kernel_path, build, cmd, "KTP.mk").await?;
+        self.print_build_summary(!status.success()).await;
 
         if !status.success() {
             return Err("Installation via KTP.mk failed".into());
@@ -104,20 +439,140 @@ impl KtpController {
         Ok(())
     }
 
-    async fn transfer_scp(&self, source_url: &str, dest: &PathBuf, username: Option<String>) -> Result<(), Box<dyn Error>> {
-        println!("Starting SCP transfer from '{}' to '{:?}'", source_url, dest);
+    /// Runs the tree's `KTP.toml` stage by stage. Everything it declares is
+    /// validated, and everything from the tree that would run is authorized,
+    /// before the first stage starts. Without `execute` only the validation
+    /// and the plan are printed.
+    pub async fn run_manifest(
+        &self,
+        kernel_path: &Path,
+        build: &BuildOptions,
+        execute: bool,
+        preflight: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        println!("{} detected.", ktp_toml::FILE_NAME);
+        let manifest = TreeManifest::load(kernel_path)?;
+        let errors = manifest.validate(kernel_path, &self.config);
+        if !errors.is_empty() {
+            for error in &errors {
+                println!("Error: {}", error);
+            }
+            return Err(format!("{} has {} error(s); no stage was run", ktp_toml::FILE_NAME, errors.len()).into());
+        }
+        let build = &manifest.apply(kernel_path, build, &self.config)?;
 
-        let final_url = if let Some(user) = username {
-            if let Some(at_pos) = source_url.find('@') {
-                let after_at = &source_url[at_pos + 1..];
-                format!("{}@{}", user, after_at)
-            } else {
-                format!("{}@{}", user, source_url)
+        println!("Stages:");
+        for (number, stage) in manifest.stages().iter().enumerate() {
+            println!("  {}. {}", number + 1, stage);
+        }
+        if !manifest.build.ktp_mk && kernel_path.join("KTP.mk").is_file() {
+            println!("Note: the tree's KTP.mk is ignored; set build.ktp_mk to run it as a stage.");
+        }
+        if !execute {
+            println!("{} is valid.", ktp_toml::FILE_NAME);
+            return Ok(());
+        }
+
+        let ktp_mk = if manifest.build.ktp_mk {
+            let decision = self.authorize_ktp_mk(kernel_path, build).await?;
+            if !decision.allowed {
+                return Err(format!("KTP.mk was not run: {}", decision.reason).into());
             }
+            Some(decision)
         } else {
-            source_url.to_string()
+            None
+        };
+        let hooks = if manifest.hooks.post_build.is_empty() {
+            Vec::new()
+        } else {
+            let decision = self
+                .authorize(kernel_path, build, ktp_toml::FILE_NAME, ktp_toml::APPROVED_COPY, ktp_toml::print_hooks)
+                .await?;
+            if !decision.allowed {
+                return Err(format!("The {} hooks were not run: {}", ktp_toml::FILE_NAME, decision.reason).into());
+            }
+            // The hooks as approved, even if the file changed since it was validated.
+            TreeManifest::parse(&decision.contents)?.hooks.post_build
+        };
+
+        let strip = manifest.patches.strip;
+        for patch in &manifest.patches.files {
+            let path = std::path::absolute(kernel_path.join(patch))?;
+            if ktp_toml::patch_applied(kernel_path, &path, strip).await {
+                println!("Patch {:?} is already applied.", patch);
+                continue;
+            }
+            let stage = format!("patch {}", patch.display());
+            let status = self.run_make(kernel_path, build, ktp_toml::patch_command(kernel_path, &path, strip), &stage).await?;
+            if !status.success() {
+                self.print_build_summary(true).await;
+                return Err(format!("'{}' failed", stage).into());
+            }
+            println!("Applied {:?}.", patch);
+        }
+
+        if manifest.build.compile {
+            if preflight {
+                self.check_toolchain(build).await?;
+            }
+            self.clean_kernel(kernel_path, build).await?;
+            self.kconfig_interface(kernel_path, build).await?;
+            if preflight {
+                self.check_host_dependencies(kernel_path, build).await?;
+            }
+            self.compile_kernel(kernel_path, build).await?;
+            self.post_build(kernel_path, build).await?;
+        }
+        if let Some(decision) = &ktp_mk {
+            self.execute_ktp_mk(kernel_path, build, decision).await?;
+        }
+        self.run_hooks(kernel_path, build, &hooks).await
+    }
+
+    /// Runs post-build hooks with `sh -c` in the tree, confined like the make
+    /// stages when the build is sandboxed.
+    async fn run_hooks(&self, kernel_path: &Path, build: &BuildOptions, hooks: &[String]) -> Result<(), Box<dyn Error>> {
+        if hooks.is_empty() {
+            return Ok(());
+        }
+        // Unknown when nothing was built, e.g. with build.compile off.
+        let release = self.kernel_release(kernel_path, build).await.ok();
+        let source_dir = std::path::absolute(kernel_path)?;
+        let output_dir = std::path::absolute(build.output_dir(kernel_path))?;
+        let artifact_dir = match &release {
+            Some(release) => Some(std::path::absolute(build.artifact_root(kernel_path).join(release))?),
+            None => None,
         };
 
+        for (number, hook) in hooks.iter().enumerate() {
+            let mut cmd = TokioCommand::new("sh");
+            cmd.current_dir(kernel_path)
+                .arg("-c")
+                .arg(hook)
+                .env("KTP_SOURCE_DIR", &source_dir)
+                .env("KTP_OUTPUT_DIR", &output_dir);
+            if let (Some(release), Some(artifact_dir)) = (&release, &artifact_dir) {
+                cmd.env("KTP_RELEASE", release).env("KTP_ARTIFACT_DIR", artifact_dir);
+            }
+            build.confine(&mut cmd, kernel_path);
+
+            let stage = format!("post-build hook {}", number + 1);
+            println!("Running {}: {}", stage, hook);
+            let status = self.run_make(kernel_path, build, cmd, &stage).await?;
+            if !status.success() {
+                self.print_build_summary(true).await;
+                return Err(format!("'{}' failed: {}", stage, hook).into());
+            }
+        }
+        println!("{} post-build hook(s) finished.", hooks.len());
+        Ok(())
+    }
+
+    async fn transfer_scp(&self, source_url: &str, dest: &PathBuf, username: Option<String>) -> Result<(), Box<dyn Error>> {
+        println!("Starting SCP transfer from '{}' to '{:?}'", source_url, dest);
+
+        let final_url = Self::scp_target(source_url, username);
+
         let status = TokioCommand::new("scp")
             .arg("-r")
             .arg(final_url)
@@ -135,33 +590,222 @@ impl KtpController {
         Ok(())
     }
 
-    async fn transfer_http(&self, url: &str, dest: &PathBuf) -> Result<(), Box<dyn Error>> {
-        println!("Starting HTTP download from '{}' to '{:?}'", url, dest);
+    fn scp_target(source_url: &str, username: Option<String>) -> String {
+        if let Some(user) = username {
+            if let Some(at_pos) = source_url.find('@') {
+                let after_at = &source_url[at_pos + 1..];
+                format!("{}@{}", user, after_at)
+            } else {
+                format!("{}@{}", user, source_url)
+            }
+        } else {
+            source_url.to_string()
+        }
+    }
+
+    /// Single-file SSH transfer that pipes `cat` on the remote host through
+    /// the download sink, so it can be verified and extracted on the fly.
+    async fn transfer_ssh_stream(
+        &self,
+        source_url: &str,
+        dest: &Path,
+        username: Option<String>,
+        download: &DownloadOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let final_url = Self::scp_target(source_url, username);
+        let at_pos = final_url.find('@').unwrap_or(0);
+        let colon = final_url[at_pos..].find(':').ok_or("Invalid SSH/Cloud URL format; expected user@host:/path")? + at_pos;
+        let (host, remote_path) = (&final_url[..colon], &final_url[colon + 1..]);
+        let filename = Path::new(remote_path)
+            .file_name()
+            .ok_or("Failed to get file name from SSH path")?
+            .to_string_lossy()
+            .into_owned();
+
+        println!("Streaming '{}' over SSH to '{:?}'", final_url, dest);
+
+        let mut child = TokioCommand::new("ssh")
+            .arg("--")
+            .arg(host)
+            .arg(format!("cat -- '{}'", remote_path.replace('\'', "'\\''")))
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::inherit())
+            .spawn()?;
+        let mut stdout = child.stdout.take().ok_or("Failed to capture ssh output")?;
+
+        let mut sink = DownloadSink::create(dest, &filename, download).await?;
+        let result: Result<(), Box<dyn Error>> = async {
+            let mut buf = vec![0u8; 1 << 16];
+            loop {
+                let n = stdout.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                sink.write(&buf[..n]).await?;
+            }
+            if !child.wait().await?.success() {
+                return Err("SSH transfer failed".into());
+            }
+            Ok(())
+        }
+        .await;
+        let summary = sink.complete(result).await?;
+
+        Self::report_download(&summary, source_url, &dest.join(&filename), dest);
+        Ok(())
+    }
+
+    fn report_download(summary: &DownloadSummary, url: &str, file_path: &Path, dest: &Path) {
+        match summary.extracted {
+            Some(entries) => println!(
+                "Extracted {} entries ({} bytes, sha256 {}) into {:?}",
+                entries, summary.bytes, summary.sha256, dest
+            ),
+            None => println!("File downloaded successfully to {:?}", file_path),
+        }
+        if let Err(e) = SourceInfo::record_download(dest, &http_auth::redact_url(url), &summary.sha256) {
+            println!("Warning: {}", e);
+        }
+    }
+
+    async fn transfer_http(
+        &self,
+        url: &str,
+        dest: &Path,
+        auth: &HttpAuth,
+        download: &DownloadOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        println!("Starting HTTP download from '{}' to '{:?}'", http_auth::redact_url(url), dest);
+
+        let filename = url.rsplit('/').next().ok_or("Failed to extract filename from URL")?;
+        let client = self.http_client()?;
 
-        let filename = url.split('/').last().ok_or("Failed to extract filename from URL")?;
-        let file_path = dest.join(filename);
+        let mut sink = DownloadSink::create(dest, filename, download).await?;
+        let result = self.stream_http(&client, url, auth, &mut sink).await;
+        let summary = sink.complete(result).await?;
 
-        if !dest.exists() {
-            fs::create_dir_all(dest).await?;
+        Self::report_download(&summary, url, &dest.join(filename), dest);
+        Ok(())
+    }
+
+    fn http_auth(opts: &TransferOptions) -> HttpAuth {
+        match &opts.http_auth {
+            HttpAuth::None => HttpAuth::from_credentials(opts.username.clone(), opts.password.clone()),
+            auth => auth.clone(),
         }
+    }
 
-        let client = reqwest::Client::new();
-        let resp = client.get(url).send().await?;
+    fn http_client(&self) -> Result<reqwest::Client, Box<dyn Error>> {
+        Ok(tls::configure_http(http_auth::client_builder(), &self.config.tls)?.build()?)
+    }
+
+    async fn stream_http(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        auth: &HttpAuth,
+        sink: &mut DownloadSink,
+    ) -> Result<(), Box<dyn Error>> {
+        let resp = http_auth::authorized_get(client, url, auth).await?;
+        Self::stream_response(resp, sink).await
+    }
 
+    async fn stream_response(resp: reqwest::Response, sink: &mut DownloadSink) -> Result<(), Box<dyn Error>> {
         if !resp.status().is_success() {
             return Err(format!("HTTP request failed with status: {}", resp.status()).into());
         }
 
-        let mut file = File::create(&file_path).await?;
         let mut stream = resp.bytes_stream();
 
         while let Some(chunk) = stream.next().await {
             let chunk = chunk?;
-            file.write_all(&chunk).await?;
+            sink.write(&chunk).await?;
         }
 
-        println!("File downloaded successfully to {:?}", file_path);
+        Ok(())
+    }
+
+    /// Walks an autoindex directory listing breadth-first and downloads
+    /// every accepted file below `url` into the same layout under `dest`.
+    async fn transfer_http_mirror(
+        &self,
+        url: &str,
+        dest: &Path,
+        auth: &HttpAuth,
+        mirror: &MirrorOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        println!("Mirroring HTTP directory '{}' to '{:?}'", http_auth::redact_url(url), dest);
+
+        let mut root = Url::parse(url)?;
+        let _ = root.set_username("");
+        let _ = root.set_password(None);
+        if !root.path().ends_with('/') {
+            root.set_path(&format!("{}/", root.path()));
+        }
+
+        // Credentials only go to the origin they were given for; index
+        // pages may link to plain HTTP or, with --allow-cross-host, elsewhere.
+        let origin = root.origin();
+        let client = self.http_client()?;
+        let mut queue = VecDeque::from([(root.clone(), PathBuf::new(), 0usize)]);
+        let mut visited = HashSet::new();
+        let mut downloaded = 0usize;
+
+        while let Some((dir_url, rel_dir, depth)) = queue.pop_front() {
+            if !visited.insert(dir_url.clone()) {
+                continue;
+            }
+
+            let dir_auth = auth.for_origin(&origin, &dir_url);
+            let resp = http_auth::authorized_get(&client, dir_url.as_str(), &dir_auth).await?;
+            if !resp.status().is_success() {
+                return Err(format!("HTTP request for {} failed with status: {}", dir_url, resp.status()).into());
+            }
+            if mirror.same_host && resp.url().host_str() != root.host_str() {
+                println!("Skipping {}: redirected to another host.", dir_url);
+                continue;
+            }
+            let base = resp.url().clone();
+            let html = resp.text().await?;
+            fs::create_dir_all(dest.join(&rel_dir)).await?;
+
+            for entry in GitFetcher::parse_directory_index(&base, &html) {
+                let entry_url = Url::parse(&entry.url)?;
+                if mirror.same_host && entry_url.host_str() != root.host_str() {
+                    continue;
+                }
+                if !entry_url.path().starts_with(root.path()) {
+                    continue;
+                }
+
+                let rel_path = rel_dir.join(&entry.name);
+                match entry.entry_type {
+                    EntryType::Directory => {
+                        if depth < mirror.max_depth && mirror.wants_dir(&rel_path) {
+                            queue.push_back((entry_url, rel_path, depth + 1));
+                        }
+                    }
+                    EntryType::File => {
+                        if mirror.wants_file(&rel_path) {
+                            let file_auth = auth.for_origin(&origin, &entry_url);
+                            let resp = http_auth::authorized_get(&client, entry_url.as_str(), &file_auth).await?;
+                            if mirror.same_host && resp.url().host_str() != root.host_str() {
+                                println!("Skipping {}: redirected to another host.", entry_url);
+                                continue;
+                            }
+                            println!("Downloading {:?}", rel_path);
+                            let file_dir = dest.join(&rel_dir);
+                            let mut sink = DownloadSink::create(&file_dir, &entry.name, &DownloadOptions::default()).await?;
+                            let result = Self::stream_response(resp, &mut sink).await;
+                            sink.complete(result).await?;
+                            downloaded += 1;
+                        }
+                    }
+                }
+            }
+        }
 
+        println!("Mirrored {} file(s) into {:?}", downloaded, dest);
         Ok(())
     }
 
@@ -171,6 +815,7 @@ impl KtpController {
         dest: &PathBuf,
         username: Option<String>,
         password: Option<String>,
+        download: &DownloadOptions,
     ) -> Result<(), Box<dyn Error>> {
         println!("Starting FTP transfer from '{}' to '{:?}'", url, dest);
 
@@ -189,93 +834,768 @@ impl KtpController {
             .file_name()
             .ok_or("Failed to get file name from FTP path")?;
 
-        if !dest.exists() {
-            fs::create_dir_all(dest).await?;
-        }
-
-        let file_path = dest.join(filename);
-        let file = File::create(&file_path).await?;
-        let file = Arc::new(Mutex::new(file));
+        let filename = filename.to_string_lossy().into_owned();
+        let sink = DownloadSink::create(dest, &filename, download).await?;
+        let sink = Arc::new(Mutex::new(sink));
 
-        ftp_stream
+        let result = ftp_stream
             .retr(remote_path, |reader: BufReader<async_ftp::DataStream>| {
-                let file = Arc::clone(&file);
+                let sink = Arc::clone(&sink);
                 async move {
                     let mut reader = reader;
-                    let mut file = file.lock().await;
+                    let mut sink = sink.lock().await;
                     let mut buf = [0u8; 8192];
 
                     loop {
                         let n = reader
                             .read(&mut buf)
                             .await
-                            .map_err(|e| FtpError::ConnectionError(e))?;
+                            .map_err(FtpError::ConnectionError)?;
                         if n == 0 {
                             break;
                         }
-                        file.write_all(&buf[..n])
+                        sink.write(&buf[..n])
                             .await
-                            .map_err(|e| FtpError::ConnectionError(e))?;
+                            .map_err(|e| FtpError::ConnectionError(std::io::Error::other(e)))?;
                     }
                     Ok::<_, FtpError>(())
                 }
             })
-            .await?;
+            .await;
+
+        let sink = Arc::try_unwrap(sink).map_err(|_| "FTP download sink still in use")?.into_inner();
+        let summary = sink.complete(result.map_err(Box::<dyn Error>::from)).await?;
 
-        println!("FTP file downloaded successfully to {:?}", file_path);
+        Self::report_download(&summary, url, &dest.join(&filename), dest);
 
         Ok(())
     }
 
-    async fn clean_kernel(&self, kernel_path: &PathBuf) -> Result<(), Box<dyn Error>> {
-        println!("Running 'make clean' in {:?}", kernel_path);
+    /// Cleans the build as far as needed: forced with `--clean`, otherwise
+    /// chosen by comparing the tree with the record of the previous build.
+    async fn clean_kernel(&self, kernel_path: &Path, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        build.prepare(kernel_path).await?;
+        let output_dir = build.output_dir(kernel_path);
+        let (level, reason) = match build.clean {
+            Some(level) => (level, format!("forced with --clean={}", level.target().unwrap_or("none"))),
+            None => {
+                let current = BuildRecord::current(kernel_path, build).await;
+                incremental::decide(BuildRecord::load(&output_dir).as_ref(), &current, &output_dir)
+            }
+        };
+        let Some(target) = level.target() else {
+            println!("Building incrementally: {}.", reason);
+            return Ok(());
+        };
+        println!("Running 'make {}' in {:?}: {}.", target, output_dir, reason);
+
+        // mrproper and distclean delete .config; the configuration stage decides what happens to it.
+        let config = output_dir.join(".config");
+        let saved_config = if level >= CleanLevel::Mrproper && config.is_file() {
+            Some(fs::read(&config).await?)
+        } else {
+            None
+        };
 
-        let status = TokioCommand::new("make")
-            .arg("clean")
-            .current_dir(kernel_path)
-            .status()
-            .await?;
+        let mut cmd = build.make_command(kernel_path);
+        cmd.arg(target);
+        let stage = format!("make {}", target);
+        let status = self.run_make(kernel_path, build, cmd, &stage).await?;
 
         if !status.success() {
-            println!("Warning: 'make clean' failed, continuing...");
+            println!("Warning: '{}' failed, continuing...", stage);
         } else {
-            println!("'make clean' completed successfully.");
+            println!("'{}' completed successfully.", stage);
+        }
+        if let Some(saved) = saved_config {
+            fs::write(&config, saved).await?;
+            println!("Kept .config across '{}'.", stage);
         }
 
         Ok(())
     }
 
-    pub async fn kconfig_interface(&self, kernel_path: &PathBuf) -> Result<(), Box<dyn Error>> {
-        println!("Launching 'make menuconfig' in {:?}", kernel_path);
+    pub async fn kconfig_interface(&self, kernel_path: &Path, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        build.prepare(kernel_path).await?;
+        let output_dir = build.output_dir(kernel_path);
 
-        let status = TokioCommand::new("make")
-            .arg("menuconfig")
-            .current_dir(kernel_path)
-            .status()
-            .await?;
+        let mode = &build.config_mode.clone().unwrap_or_else(|| ConfigMode::detect(&output_dir));
+        if let ConfigMode::CopyFrom(source) = mode {
+            let config = output_dir.join(".config");
+            // Copying a file onto itself truncates it.
+            let same_file = match (fs::canonicalize(source).await, fs::canonicalize(&config).await) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false,
+            };
+            if same_file {
+                println!("{:?} is already the build's .config; using it as is", source);
+            } else {
+                fs::copy(source, &config)
+                    .await
+                    .map_err(|e| format!("Failed to copy {:?} to {:?}: {}", source, config, e))?;
+                println!("Copied {:?} to {:?}", source, config);
+            }
+        }
+        match mode.target() {
+            Some(target) => {
+                println!("Launching 'make {}' in {:?}", target, output_dir);
+
+                let mut cmd = build.make_command(kernel_path);
+                cmd.arg(&target);
+                let status = if mode.is_interactive() {
+                    // Interactive frontends need the terminal; they are not captured.
+                    self.open_build_log(kernel_path, build).await?;
+                    if let Some(log) = self.build_log.lock().await.as_mut() {
+                        log.note(&format!("make {} (interactive, not captured)", target)).await?;
+                    }
+                    cmd.status().await?
+                } else {
+                    self.run_make(kernel_path, build, cmd, &format!("make {}", target)).await?
+                };
+
+                if !status.success() {
+                    if !mode.is_interactive() {
+                        self.print_build_summary(true).await;
+                        return Err(format!("'make {}' failed", target).into());
+                    }
+                    println!("Warning: 'make {}' was cancelled or failed.", target);
+                } else {
+                    println!("Kconfig configuration completed.");
+                }
+            }
+            None => println!("Skipping kernel configuration."),
+        }
 
+        if build.has_config_edits() {
+            self.apply_config_edits(kernel_path, build).await?;
+        }
+        Ok(())
+    }
+
+    /// Merges config fragments and `--set`/`--unset` edits into `.config`
+    /// the way `merge_config.sh` does, runs `olddefconfig` and reports every
+    /// requested option that Kconfig dropped or changed.
+    pub async fn apply_config_edits(&self, kernel_path: &Path, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        let config_path = build.output_dir(kernel_path).join(".config");
+        let mut config = if config_path.exists() { DotConfig::load(&config_path)? } else { DotConfig::default() };
+
+        // Like merge_config.sh, a fragment that changes a value of the base
+        // .config is reported as well as one that changes an earlier fragment.
+        let mut requested = DotConfig::default();
+        for fragment_path in &build.config_fragments {
+            println!("Merging config fragment {:?}", fragment_path);
+            let fragment = DotConfig::load(fragment_path)?;
+            for redefined in config.merge(&fragment) {
+                println!(
+                    "Value of CONFIG_{} is redefined by fragment {:?}: {} -> {}",
+                    redefined.name, fragment_path, redefined.previous, redefined.value
+                );
+            }
+            requested.merge(&fragment);
+        }
+        for (name, value) in &build.config_edits {
+            requested.set(name, value.clone());
+            config.set(name, value.clone());
+        }
+        config.save(&config_path)?;
+
+        println!("Running 'make olddefconfig' in {:?}", build.output_dir(kernel_path));
+        let mut cmd = build.make_command(kernel_path);
+        cmd.arg("olddefconfig");
+        let status = self.run_make(kernel_path, build, cmd, "make olddefconfig").await?;
         if !status.success() {
-            println!("Warning: 'make menuconfig' was cancelled or failed.");
-        } else {
-            println!("Kconfig configuration completed.");
+            self.print_build_summary(true).await;
+            return Err("'make olddefconfig' failed".into());
         }
 
+        let result = DotConfig::load(&config_path)?;
+        let mismatches = result.mismatches(&requested);
+        for mismatch in &mismatches {
+            if mismatch.actual.is_set() {
+                println!(
+                    "WARNING: CONFIG_{} requested {} but is {} after olddefconfig",
+                    mismatch.name, mismatch.requested, mismatch.actual
+                );
+            } else {
+                println!(
+                    "WARNING: CONFIG_{}={} was dropped by olddefconfig; see 'ktp config explain CONFIG_{} --source {}'",
+                    mismatch.name,
+                    mismatch.requested,
+                    mismatch.name,
+                    kernel_path.display()
+                );
+            }
+        }
+        println!(
+            "Applied {} config option(s), {} not kept as requested.",
+            requested.symbols.len(),
+            mismatches.len()
+        );
         Ok(())
     }
 
-    pub async fn compile_kernel(&self, kernel_path: &PathBuf) -> Result<(), Box<dyn Error>> {
-        println!("Starting kernel compilation in {:?}", kernel_path);
+    pub async fn compile_kernel(&self, kernel_path: &Path, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        build.prepare(kernel_path).await?;
+        let targets = if build.targets.is_empty() { "default".to_string() } else { build.targets.join(" ") };
+        println!(
+            "Starting kernel compilation in {:?} (targets: {}, {} jobs)",
+            kernel_path,
+            targets,
+            build.jobs()
+        );
+        if let Some(profile) = &build.toolchain {
+            println!("Using toolchain profile {}", profile.describe());
+        }
+        if let Some(dir) = &build.build_dir {
+            println!("Build output goes to {:?}", dir);
+        }
 
-        let status = TokioCommand::new("make")
-            .current_dir(kernel_path)
-            .status()
-            .await?;
+        let cache_before = match &build.compiler_cache {
+            Some(cache) => {
+                let program = cache.tool.program();
+                if toolchain::find_in_path(program).is_none() {
+                    return Err(format!("Compiler cache {} not found on PATH", program).into());
+                }
+                println!("Compiling through {}", program);
+                cache.stats().await
+            }
+            None => None,
+        };
+        let started = std::time::Instant::now();
+        // Only a build that compiles everything can be the warning baseline.
+        let full_build = !kbuild::has_objects(&build.output_dir(kernel_path));
+
+        let mut cmd = build.make_command(kernel_path);
+        cmd.args(&build.targets);
+        let status = self.run_make(kernel_path, build, cmd, &format!("make {}", targets)).await?;
+        self.print_build_summary(!status.success()).await;
+        if let (Some(cache), Some(before)) = (&build.compiler_cache, cache_before) {
+            if let Some(after) = cache.stats().await {
+                compiler_cache::report(after.since(before), started.elapsed(), build.jobs(), &build.output_dir(kernel_path))?;
+            }
+        }
 
         if !status.success() {
             return Err("Kernel compilation failed".into());
         }
+        self.check_warning_regressions(kernel_path, build, full_build).await?;
+        if full_build {
+            if let Some(log) = self.build_log.lock().await.as_ref() {
+                println!("Saved the warning baseline for later builds in {:?}", log.save_baseline()?);
+            }
+        }
+        BuildRecord::current(kernel_path, build).await.save(&build.output_dir(kernel_path))?;
 
         println!("Kernel compilation finished successfully.");
         Ok(())
     }
+
+    /// Stages that run after a successful compile.
+    pub async fn post_build(&self, kernel_path: &Path, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        if build.no_artifacts && build.no_modules && build.package.formats.is_empty() && build.install.is_none() {
+            return Ok(());
+        }
+        let release = self.kernel_release(kernel_path, build).await?;
+        if !build.no_artifacts {
+            self.collect_artifacts(kernel_path, build, &release).await?;
+        }
+        if !build.no_modules {
+            self.install_modules(kernel_path, build, &release).await?;
+        }
+        if !build.package.formats.is_empty() {
+            self.build_packages(kernel_path, build, &release).await?;
+        }
+        if let Some(options) = &build.install {
+            self.install_kernel(kernel_path, build, &release, options).await?;
+        }
+        Ok(())
+    }
+
+    /// The release string of the configured tree (`make kernelrelease`).
+    pub async fn kernel_release(&self, kernel_path: &Path, build: &BuildOptions) -> Result<String, Box<dyn Error>> {
+        let output = build
+            .make_command(kernel_path)
+            .arg("-s")
+            .arg("kernelrelease")
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(format!(
+                "'make kernelrelease' failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let release = stdout.lines().last().unwrap_or_default().trim().to_string();
+        artifacts::validate_release(&release)?;
+        Ok(release)
+    }
+
+    /// Copies the kernel image, `vmlinux`, `System.map`, `.config` and dtbs
+    /// into `artifacts/<release>/` and writes a manifest with their hashes,
+    /// the config hash, the toolchain versions and the source revision.
+    /// Returns the artifact directory.
+    pub async fn collect_artifacts(
+        &self,
+        kernel_path: &Path,
+        build: &BuildOptions,
+        release: &str,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        let output_dir = build.output_dir(kernel_path);
+        let srcarch = build.srcarch();
+        let dest = build.artifact_root(kernel_path).join(release);
+        println!("Collecting {} artifacts for {} into {:?}", srcarch, release, dest);
+
+        let copied = {
+            let (output_dir, srcarch, dest) = (output_dir.clone(), srcarch.clone(), dest.clone());
+            tokio::task::spawn_blocking(move || artifacts::collect(&output_dir, &srcarch, &dest)).await??
+        };
+        if copied.is_empty() {
+            println!("Warning: no build artifacts found in {:?}", output_dir);
+        }
+
+        let toolchain = toolchain::check_tools(&build.effective_toolchain())
+            .await
+            .into_iter()
+            .map(|tool| ToolVersion { role: tool.role.to_string(), name: tool.name, version: tool.version })
+            .collect();
+        let config = output_dir.join(".config");
+        let mut manifest = Manifest {
+            release: release.to_string(),
+            arch: srcarch,
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            source: SourceInfo::detect(kernel_path),
+            config_sha256: config.is_file().then(|| artifacts::sha256_file(&config)).transpose()?,
+            toolchain,
+            files: Vec::new(),
+            reproducible: build.reproducible,
+        };
+        for path in &copied {
+            manifest.add_file(&dest, path)?;
+            println!("  {}", path.strip_prefix(&dest).unwrap_or(path).display());
+        }
+        manifest.save(&dest)?;
+        println!("Wrote {:?}", dest.join(artifacts::MANIFEST));
+        Ok(dest)
+    }
+
+    /// `ktp build verify`: rebuilds the out-of-tree build in `--build-dir`
+    /// from the same `.config` in a fresh sibling directory, reproducibly
+    /// and without the compiler cache, and compares the artifact hashes
+    /// with the original manifest.
+    pub async fn verify_build(&self, kernel_path: &Path, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        let Some(build_dir) = &build.build_dir else {
+            return Err("Verification rebuilds out of tree; pass the --build-dir of the build to verify".into());
+        };
+        let release = self.kernel_release(kernel_path, build).await?;
+        let reference_dir = build.artifact_root(kernel_path).join(&release);
+        if !reference_dir.join(artifacts::MANIFEST).exists() {
+            return Err(format!("No manifest for {} in {:?}; build it with --reproducible first", release, reference_dir).into());
+        }
+        let reference = Manifest::load(&reference_dir)?;
+        if !reference.reproducible {
+            println!("Warning: {} was not built with --reproducible; expect differences.", release);
+        }
+
+        let mut fresh_name = build_dir.file_name().unwrap_or_default().to_os_string();
+        fresh_name.push("-verify");
+        let fresh = build_dir.with_file_name(fresh_name);
+        if fresh.exists() {
+            if !fresh.join(artifacts::VERIFY_MARKER).exists() {
+                return Err(format!("{:?} exists but was not created by 'ktp build verify'; move it out of the way", fresh).into());
+            }
+            fs::remove_dir_all(&fresh).await?;
+        }
+        fs::create_dir_all(&fresh).await?;
+        fs::write(fresh.join(artifacts::VERIFY_MARKER), "").await?;
+        fs::copy(build_dir.join(".config"), fresh.join(".config")).await?;
+
+        // A generated module signing key would be regenerated, and every
+        // signed module and the certificate in vmlinux would differ.
+        let config = DotConfig::load(&build_dir.join(".config"))?;
+        if config.get("MODULE_SIG").is_set() {
+            let default_key = matches!(config.get("MODULE_SIG_KEY"), ConfigValue::Value(key) if key == "\"certs/signing_key.pem\"");
+            if default_key && build_dir.join(artifacts::SIGNING_KEY_FILES[0]).is_file() {
+                for file in artifacts::SIGNING_KEY_FILES {
+                    if build_dir.join(file).is_file() {
+                        fs::create_dir_all(fresh.join(file).parent().expect("key file has a directory")).await?;
+                        fs::copy(build_dir.join(file), fresh.join(file)).await?;
+                    }
+                }
+                println!("Reusing the module signing key of {:?}", build_dir);
+            } else if default_key {
+                println!("Warning: CONFIG_MODULE_SIG is set but {:?} has no signing key; signed modules will differ.", build_dir);
+            }
+            if config.get("MODULE_SIG_KEY_TYPE_ECDSA").is_set() {
+                println!("Warning: ECDSA module signatures are randomised; signed modules will differ.");
+            }
+        }
+        println!("Rebuilding {} in {:?}", release, fresh);
+
+        let mut verify = build.clone();
+        verify.build_dir = Some(fresh.clone());
+        verify.artifact_dir = Some(fresh.join("artifacts"));
+        verify.log_dir = None;
+        verify.warning_baseline = None;
+        verify.no_new_warnings = false;
+        verify.compiler_cache = None;
+        verify.reproducible = true;
+        self.compile_kernel(kernel_path, &verify).await?;
+        let rebuilt_dir = self.collect_artifacts(kernel_path, &verify, &release).await?;
+        let comparison = reference.compare(&Manifest::load(&rebuilt_dir)?);
+
+        for path in &comparison.matching {
+            println!("  same     {}", path);
+        }
+        for (path, original, rebuilt) in &comparison.differing {
+            println!("  DIFFERS  {} ({} vs {})", path, &original[..12], &rebuilt[..12]);
+        }
+        for path in &comparison.missing {
+            println!("  skipped  {} (not part of the rebuild)", path);
+        }
+        if !comparison.differing.is_empty() {
+            return Err(format!(
+                "{} of {} artifacts differ from the original build",
+                comparison.differing.len(),
+                comparison.differing.len() + comparison.matching.len()
+            )
+            .into());
+        }
+        println!("Verified: all {} rebuilt artifacts match {:?}.", comparison.matching.len(), reference_dir);
+        Ok(())
+    }
+
+    /// Installs modules into a staging root with `modules_install`, optionally
+    /// compresses them, runs depmod against the staging root and packs it as
+    /// `modules-<release>.tar.zst` in the artifact directory. Nothing here
+    /// needs root. Returns the bundle, or `None` if the build has no modules.
+    pub async fn install_modules(
+        &self,
+        kernel_path: &Path,
+        build: &BuildOptions,
+        release: &str,
+    ) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        let output_dir = build.output_dir(kernel_path);
+        let config = DotConfig::load(&output_dir.join(".config"))?;
+        if config.get("MODULES") != &ConfigValue::Yes {
+            println!("CONFIG_MODULES is not set; skipping modules_install.");
+            return Ok(None);
+        }
+        if !build.builds_modules() {
+            println!("Modules are not among the build targets; skipping modules_install.");
+            return Ok(None);
+        }
+
+        let staging = std::path::absolute(build.staging_dir(kernel_path, release))?;
+        if staging.exists() {
+            fs::remove_dir_all(&staging).await?;
+        }
+        fs::create_dir_all(&staging).await?;
+        println!("Installing modules into {:?}", staging);
+
+        // depmod runs below, after compression, against the staging root.
+        let no_depmod = toolchain::find_in_path("true").unwrap_or_else(|| PathBuf::from("/bin/true"));
+        let mut cmd = build.make_command(kernel_path);
+        cmd.arg("modules_install")
+            .arg(format!("INSTALL_MOD_PATH={}", staging.display()))
+            .arg(format!("DEPMOD={}", no_depmod.display()));
+        if build.strip_modules {
+            cmd.arg("INSTALL_MOD_STRIP=1");
+        }
+        let status = self.run_make(kernel_path, build, cmd, "make modules_install").await?;
+        if !status.success() {
+            self.print_build_summary(true).await;
+            return Err("'make modules_install' failed".into());
+        }
+
+        let modules_dir = modules::modules_dir(&staging, release)
+            .ok_or_else(|| format!("modules_install left no lib/modules/{} in {:?}", release, staging))?;
+        if let Some(compression) = build.module_compression {
+            let dir = modules_dir.clone();
+            let count = tokio::task::spawn_blocking(move || modules::compress_modules(&dir, compression)).await??;
+            println!("Compressed {} module(s) to .ko.{}", count, compression.extension());
+        }
+
+        match toolchain::find_system_tool("depmod") {
+            Some(depmod) => {
+                let mut cmd = TokioCommand::new(depmod);
+                cmd.arg("-b").arg(&staging);
+                let system_map = output_dir.join("System.map");
+                if system_map.is_file() {
+                    cmd.arg("-F").arg(system_map);
+                }
+                let status = cmd.arg(release).status().await?;
+                if !status.success() {
+                    return Err(format!("depmod failed for {}", release).into());
+                }
+            }
+            None => println!("Warning: depmod not found; modules.dep was not generated."),
+        }
+
+        let artifact_dir = build.artifact_root(kernel_path).join(release);
+        fs::create_dir_all(&artifact_dir).await?;
+        let bundle = artifact_dir.join(format!("modules-{}.tar.zst", release));
+        {
+            let (staging, bundle) = (staging.clone(), bundle.clone());
+            tokio::task::spawn_blocking(move || modules::create_bundle(&staging, &bundle)).await??;
+        }
+        if artifact_dir.join(artifacts::MANIFEST).exists() {
+            let mut manifest = Manifest::load(&artifact_dir)?;
+            manifest.add_file(&artifact_dir, &bundle)?;
+            manifest.save(&artifact_dir)?;
+        }
+        println!("Module bundle written to {:?}", bundle);
+        Ok(Some(bundle))
+    }
+
+    /// Builds the configured distribution packages through Kbuild's
+    /// `bindeb-pkg`/`binrpm-pkg` targets and moves them into
+    /// `artifacts/<release>/packages`. Debian builds yield image, headers
+    /// and (with debug info) dbg packages.
+    pub async fn build_packages(
+        &self,
+        kernel_path: &Path,
+        build: &BuildOptions,
+        release: &str,
+    ) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let output_dir = build.output_dir(kernel_path);
+        let artifact_dir = build.artifact_root(kernel_path).join(release);
+        let dest = artifact_dir.join("packages");
+        let mut built = Vec::new();
+
+        for &format in &build.package.formats {
+            let (builder, provider) = format.builder();
+            if toolchain::find_in_path(builder).is_none() {
+                return Err(format!("{} is needed for {} packages; install {}", builder, format.target(), provider).into());
+            }
+            if format == PackageFormat::Deb
+                && !DotConfig::load(&output_dir.join(".config"))?.get("DEBUG_INFO").is_set()
+            {
+                println!("Note: CONFIG_DEBUG_INFO is not set, so no -dbg package will be built.");
+            }
+
+            let macros = std::path::absolute(output_dir.join("ktp-rpmmacros"))?;
+            let settings = packaging::make_settings(format, release, &build.package, &macros)?;
+            let mut stage_build = build.clone();
+            let mut package_dir = output_dir.clone();
+            // bindeb-pkg writes its packages next to the build directory,
+            // which the sandbox keeps read-only. Sandboxed, it builds in a
+            // staging directory below the build directory instead.
+            if let (Some(sandbox), PackageFormat::Deb) = (&mut stage_build.sandbox, format) {
+                package_dir = output_dir.join(packaging::DEB_STAGING).join(
+                    output_dir.file_name().unwrap_or_else(|| std::ffi::OsStr::new("build")),
+                );
+                fs::create_dir_all(&package_dir).await?;
+                fs::copy(output_dir.join(".config"), package_dir.join(".config")).await?;
+                sandbox.writable.push(output_dir.clone());
+                stage_build.artifact_dir = Some(build.artifact_root(kernel_path));
+                stage_build.build_dir = Some(package_dir.clone());
+            }
+            let mut cmd = stage_build.make_command(kernel_path);
+            cmd.arg(format.target());
+            for (name, value) in settings.vars {
+                cmd.arg(format!("{}={}", name, value));
+            }
+            cmd.envs(settings.env);
+
+            // Packages are picked up by release and modification time; allow
+            // for coarse timestamps.
+            let since = std::time::SystemTime::now() - std::time::Duration::from_secs(1);
+            let stage = format!("make {}", format.target());
+            let status = self.run_make(kernel_path, build, cmd, &stage).await?;
+            if !status.success() {
+                self.print_build_summary(true).await;
+                return Err(format!("'{}' failed", stage).into());
+            }
+
+            let packages = {
+                let (package_dir, release, dest) = (package_dir.clone(), release.to_string(), dest.clone());
+                tokio::task::spawn_blocking(move || {
+                    packaging::collect_packages(format, &package_dir, &release, since, &dest)
+                })
+                .await??
+            };
+            if packages.is_empty() {
+                println!("Warning: '{}' succeeded but produced no packages", stage);
+            }
+            built.extend(packages);
+        }
+
+        if artifact_dir.join(artifacts::MANIFEST).exists() {
+            let mut manifest = Manifest::load(&artifact_dir)?;
+            for path in &built {
+                manifest.add_file(&artifact_dir, path)?;
+            }
+            manifest.save(&artifact_dir)?;
+        }
+        for path in &built {
+            println!("Package: {:?}", path);
+        }
+        Ok(built)
+    }
+
+    /// Installs the kernel into `<root>/boot` the way `make install` would,
+    /// generates an initramfs if a generator is available and writes a boot
+    /// entry. With `--root` nothing on the host is touched.
+    pub async fn install_kernel(
+        &self,
+        kernel_path: &Path,
+        build: &BuildOptions,
+        release: &str,
+        options: &InstallOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let output_dir = build.output_dir(kernel_path);
+        println!("Installing {} into {:?}", release, options.root);
+
+        let staged = build.staging_dir(kernel_path, release);
+        let staged_modules = modules::modules_dir(&staged, release);
+        let has_modules = DotConfig::load(&output_dir.join(".config"))?.get("MODULES") == &ConfigValue::Yes;
+        if has_modules && staged_modules.is_none() {
+            println!("Warning: no staged modules for {}; run without --no-modules to install them.", release);
+        }
+        let mut installed = {
+            let (output_dir, srcarch, release, options) =
+                (output_dir.clone(), build.srcarch(), release.to_string(), options.clone());
+            tokio::task::spawn_blocking(move || {
+                install::install_files(&output_dir, &srcarch, &release, staged_modules.as_deref(), &options)
+            })
+            .await??
+        };
+        for path in [&installed.image, &installed.system_map, &installed.config] {
+            println!("  {:?}", path);
+        }
+
+        if options.initramfs && has_modules {
+            match InitramfsGenerator::detect() {
+                Some(generator) => {
+                    println!("Generating initramfs with {}", generator.name);
+                    let status = generator.command(release, options)?.status().await?;
+                    if !status.success() {
+                        return Err(format!("{} failed for {}", generator.name, release).into());
+                    }
+                    let image = generator.output(&options.boot_dir(), release);
+                    println!("  {:?}", image);
+                    installed.initramfs = image.is_file().then_some(image);
+                }
+                None => println!("Warning: no initramfs generator (dracut, mkinitcpio, update-initramfs) found."),
+            }
+        }
+
+        let kind = options.boot_entry_kind();
+        match install::write_boot_entry(kind, options, release, &installed)? {
+            Some(entry) => {
+                println!("Boot entry written to {:?}", entry);
+                if kind == BootEntry::Grub {
+                    println!("Run grub-mkconfig (or update-grub) to add it to the boot menu.");
+                }
+            }
+            None if options.boot_entry == BootEntry::Auto => {
+                println!("No boot loader configuration found in {:?}; no boot entry written.", options.root)
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    async fn open_build_log(&self, kernel_path: &Path, build: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        let mut log = self.build_log.lock().await;
+        if log.is_none() {
+            *log = Some(BuildLog::create(&build.log_dir(kernel_path)).await?);
+        }
+        Ok(())
+    }
+
+    /// Runs a build stage (make, a patch or a hook) through the run's build log.
+    async fn run_make(
+        &self,
+        kernel_path: &Path,
+        build: &BuildOptions,
+        cmd: TokioCommand,
+        stage: &str,
+    ) -> Result<ExitStatus, Box<dyn Error>> {
+        self.open_build_log(kernel_path, build).await?;
+        let mut log = self.build_log.lock().await;
+        let log = log.as_mut().expect("build log opened");
+        Ok(log.run(cmd, stage).await?)
+    }
+
+    /// Compares this run's warnings with the baseline build, ignoring line
+    /// numbers, and fails under `--no-new-warnings` if any are new.
+    async fn check_warning_regressions(
+        &self,
+        kernel_path: &Path,
+        build: &BuildOptions,
+        full_build: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let log = self.build_log.lock().await;
+        let Some(log) = log.as_ref() else {
+            return Ok(());
+        };
+
+        let output_dir = build.output_dir(kernel_path);
+        // Relative file names in a baseline are as its compiler saw them,
+        // from its build directory.
+        let mut baseline_build = output_dir.clone();
+        let baseline_path = match &build.warning_baseline {
+            Some(path) if path.is_dir() => {
+                let logs = if path.join("ktp-logs").is_dir() {
+                    baseline_build = path.clone();
+                    path.join("ktp-logs")
+                } else {
+                    path.clone()
+                };
+                Some(buildlog::find_baseline(&logs).ok_or_else(|| format!("No build diagnostics found in {:?}", logs))?)
+            }
+            Some(path) => Some(path.clone()),
+            None => log.baseline.clone(),
+        };
+        let Some(baseline_path) = baseline_path else {
+            if build.no_new_warnings {
+                return Err("--no-new-warnings needs a baseline build; none found".into());
+            }
+            println!("No baseline build to compare warnings against.");
+            return Ok(());
+        };
+
+        let current = WarningSet::from_diagnostics(&log.diagnostics, kernel_path, &output_dir);
+        let baseline = WarningSet::load(&baseline_path, kernel_path, &baseline_build)?;
+        let mut diff = current.diff(&baseline);
+        if full_build {
+            println!(
+                "Warnings compared to {:?}: {} new, {} fixed",
+                baseline_path,
+                diff.new_count(),
+                diff.fixed_count()
+            );
+        } else {
+            // Files that were not recompiled have no warnings in this run.
+            diff.fixed.clear();
+            println!(
+                "Warnings compared to {:?}: {} new (incremental build, only recompiled files are compared)",
+                baseline_path,
+                diff.new_count()
+            );
+        }
+        diff.print();
+
+        if build.no_new_warnings && !diff.new.is_empty() {
+            return Err(format!("Build introduced {} new warning(s)", diff.new_count()).into());
+        }
+        Ok(())
+    }
+
+    /// Prints the diagnostics summary of this run and stores the records
+    /// next to the log.
+    pub async fn print_build_summary(&self, failed: bool) {
+        if let Some(log) = self.build_log.lock().await.as_ref() {
+            log.print_summary(failed);
+            match log.save_diagnostics() {
+                Ok(path) => println!("Diagnostics written to {:?}", path),
+                Err(e) => println!("Warning: {}", e),
+            }
+        }
+    }
 }
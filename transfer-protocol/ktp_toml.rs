@@ -0,0 +1,505 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tokio::process::Command as TokioCommand;
+
+use crate::artifacts::{self, SourceInfo};
+use crate::config::{KtpConfig, PackageConfig, ToolchainProfile};
+use crate::dotconfig;
+use crate::kbuild::{self, BuildOptions, ConfigMode};
+use crate::toolchain;
+
+pub const FILE_NAME: &str = "KTP.toml";
+
+/// Copy of the last `KTP.toml` whose hooks were allowed to run.
+pub const APPROVED_COPY: &str = ".ktp-toml.approved";
+
+/// Declarative build pipeline of a kernel tree, read from `KTP.toml` at its
+/// top. Paths are relative to the tree. Options given on the command line
+/// take precedence over the manifest.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TreeManifest {
+    pub source: SourceDecl,
+    /// Expected SHA-256 of files in the tree.
+    pub checksums: BTreeMap<String, String>,
+    pub patches: PatchesDecl,
+    pub config: ConfigDecl,
+    /// A toolchain profile name from the KTP config, or an inline profile.
+    pub toolchain: Option<ToolchainDecl>,
+    pub build: BuildDecl,
+    pub package: Option<PackageConfig>,
+    pub hooks: HooksDecl,
+}
+
+/// Where the tree comes from, checked against the download record or the
+/// Git checkout.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceDecl {
+    pub url: Option<String>,
+    /// SHA-256 of the downloaded archive.
+    pub sha256: Option<String>,
+    /// Git commit (or a prefix of it) the tree must be checked out at.
+    pub commit: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PatchesDecl {
+    /// Patch files, applied in order with `patch -p<strip>`.
+    pub files: Vec<PathBuf>,
+    pub strip: u32,
+}
+
+impl Default for PatchesDecl {
+    fn default() -> Self {
+        PatchesDecl { files: Vec::new(), strip: 1 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigDecl {
+    /// Configuration mode as `--kernel-config` takes it.
+    pub mode: Option<String>,
+    pub fragments: Vec<PathBuf>,
+    /// `CONFIG_NAME = "value"` edits applied after the fragments.
+    pub set: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ToolchainDecl {
+    Profile(String),
+    Inline(Box<ToolchainProfile>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuildDecl {
+    pub targets: Vec<String>,
+    pub make_vars: BTreeMap<String, String>,
+    pub build_dir: Option<PathBuf>,
+    /// Run KTP's configure, compile and post-build stages.
+    pub compile: bool,
+    /// Run the tree's `KTP.mk` as a legacy stage after the build.
+    pub ktp_mk: bool,
+}
+
+impl Default for BuildDecl {
+    fn default() -> Self {
+        BuildDecl { targets: Vec::new(), make_vars: BTreeMap::new(), build_dir: None, compile: true, ktp_mk: false }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksDecl {
+    /// Shell commands run in the tree after the build, with `KTP_SOURCE_DIR`,
+    /// `KTP_OUTPUT_DIR`, `KTP_ARTIFACT_DIR` and `KTP_RELEASE` set.
+    pub post_build: Vec<String>,
+}
+
+impl TreeManifest {
+    pub fn exists(kernel_path: &Path) -> bool {
+        kernel_path.join(FILE_NAME).is_file()
+    }
+
+    pub fn load(kernel_path: &Path) -> Result<Self> {
+        let path = kernel_path.join(FILE_NAME);
+        let text = std::fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        Self::parse(&text).with_context(|| format!("Invalid {:?}", path))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Everything wrong with the manifest for this tree, so that all of it
+    /// is reported before any stage runs. Declarations that cannot be
+    /// checked are only warned about. The manifest comes with the tree, so
+    /// every path in it has to stay inside the tree.
+    pub fn validate(&self, kernel_path: &Path, config: &KtpConfig) -> Vec<String> {
+        let mut errors = Vec::new();
+        let in_tree = |errors: &mut Vec<String>, what: &str, path: &Path, must_exist: bool| {
+            if !within_tree(kernel_path, path) {
+                errors.push(format!("{} {:?} is outside the tree", what, path));
+                false
+            } else if must_exist && !kernel_path.join(path).is_file() {
+                errors.push(format!("{} {:?} does not exist", what, path));
+                false
+            } else {
+                true
+            }
+        };
+
+        let source = SourceInfo::detect(kernel_path);
+        if let Some(expected) = &self.source.sha256 {
+            match &source.tarball_sha256 {
+                _ if !is_sha256(expected) => errors.push(format!("source.sha256 '{}' is not a SHA-256", expected)),
+                Some(actual) if !actual.eq_ignore_ascii_case(expected) => {
+                    errors.push(format!("source.sha256 is {}, but the downloaded archive had {}", expected, actual))
+                }
+                Some(_) => {}
+                None => println!("Warning: no download record in the tree; source.sha256 cannot be checked."),
+            }
+        }
+        if let Some(expected) = &self.source.commit {
+            match &source.commit {
+                Some(actual) if !actual.starts_with(&expected.to_ascii_lowercase()) => {
+                    errors.push(format!("source.commit is {}, but the tree is checked out at {}", expected, actual))
+                }
+                Some(_) => {}
+                None => println!("Warning: the tree is not a Git checkout; source.commit cannot be checked."),
+            }
+        }
+        if let (Some(url), Some(tarball)) = (&self.source.url, &source.tarball) {
+            if url != tarball {
+                println!("Warning: source.url is {} but the tree was downloaded from {}", url, tarball);
+            }
+        }
+
+        for (file, expected) in &self.checksums {
+            if !in_tree(&mut errors, "Checksummed file", Path::new(file), false) {
+                continue;
+            }
+            let path = kernel_path.join(file);
+            match artifacts::sha256_file(&path) {
+                _ if !is_sha256(expected) => errors.push(format!("checksums.\"{}\" is not a SHA-256", file)),
+                Ok(actual) if !actual.eq_ignore_ascii_case(expected) => {
+                    errors.push(format!("{} has SHA-256 {}, expected {}", file, actual, expected))
+                }
+                Ok(_) => {}
+                Err(_) => errors.push(format!("checksummed file {:?} does not exist", file)),
+            }
+        }
+
+        for patch in &self.patches.files {
+            in_tree(&mut errors, "Patch", patch, true);
+        }
+        if !self.patches.files.is_empty() && toolchain::find_in_path("patch").is_none() {
+            errors.push("patches are declared, but the patch program is not installed".to_string());
+        }
+
+        if let Some(mode) = &self.config.mode {
+            match kbuild::parse_config_mode(mode) {
+                Ok(ConfigMode::CopyFrom(path)) => {
+                    in_tree(&mut errors, "config.mode file", &path, true);
+                }
+                Ok(_) => {}
+                Err(e) => errors.push(format!("config.mode: {}", e)),
+            }
+        }
+        for fragment in &self.config.fragments {
+            in_tree(&mut errors, "Config fragment", fragment, true);
+        }
+        for (name, value) in &self.config.set {
+            if let Err(e) = dotconfig::parse_assignment(&format!("{}={}", name, value)) {
+                errors.push(format!("config.set: {}", e));
+            }
+        }
+
+        match &self.toolchain {
+            Some(ToolchainDecl::Profile(name)) => {
+                if let Err(e) = config.toolchain(name) {
+                    errors.push(format!("toolchain: {}", e));
+                }
+            }
+            Some(ToolchainDecl::Inline(profile)) => {
+                if let Some(dir) = &profile.build_dir {
+                    in_tree(&mut errors, "toolchain.build_dir", dir, false);
+                }
+            }
+            None => {}
+        }
+        if let Some(dir) = &self.build.build_dir {
+            in_tree(&mut errors, "build.build_dir", dir, false);
+        }
+        for name in self.build.make_vars.keys() {
+            if let Err(e) = kbuild::parse_make_var(&format!("{}=", name)) {
+                errors.push(format!("build.make_vars: {}", e));
+            } else if name == "O" || name == "KBUILD_OUTPUT" {
+                errors.push(format!("build.make_vars cannot set {}; use build.build_dir", name));
+            }
+        }
+        if self.build.ktp_mk && !kernel_path.join("KTP.mk").is_file() {
+            errors.push("build.ktp_mk is set, but the tree has no KTP.mk".to_string());
+        }
+        if !self.build.compile && !self.build.ktp_mk && !self.hooks.post_build.is_empty() {
+            println!("Warning: build.compile is off and nothing else builds; the hooks run on an unbuilt tree.");
+        }
+        if self.hooks.post_build.iter().any(|hook| hook.trim().is_empty()) {
+            errors.push("hooks.post_build contains an empty command".to_string());
+        }
+        errors
+    }
+
+    /// `build` with the manifest's settings filled in where the command line
+    /// left them unset. Fragments, config edits and make variables from the
+    /// manifest come first, so the command line's override them.
+    pub fn apply(&self, kernel_path: &Path, build: &BuildOptions, config: &KtpConfig) -> Result<BuildOptions> {
+        let tree = std::path::absolute(kernel_path)?;
+        let mut build = build.clone();
+
+        if build.toolchain.is_none() {
+            build.toolchain = match &self.toolchain {
+                Some(ToolchainDecl::Profile(name)) => Some(config.toolchain(name)?),
+                Some(ToolchainDecl::Inline(profile)) => Some(ToolchainProfile {
+                    name: FILE_NAME.to_string(),
+                    build_dir: profile.build_dir.as_ref().map(|dir| kbuild::normalize_path(&tree.join(dir))),
+                    ..profile.as_ref().clone()
+                }),
+                None => None,
+            };
+        }
+        if build.build_dir.is_none() {
+            build.build_dir = self
+                .build
+                .build_dir
+                .as_ref()
+                .map(|dir| kbuild::normalize_path(&tree.join(dir)))
+                .or_else(|| build.toolchain.as_ref().and_then(|t| t.build_dir.clone()))
+                .map(std::path::absolute)
+                .transpose()?;
+        }
+
+        if build.config_mode.is_none() {
+            build.config_mode = self
+                .config
+                .mode
+                .as_deref()
+                .map(kbuild::parse_config_mode)
+                .transpose()
+                .map_err(anyhow::Error::msg)?
+                .map(|mode| match mode {
+                    ConfigMode::CopyFrom(path) => ConfigMode::CopyFrom(tree.join(path)),
+                    mode => mode,
+                });
+        }
+        build.config_fragments =
+            self.config.fragments.iter().map(|f| tree.join(f)).chain(build.config_fragments).collect();
+        let mut edits = Vec::new();
+        for (name, value) in &self.config.set {
+            edits.push(dotconfig::parse_assignment(&format!("{}={}", name, value)).map_err(anyhow::Error::msg)?);
+        }
+        build.config_edits = edits.into_iter().chain(build.config_edits).collect();
+
+        if build.targets.is_empty() {
+            build.targets = self.build.targets.clone();
+        }
+        build.make_vars =
+            self.build.make_vars.iter().map(|(k, v)| (k.clone(), v.clone())).chain(build.make_vars).collect();
+
+        if let Some(package) = &self.package {
+            if build.package.formats.is_empty() {
+                build.package.formats = package.formats.clone();
+            }
+            let current = &mut build.package;
+            current.revision = current.revision.take().or_else(|| package.revision.clone());
+            current.maintainer = current.maintainer.take().or_else(|| package.maintainer.clone());
+            current.distribution = current.distribution.take().or_else(|| package.distribution.clone());
+            current.source_name = current.source_name.take().or_else(|| package.source_name.clone());
+            current.local_version = current.local_version.take().or_else(|| package.local_version.clone());
+        }
+        Ok(build)
+    }
+
+    /// The stages this manifest runs, in order, for the plan printed before
+    /// running it.
+    pub fn stages(&self) -> Vec<String> {
+        let mut stages = Vec::new();
+        if !self.patches.files.is_empty() {
+            stages.push(format!("apply {} patch(es)", self.patches.files.len()));
+        }
+        if self.build.compile {
+            stages.push("configure, compile and post-build".to_string());
+        }
+        if self.build.ktp_mk {
+            stages.push("KTP.mk (legacy)".to_string());
+        }
+        if !self.hooks.post_build.is_empty() {
+            stages.push(format!("{} post-build hook(s)", self.hooks.post_build.len()));
+        }
+        stages
+    }
+}
+
+/// Whether `path`, relative to `tree`, stays inside it: it is relative,
+/// does not lead out through `..` and does not lead out through a symlink
+/// either. For a path that does not exist yet, its deepest existing
+/// ancestor decides.
+fn within_tree(tree: &Path, path: &Path) -> bool {
+    if !lexically_within(path) {
+        return false;
+    }
+    let Ok(root) = tree.canonicalize() else {
+        return false;
+    };
+    let full = tree.join(path);
+    let existing = full.ancestors().find(|p| p.symlink_metadata().is_ok()).unwrap_or(tree);
+    existing.canonicalize().is_ok_and(|resolved| resolved.starts_with(&root))
+}
+
+/// Whether a relative `path` stays below the directory it is relative to,
+/// judged from its components alone.
+fn lexically_within(path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(_) => depth += 1,
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => match depth.checked_sub(1) {
+                Some(up) => depth = up,
+                None => return false,
+            },
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Summary of the code a `KTP.toml` makes KTP run, for the trust policy.
+pub fn print_hooks(text: &str) {
+    let Ok(manifest) = TreeManifest::parse(text) else {
+        return;
+    };
+    println!("KTP.toml hooks will run:");
+    for hook in &manifest.hooks.post_build {
+        println!("  post-build: {}", hook);
+    }
+    crate::ktp_mk::print_notable(text);
+}
+
+/// Whether `patch` is already in the tree: it reverses cleanly.
+pub async fn patch_applied(kernel_path: &Path, patch: &Path, strip: u32) -> bool {
+    TokioCommand::new("patch")
+        .current_dir(kernel_path)
+        .arg(format!("-p{}", strip))
+        .args(["--reverse", "--dry-run", "--batch", "--force", "--silent", "-i"])
+        .arg(patch)
+        .output()
+        .await
+        .is_ok_and(|output| output.status.success())
+}
+
+pub fn patch_command(kernel_path: &Path, patch: &Path, strip: u32) -> TokioCommand {
+    let mut cmd = TokioCommand::new("patch");
+    cmd.current_dir(kernel_path).arg(format!("-p{}", strip)).args(["--forward", "--batch", "-i"]).arg(patch);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dotconfig::ConfigValue;
+
+    #[test]
+    fn lexically_within_rejects_escapes() {
+        for path in ["patches/a.patch", "./configs/x.config", "a/../b", "build"] {
+            assert!(lexically_within(Path::new(path)), "{}", path);
+        }
+        for path in ["../build", "a/../../b", "/etc/passwd", "../../.."] {
+            assert!(!lexically_within(Path::new(path)), "{}", path);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_paths_outside_the_tree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tree = tmp.path();
+        let manifest = TreeManifest::parse(
+            r#"
+            checksums = { "../secret" = "0000000000000000000000000000000000000000000000000000000000000000" }
+            patches = { files = ["../fix.patch"] }
+            config = { mode = "/home/u/.ssh/id_rsa", fragments = ["/etc/hostname"] }
+            build = { build_dir = "../../..", make_vars = { O = "/home/u" } }
+            "#,
+        )
+        .unwrap();
+        let errors = manifest.validate(tree, &KtpConfig::default());
+        for expected in ["../secret", "../fix.patch", "id_rsa", "/etc/hostname", "build.build_dir", "cannot set O"] {
+            assert!(errors.iter().any(|e| e.contains(expected)), "no error for {}: {:?}", expected, errors);
+        }
+        assert!(errors.iter().all(|e| !e.contains("does not exist")), "{:?}", errors);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn validate_rejects_symlinks_out_of_the_tree() {
+        let (tree, outside) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (tree, outside) = (tree.path(), outside.path());
+        std::fs::write(outside.join("x.config"), "CONFIG_X=y\n").unwrap();
+        std::os::unix::fs::symlink(outside, tree.join("configs")).unwrap();
+        let manifest =
+            TreeManifest::parse(r#"config = { fragments = ["configs/x.config"] }
+build = { build_dir = "configs/build" }"#)
+                .unwrap();
+        let errors = manifest.validate(tree, &KtpConfig::default());
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors.iter().all(|e| e.contains("outside the tree")), "{:?}", errors);
+    }
+
+    #[test]
+    fn validate_accepts_a_manifest_inside_the_tree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tree = tmp.path();
+        std::fs::create_dir_all(tree.join("configs")).unwrap();
+        std::fs::write(tree.join("configs/x.config"), "CONFIG_X=y\n").unwrap();
+        let manifest = TreeManifest::parse(
+            r#"
+            config = { mode = "defconfig", fragments = ["configs/x.config"], set = { CONFIG_Y = "m" } }
+            build = { build_dir = "build", targets = ["bzImage"] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(manifest.validate(tree, &KtpConfig::default()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn apply_lets_the_command_line_win() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tree = tmp.path();
+        let manifest = TreeManifest::parse(
+            r#"
+            config = { mode = "tinyconfig", fragments = ["a.config"], set = { CONFIG_A = "y" } }
+            build = { build_dir = "out/../build", targets = ["bzImage"], make_vars = { KCFLAGS = "-O1" } }
+            "#,
+        )
+        .unwrap();
+
+        let defaults = manifest.apply(tree, &BuildOptions::default(), &KtpConfig::default()).unwrap();
+        assert_eq!(defaults.build_dir, Some(tree.join("build")));
+        assert_eq!(defaults.config_mode, Some(ConfigMode::Tinyconfig));
+        assert_eq!(defaults.targets, vec!["bzImage".to_string()]);
+
+        let cli = BuildOptions {
+            build_dir: Some(PathBuf::from("/cli/build")),
+            config_mode: Some(ConfigMode::Olddefconfig),
+            config_fragments: vec![PathBuf::from("/cli/b.config")],
+            config_edits: vec![("A".to_string(), ConfigValue::NotSet)],
+            targets: vec!["modules".to_string()],
+            make_vars: vec![("KCFLAGS".to_string(), "-O2".to_string())],
+            ..BuildOptions::default()
+        };
+        let build = manifest.apply(tree, &cli, &KtpConfig::default()).unwrap();
+        assert_eq!(build.build_dir, Some(PathBuf::from("/cli/build")));
+        assert_eq!(build.config_mode, Some(ConfigMode::Olddefconfig));
+        assert_eq!(build.targets, vec!["modules".to_string()]);
+        // Applied in order, so the command line's come last and win.
+        assert_eq!(build.config_fragments, vec![tree.join("a.config"), PathBuf::from("/cli/b.config")]);
+        assert_eq!(
+            build.config_edits,
+            vec![("A".to_string(), ConfigValue::Yes), ("A".to_string(), ConfigValue::NotSet)]
+        );
+        assert_eq!(
+            build.make_vars,
+            vec![("KCFLAGS".to_string(), "-O1".to_string()), ("KCFLAGS".to_string(), "-O2".to_string())]
+        );
+    }
+}
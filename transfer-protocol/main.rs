@@ -1,10 +1,239 @@
 use std::path::PathBuf;
-use clap::{Parser, Subcommand};
-use tokio;
+use clap::{ArgAction, ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use rpassword::read_password;
+use std::io::IsTerminal;
 
 pub mod ktp_protocol;
 pub mod gitfetcher;
+pub mod config;
+pub mod http_auth;
+pub mod tls;
+pub mod mirror;
+pub mod extract;
+pub mod preflight;
+pub mod kbuild;
+pub mod toolchain;
+pub mod dotconfig;
+pub mod kconfig;
+pub mod buildlog;
+pub mod artifacts;
+pub mod modules;
+pub mod packaging;
+pub mod install;
+pub mod hostdeps;
+pub mod incremental;
+pub mod compiler_cache;
+pub mod sandbox;
+pub mod ktp_mk;
+pub mod ktp_toml;
+
+#[derive(Args)]
+struct DownloadArgs {
+    /// Decompress (gz/xz/zstd) and untar while downloading
+    #[arg(long)]
+    extract: bool,
+    /// Keep the compressed archive when extracting
+    #[arg(long, requires = "extract")]
+    keep_archive: bool,
+    #[arg(long, default_value_t = 0, requires = "extract")]
+    strip_components: usize,
+    /// Expected SHA-256 of the downloaded file
+    #[arg(long)]
+    sha256: Option<String>,
+}
+
+impl DownloadArgs {
+    fn into_options(self) -> extract::DownloadOptions {
+        extract::DownloadOptions {
+            extract: self.extract.then_some(extract::ExtractOptions {
+                strip_components: self.strip_components,
+                keep_archive: self.keep_archive,
+            }),
+            sha256: self.sha256,
+        }
+    }
+}
+
+#[derive(Args)]
+struct BuildArgs {
+    /// Parallel make jobs (defaults to the CPUs available to KTP)
+    #[arg(short = 'j', long, global = true)]
+    jobs: Option<usize>,
+    /// Extra make variable, NAME=value (repeatable)
+    #[arg(long = "make-var", value_parser = kbuild::parse_make_var, global = true)]
+    make_vars: Vec<(String, String)>,
+    /// Build target such as bzImage, modules or dtbs (repeatable)
+    #[arg(long = "target", global = true)]
+    targets: Vec<String>,
+    /// Run make with V=1
+    #[arg(long, global = true)]
+    verbose_build: bool,
+    /// Toolchain profile from the config file (ARCH, CROSS_COMPILE, LLVM, ...)
+    #[arg(long, global = true)]
+    toolchain: Option<String>,
+    /// Out-of-tree build directory passed to make as O= (overrides the profile's build_dir)
+    #[arg(long, global = true)]
+    build_dir: Option<PathBuf>,
+    /// How .config is produced: menuconfig, nconfig, xconfig, defconfig, <name>_defconfig,
+    /// olddefconfig, localmodconfig, tinyconfig, skip or a path to a .config to copy
+    /// (default: menuconfig on a terminal, otherwise olddefconfig or defconfig)
+    #[arg(long, value_parser = kbuild::parse_config_mode, global = true)]
+    kernel_config: Option<kbuild::ConfigMode>,
+    /// Config fragment merged over the base .config (repeatable, applied in order)
+    #[arg(long = "config-fragment", global = true)]
+    config_fragments: Vec<PathBuf>,
+    /// Set a config option, CONFIG_NAME=value (repeatable)
+    #[arg(long = "set", value_parser = dotconfig::parse_assignment, global = true)]
+    config_set: Vec<(String, dotconfig::ConfigValue)>,
+    /// Disable a config option, CONFIG_NAME (repeatable)
+    #[arg(long = "unset", value_parser = dotconfig::parse_symbol, global = true)]
+    config_unset: Vec<String>,
+    /// `--set` and `--unset` merged in command-line order by `order_config_edits`.
+    #[arg(skip)]
+    config_edits: Vec<(String, dotconfig::ConfigValue)>,
+    /// Directory for build logs and diagnostics (default: <build dir>/ktp-logs)
+    #[arg(long, global = true)]
+    log_dir: Option<PathBuf>,
+    /// Compare warnings against this diagnostics file, log dir or build dir (default: previous run)
+    #[arg(long, global = true)]
+    warning_baseline: Option<PathBuf>,
+    /// Fail when the build has warnings the baseline does not
+    #[arg(long, global = true)]
+    no_new_warnings: bool,
+    /// Root for artifacts/<release>/ directories (default: <build dir>/artifacts)
+    #[arg(long, global = true)]
+    artifact_dir: Option<PathBuf>,
+    /// Do not collect build artifacts after compiling
+    #[arg(long, global = true)]
+    no_artifacts: bool,
+    /// Do not run modules_install or build the module bundle
+    #[arg(long, global = true)]
+    no_modules: bool,
+    /// Strip debug info from installed modules (INSTALL_MOD_STRIP=1)
+    #[arg(long, global = true)]
+    strip_modules: bool,
+    /// Compress installed modules: gzip, xz or zstd
+    #[arg(long, value_parser = modules::parse_compression, global = true)]
+    module_compress: Option<modules::ModuleCompression>,
+    /// Build distribution packages: deb or rpm (repeatable; overrides [package] formats)
+    #[arg(long = "package", value_parser = packaging::parse_format, global = true)]
+    packages: Vec<packaging::PackageFormat>,
+    /// Package revision (overrides [package] revision)
+    #[arg(long, global = true)]
+    package_revision: Option<String>,
+    /// Package maintainer, "Name <email>" (overrides [package] maintainer)
+    #[arg(long, global = true)]
+    maintainer: Option<String>,
+    /// Install the kernel, System.map, config and modules after building
+    #[arg(long, global = true)]
+    install: bool,
+    /// Root to install into instead of / (implies --install)
+    #[arg(long, global = true)]
+    root: Option<PathBuf>,
+    /// Do not generate an initramfs when installing
+    #[arg(long, global = true)]
+    no_initramfs: bool,
+    /// Boot entry to write when installing: auto, bls, grub or none
+    #[arg(long, value_parser = install::parse_boot_entry, default_value = "auto", global = true)]
+    boot_entry: install::BootEntry,
+    /// Kernel command line for the boot entry (default: <root>/etc/kernel/cmdline or /proc/cmdline)
+    #[arg(long, global = true)]
+    kernel_cmdline: Option<String>,
+    /// Clean before compiling: none, clean, mrproper or distclean (default: decided from what changed)
+    #[arg(long, value_parser = incremental::parse_clean_level, global = true)]
+    clean: Option<incremental::CleanLevel>,
+    /// Compiler cache wrapping CC and HOSTCC: ccache or sccache (overrides the profile's [cache])
+    #[arg(long, value_parser = compiler_cache::parse_cache_tool, global = true)]
+    compiler_cache: Option<compiler_cache::CacheTool>,
+    /// Do not use the profile's compiler cache
+    #[arg(long, conflicts_with = "compiler_cache", global = true)]
+    no_compiler_cache: bool,
+    /// Pin SOURCE_DATE_EPOCH, build user/host/timestamp and path prefixes so builds are reproducible
+    #[arg(long, global = true)]
+    reproducible: bool,
+    /// Run make stages and KTP.mk in a user/mount/network namespace with a read-only source tree and no network
+    #[arg(long, global = true)]
+    sandbox: bool,
+}
+
+impl BuildArgs {
+    /// Merges `--set` and `--unset` into one list in the order they were
+    /// given, so `--unset X --set X=y` ends with X=y. The options are global,
+    /// so their positions are read from the innermost subcommand's matches.
+    fn order_config_edits(&mut self, matches: &ArgMatches) {
+        let mut leaf = matches;
+        while let Some((_, sub)) = leaf.subcommand() {
+            leaf = sub;
+        }
+        let indices = |id: &str| leaf.indices_of(id).map(|i| i.collect::<Vec<_>>()).unwrap_or_default();
+        let sets = self.config_set.drain(..);
+        let unsets = self.config_unset.drain(..).map(|name| (name, dotconfig::ConfigValue::NotSet));
+        let mut edits: Vec<(usize, (String, dotconfig::ConfigValue))> = indices("config_set")
+            .into_iter()
+            .zip(sets)
+            .chain(indices("config_unset").into_iter().zip(unsets))
+            .collect();
+        edits.sort_by_key(|(index, _)| *index);
+        self.config_edits = edits.into_iter().map(|(_, edit)| edit).collect();
+    }
+
+    fn into_options(self, config: &config::KtpConfig) -> anyhow::Result<kbuild::BuildOptions> {
+        let toolchain = self.toolchain.map(|name| config.toolchain(&name)).transpose()?;
+        // make runs inside the source tree, so O= has to be absolute.
+        let build_dir = self
+            .build_dir
+            .or_else(|| toolchain.as_ref().and_then(|t| t.build_dir.clone()))
+            .map(std::path::absolute)
+            .transpose()?;
+        let install = match self.root {
+            Some(root) => Some(std::path::absolute(root)?),
+            None => self.install.then(|| PathBuf::from("/")),
+        }
+        .map(|root| install::InstallOptions {
+            root,
+            initramfs: !self.no_initramfs,
+            boot_entry: self.boot_entry,
+            cmdline: self.kernel_cmdline,
+        });
+        let profile_cache = toolchain.as_ref().and_then(|t| t.cache.clone());
+        let compiler_cache = match self.compiler_cache {
+            _ if self.no_compiler_cache => None,
+            Some(tool) => Some(compiler_cache::CacheConfig { tool, ..profile_cache.unwrap_or_default() }),
+            None => profile_cache,
+        };
+        let mut package = config.package.clone();
+        if !self.packages.is_empty() {
+            package.formats = self.packages;
+        }
+        package.revision = self.package_revision.or(package.revision);
+        package.maintainer = self.maintainer.or(package.maintainer);
+        Ok(kbuild::BuildOptions {
+            jobs: self.jobs,
+            make_vars: self.make_vars,
+            targets: self.targets,
+            verbose: self.verbose_build,
+            toolchain,
+            build_dir,
+            config_mode: self.kernel_config,
+            config_fragments: self.config_fragments,
+            config_edits: self.config_edits,
+            log_dir: self.log_dir,
+            warning_baseline: self.warning_baseline,
+            no_new_warnings: self.no_new_warnings,
+            artifact_dir: self.artifact_dir,
+            no_artifacts: self.no_artifacts,
+            no_modules: self.no_modules,
+            strip_modules: self.strip_modules,
+            module_compression: self.module_compress,
+            package,
+            install,
+            clean: self.clean,
+            compiler_cache,
+            reproducible: self.reproducible,
+            sandbox: self.sandbox.then(sandbox::SandboxOptions::default),
+        })
+    }
+}
 
 #[derive(Subcommand)]
 enum Protocol {
@@ -15,12 +244,38 @@ enum Protocol {
         dest: PathBuf,
         #[arg(long)]
         username: Option<String>,
+        #[command(flatten)]
+        download: DownloadArgs,
     },
     Http {
         #[arg(long, required = true)]
         source: String,
         #[arg(long, required = true)]
         dest: PathBuf,
+        /// Basic auth user; the password comes from KTP_HTTP_PASSWORD, the config or a prompt
+        #[arg(long)]
+        username: Option<String>,
+        /// Use credentials from ~/.netrc (or --netrc-file)
+        #[arg(long)]
+        netrc: bool,
+        #[arg(long)]
+        netrc_file: Option<PathBuf>,
+        /// Recursively mirror a directory index page
+        #[arg(long)]
+        recursive: bool,
+        #[arg(long, default_value_t = 10)]
+        max_depth: usize,
+        /// Only download files matching this wildcard (repeatable)
+        #[arg(long)]
+        include: Vec<String>,
+        /// Skip files and directories matching this wildcard (repeatable)
+        #[arg(long)]
+        exclude: Vec<String>,
+        /// Follow index links that point to other hosts
+        #[arg(long)]
+        allow_cross_host: bool,
+        #[command(flatten)]
+        download: DownloadArgs,
     },
     Ftp {
         #[arg(long, required = true)]
@@ -31,6 +286,8 @@ enum Protocol {
         username: Option<String>,
         #[arg(long)]
         password: Option<String>,
+        #[command(flatten)]
+        download: DownloadArgs,
     },
     Mktp {
         #[arg(long, required = true)]
@@ -40,6 +297,27 @@ enum Protocol {
         #[arg(long, required = true)]
         dest: PathBuf,
     },
+    /// Validate or run a tree's KTP.toml pipeline manifest
+    Manifest {
+        #[command(subcommand)]
+        action: ManifestAction,
+    },
+    /// Operations on completed builds
+    Build {
+        #[command(subcommand)]
+        action: BuildAction,
+    },
+    /// Check the toolchain and host build dependencies
+    Doctor {
+        /// Kernel source tree whose .config (or the --build-dir one) decides what is needed
+        #[arg(long)]
+        source: Option<PathBuf>,
+    },
+    /// Inspect kernel configurations
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
     Git {
         #[arg(long)]
         source: String,
@@ -60,6 +338,60 @@ enum Protocol {
     },
 }
 
+#[derive(Subcommand)]
+enum BuildAction {
+    /// Rebuild in a fresh build directory and compare artifact hashes with the existing build
+    Verify {
+        /// Kernel source tree; the build to verify is the one in --build-dir
+        #[arg(long, required = true)]
+        source: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ManifestAction {
+    /// Validate KTP.toml against the tree and print its stages
+    Check {
+        #[arg(long, required = true)]
+        dest: PathBuf,
+    },
+    /// Validate KTP.toml, then run its stages
+    Run {
+        #[arg(long, required = true)]
+        dest: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Compare two .config files, build directories or gzipped configs (/proc/config.gz)
+    Diff {
+        a: PathBuf,
+        b: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Explain why a symbol is (not) set and what must be enabled for it
+    Explain {
+        symbol: String,
+        /// Kernel source tree; its .config (or the --build-dir one) is evaluated
+        #[arg(long, required = true)]
+        source: PathBuf,
+    },
+    /// Search symbol names and prompts, like menuconfig's '/'
+    Search {
+        pattern: String,
+        #[arg(long, required = true)]
+        source: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
 #[derive(Parser)]
 #[command(name = "KTP Controller")]
 #[command(author = "Zaman Huseyinli")]
@@ -68,18 +400,36 @@ enum Protocol {
 struct Cli {
     #[command(subcommand)]
     protocol: Protocol,
-    #[arg(long, default_value_t = true)]
+    /// Configure and compile after transferring (--auto-compile=false to only transfer)
+    #[arg(long, default_value_t = true, num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
     auto_compile: bool,
+    /// Path to the KTP config file
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Skip the free space and filesystem checks before transferring
+    #[arg(long)]
+    skip_preflight: bool,
+    /// When a tree's KTP.mk may run: deny, prompt, allowlist or signed (overrides [ktp_mk] policy)
+    #[arg(long, value_parser = ktp_mk::parse_trust_policy)]
+    ktp_mk_policy: Option<ktp_mk::TrustPolicy>,
+    #[command(flatten)]
+    build: BuildArgs,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
-    let ktp = ktp_protocol::KtpController::new();
-    let gitfetcher_instance = gitfetcher::GitFetcher::new();
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    cli.build.order_config_edits(&matches);
+    let mut config = config::KtpConfig::load(cli.config.as_deref())?;
+    if let Some(policy) = cli.ktp_mk_policy {
+        config.ktp_mk.policy = policy;
+    }
+    let build = cli.build.into_options(&config)?;
+    let ktp = ktp_protocol::KtpController::with_config(config);
 
     match cli.protocol {
-        Protocol::Scp { source, dest, username } => {
+        Protocol::Scp { source, dest, username, download } => {
             ktp.transfer_kernel(ktp_protocol::TransferOptions {
                 protocol: ktp_protocol::TransferProtocol::SSH,
                 source_url: source,
@@ -87,9 +437,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 auto_compile: cli.auto_compile,
                 username,
                 password: None,
+                http_auth: http_auth::HttpAuth::None,
+                mirror: None,
+                download: download.into_options(),
+                preflight: !cli.skip_preflight,
+                build: build.clone(),
             }).await?;
         }
-        Protocol::Http { source, dest } => {
+        Protocol::Http {
+            source,
+            dest,
+            username,
+            netrc,
+            netrc_file,
+            recursive,
+            max_depth,
+            include,
+            exclude,
+            allow_cross_host,
+            download,
+        } => {
+            // Secrets are not taken as arguments, where ps and the shell
+            // history would show them; see HttpAuth::resolve.
+            let explicit = match username {
+                Some(username) => http_auth::HttpAuth::Basic { username, password: None },
+                None if netrc || netrc_file.is_some() => http_auth::HttpAuth::Netrc(netrc_file),
+                None => http_auth::HttpAuth::None,
+            };
+            let mut auth = http_auth::HttpAuth::resolve(&source, explicit, &ktp.config().http)?;
+            if auth.needs_password() && std::io::stdin().is_terminal() {
+                println!("Enter HTTP password (input hidden): ");
+                auth = auth.with_password(read_password()?);
+            }
             ktp.transfer_kernel(ktp_protocol::TransferOptions {
                 protocol: ktp_protocol::TransferProtocol::HTTP,
                 source_url: source,
@@ -97,9 +476,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 auto_compile: cli.auto_compile,
                 username: None,
                 password: None,
+                http_auth: auth,
+                mirror: recursive.then_some(mirror::MirrorOptions {
+                    max_depth,
+                    include,
+                    exclude,
+                    same_host: !allow_cross_host,
+                }),
+                download: download.into_options(),
+                preflight: !cli.skip_preflight,
+                build: build.clone(),
             }).await?;
         }
-        Protocol::Ftp { source, dest, username, mut password } => {
+        Protocol::Ftp { source, dest, username, mut password, download } => {
             if password.is_none() {
                 println!("Enter FTP password (input hidden): ");
                 password = Some(read_password()?);
@@ -111,16 +500,64 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 auto_compile: cli.auto_compile,
                 username,
                 password,
+                http_auth: http_auth::HttpAuth::None,
+                mirror: None,
+                download: download.into_options(),
+                preflight: !cli.skip_preflight,
+                build: build.clone(),
             }).await?;
         }
         Protocol::Mktp { dest } => {
-            ktp.run_ktp_mk(&dest).await?;
+            if !cli.skip_preflight {
+                ktp.check_toolchain(&build).await?;
+            }
+            ktp.run_ktp_mk(&dest, &build).await?;
         }
         Protocol::Kconfig { dest } => {
-            ktp.kconfig_interface(&dest).await?;
+            if !cli.skip_preflight {
+                ktp.check_toolchain(&build).await?;
+            }
+            ktp.kconfig_interface(&dest, &build).await?;
             if cli.auto_compile {
-                ktp.compile_kernel(&dest).await?;
+                if !cli.skip_preflight {
+                    ktp.check_host_dependencies(&dest, &build).await?;
+                }
+                ktp.compile_kernel(&dest, &build).await?;
+                ktp.post_build(&dest, &build).await?;
+            }
+        }
+        Protocol::Manifest { action: ManifestAction::Check { dest } } => {
+            ktp.run_manifest(&dest, &build, false, !cli.skip_preflight).await?;
+        }
+        Protocol::Manifest { action: ManifestAction::Run { dest } } => {
+            ktp.run_manifest(&dest, &build, true, !cli.skip_preflight).await?;
+        }
+        Protocol::Build { action: BuildAction::Verify { source } } => {
+            ktp.verify_build(&source, &build).await?;
+        }
+        Protocol::Doctor { source } => {
+            ktp.doctor(source.as_deref(), &build).await?;
+        }
+        Protocol::Config { action: ConfigAction::Diff { a, b, format } } => {
+            let old = dotconfig::DotConfig::load(&dotconfig::config_path(&a))?;
+            let new = dotconfig::DotConfig::load(&dotconfig::config_path(&b))?;
+            let diff = dotconfig::ConfigDiff::between(&old, &new);
+            match format {
+                OutputFormat::Text => diff.print(),
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+            }
+        }
+        Protocol::Config { action: ConfigAction::Explain { symbol, source } } => {
+            let (tree, config) = load_kconfig(&source, &build)?;
+            tree.explain(&symbol, &config)?;
+        }
+        Protocol::Config { action: ConfigAction::Search { pattern, source } } => {
+            let (tree, config) = load_kconfig(&source, &build)?;
+            let matches = tree.search(&pattern);
+            for symbol in &matches {
+                tree.describe(symbol, &config);
             }
+            println!("{} symbol(s) match '{}'.", matches.len(), pattern);
         }
         Protocol::Git {
             source,
@@ -132,6 +569,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             branch,
             push,
         } => {
+            // The TLS settings are only loaded for commands that connect,
+            // so a broken [tls] section does not break offline commands.
+            let gitfetcher_instance = gitfetcher::GitFetcher::with_tls(ktp.config().tls.clone())?;
             // Eğer run_git_operations async değilse .await kaldır
 gitfetcher_instance
     .run_git_operations(
@@ -150,3 +590,54 @@ gitfetcher_instance
 
     Ok(())
 }
+
+/// Parses the Kconfig tree of `source` for the build's architecture, with the
+/// `.config` of the build (empty if it has not been configured yet).
+fn load_kconfig(source: &std::path::Path, build: &kbuild::BuildOptions) -> anyhow::Result<(kconfig::KconfigTree, dotconfig::DotConfig)> {
+    let tree = kconfig::KconfigTree::parse(source, &build.srcarch())?;
+    for missing in &tree.missing_sources {
+        println!("Warning: sourced Kconfig file {:?} not found", missing);
+    }
+
+    let config_path = build.output_dir(source).join(".config");
+    let config = if config_path.exists() {
+        dotconfig::DotConfig::load(&config_path)?
+    } else {
+        println!("Note: {:?} does not exist; all symbols are evaluated as unset.", config_path);
+        dotconfig::DotConfig::default()
+    };
+    Ok((tree, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edits(args: &[&str]) -> Vec<(String, dotconfig::ConfigValue)> {
+        let matches = Cli::command().try_get_matches_from(args).unwrap();
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        cli.build.order_config_edits(&matches);
+        cli.build.config_edits
+    }
+
+    #[test]
+    fn config_edits_keep_command_line_order() {
+        use dotconfig::ConfigValue::{NotSet, Yes};
+        assert_eq!(
+            edits(&["ktp", "--unset", "CONFIG_A", "--set", "CONFIG_A=y", "--unset", "B", "mktp", "--dest", "/k"]),
+            vec![("A".to_string(), NotSet), ("A".to_string(), Yes), ("B".to_string(), NotSet)]
+        );
+        assert_eq!(
+            edits(&["ktp", "mktp", "--set", "A=y", "--dest", "/k", "--unset", "A"]),
+            vec![("A".to_string(), Yes), ("A".to_string(), NotSet)]
+        );
+    }
+
+    #[test]
+    fn http_secrets_are_not_arguments() {
+        for flag in ["--password=x", "--token=x"] {
+            let args = ["ktp", "http", "--source=https://example.com/l.tar", "--dest=/d", flag];
+            assert!(Cli::command().try_get_matches_from(args).is_err());
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::path::Path;
+
+/// Options for recursively mirroring an HTTP directory index.
+#[derive(Debug, Clone)]
+pub struct MirrorOptions {
+    /// How many directory levels below the source URL are followed.
+    pub max_depth: usize,
+    /// Wildcard patterns a file must match to be downloaded (all files when empty).
+    pub include: Vec<String>,
+    /// Wildcard patterns for files and directories that are skipped.
+    pub exclude: Vec<String>,
+    /// Only follow links and redirects that stay on the source host.
+    pub same_host: bool,
+}
+
+impl Default for MirrorOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 10,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            same_host: true,
+        }
+    }
+}
+
+impl MirrorOptions {
+    pub fn wants_file(&self, rel_path: &Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| pattern_matches(p, rel_path)))
+            && !self.exclude.iter().any(|p| pattern_matches(p, rel_path))
+    }
+
+    pub fn wants_dir(&self, rel_path: &Path) -> bool {
+        !self.exclude.iter().any(|p| pattern_matches(p, rel_path))
+    }
+}
+
+/// Patterns containing a `/` are matched against the whole relative path,
+/// others against the file name only (`*.patch.xz`, `incr/*`).
+fn pattern_matches(pattern: &str, rel_path: &Path) -> bool {
+    let path = rel_path.to_string_lossy().replace('\\', "/");
+    let subject = if pattern.contains('/') {
+        path.as_str()
+    } else {
+        path.rsplit('/').next().unwrap_or_default()
+    };
+    wildcard_match(pattern.as_bytes(), subject.as_bytes())
+}
+
+/// Matches `*` (any run of characters) and `?` (one character).
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        wildcard_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn wildcards_match_runs_and_single_characters() {
+        assert!(matches("*.patch.xz", "patch-6.9.1.patch.xz"));
+        assert!(!matches("*.patch.xz", "patch-6.9.1.patch.xz.sign"));
+        assert!(matches("patch-6.?.?.xz", "patch-6.9.1.xz"));
+        assert!(!matches("patch-6.?.xz", "patch-6.10.xz"));
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(matches("**", ""));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+        assert!(!matches("?", ""));
+        assert!(matches("a*b", "aaab"));
+        assert!(!matches("a*b", "aaabc"));
+    }
+
+    #[test]
+    fn patterns_with_a_slash_match_the_whole_path() {
+        let options = MirrorOptions {
+            include: vec!["*.xz".to_string(), "incr/*".to_string()],
+            exclude: vec!["old*".to_string()],
+            ..MirrorOptions::default()
+        };
+        assert!(options.wants_file(Path::new("v6/patch-6.9.xz")));
+        assert!(options.wants_file(Path::new("incr/patch-6.9.1-2")));
+        assert!(!options.wants_file(Path::new("v6/incr/patch-6.9.1-2")));
+        assert!(!options.wants_file(Path::new("v6/old-6.1.xz")));
+        assert!(!options.wants_dir(Path::new("v6/old")));
+        assert!(options.wants_dir(Path::new("v6/incr")));
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+
+/// Compression applied to installed modules that Kbuild left uncompressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleCompression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl ModuleCompression {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ModuleCompression::Gzip => "gz",
+            ModuleCompression::Xz => "xz",
+            ModuleCompression::Zstd => "zst",
+        }
+    }
+}
+
+pub fn parse_compression(s: &str) -> Result<ModuleCompression, String> {
+    match s {
+        "gzip" | "gz" => Ok(ModuleCompression::Gzip),
+        "xz" => Ok(ModuleCompression::Xz),
+        "zstd" | "zst" => Ok(ModuleCompression::Zstd),
+        other => Err(format!("unknown module compression '{}' (expected gzip, xz or zstd)", other)),
+    }
+}
+
+/// `lib/modules/<release>` (or `usr/lib/modules/<release>` on merged-/usr
+/// layouts) inside a staging root.
+pub fn modules_dir(staging: &Path, release: &str) -> Option<PathBuf> {
+    ["lib/modules", "usr/lib/modules"]
+        .iter()
+        .map(|base| staging.join(base).join(release))
+        .find(|dir| dir.is_dir())
+}
+
+/// Compresses every plain `.ko` below `dir`, replacing it. Returns how many
+/// modules were compressed.
+pub fn compress_modules(dir: &Path, compression: ModuleCompression) -> Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            count += compress_modules(&path, compression)?;
+        } else if file_type.is_file() && path.extension().is_some_and(|e| e == "ko") {
+            compress_file(&path, compression)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn compress_file(path: &Path, compression: ModuleCompression) -> Result<()> {
+    let target = PathBuf::from(format!("{}.{}", path.display(), compression.extension()));
+    let mut input = fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let output = fs::File::create(&target).with_context(|| format!("Failed to create {:?}", target))?;
+    match compression {
+        ModuleCompression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::best());
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+        ModuleCompression::Xz => {
+            // The in-kernel xz decompressor only understands CRC32 checks.
+            let stream = xz2::stream::Stream::new_easy_encoder(6, xz2::stream::Check::Crc32)?;
+            let mut encoder = xz2::write::XzEncoder::new_stream(output, stream);
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+        ModuleCompression::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(output, 19)?;
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Packs everything below `staging` into a zstd-compressed tarball, keeping
+/// symlinks (`build`, `source`) as links.
+pub fn create_bundle(staging: &Path, target: &Path) -> Result<()> {
+    let file = fs::File::create(target).with_context(|| format!("Failed to create {:?}", target))?;
+    let encoder = zstd::stream::write::Encoder::new(file, 19)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(false);
+    for entry in fs::read_dir(staging)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.file_type()?.is_dir() {
+            builder.append_dir_all(&name, entry.path())?;
+        } else {
+            builder.append_path_with_name(entry.path(), &name)?;
+        }
+    }
+    builder.into_inner()?.finish()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn compress_modules_only_touches_plain_ko_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("kernel/drivers")).unwrap();
+        fs::write(dir.join("kernel/a.ko"), "module a").unwrap();
+        fs::write(dir.join("kernel/drivers/b.ko"), "module b").unwrap();
+        fs::write(dir.join("kernel/c.ko.xz"), "already compressed").unwrap();
+        fs::write(dir.join("modules.dep"), "kernel/a.ko:\n").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.join("kernel/a.ko"), dir.join("link.ko")).unwrap();
+
+        assert_eq!(compress_modules(dir, ModuleCompression::Gzip).unwrap(), 2);
+        assert!(!dir.join("kernel/a.ko").exists());
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(dir.join("kernel/drivers/b.ko.gz")).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "module b");
+        assert_eq!(fs::read_to_string(dir.join("kernel/c.ko.xz")).unwrap(), "already compressed");
+        assert_eq!(fs::read_to_string(dir.join("modules.dep")).unwrap(), "kernel/a.ko:\n");
+        assert!(!dir.join("kernel/c.ko.xz.gz").exists() && !dir.join("modules.dep.gz").exists());
+    }
+}
@@ -0,0 +1,246 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::config::PackageConfig;
+
+/// Directory below the build directory where sandboxed `bindeb-pkg` builds,
+/// so that its packages land inside the build directory.
+pub const DEB_STAGING: &str = "ktp-deb";
+
+/// Distribution package formats, built through Kbuild's own packaging targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageFormat {
+    Deb,
+    Rpm,
+}
+
+impl PackageFormat {
+    /// The Kbuild target that produces binary packages.
+    pub fn target(self) -> &'static str {
+        match self {
+            PackageFormat::Deb => "bindeb-pkg",
+            PackageFormat::Rpm => "binrpm-pkg",
+        }
+    }
+
+    /// The host tool the target drives, and the package that provides it.
+    pub fn builder(self) -> (&'static str, &'static str) {
+        match self {
+            PackageFormat::Deb => ("dpkg-buildpackage", "dpkg-dev"),
+            PackageFormat::Rpm => ("rpmbuild", "rpm-build"),
+        }
+    }
+}
+
+pub fn parse_format(s: &str) -> Result<PackageFormat, String> {
+    match s {
+        "deb" => Ok(PackageFormat::Deb),
+        "rpm" => Ok(PackageFormat::Rpm),
+        other => Err(format!("unknown package format '{}' (expected deb or rpm)", other)),
+    }
+}
+
+/// Splits `Name <email>` into its parts.
+pub fn split_maintainer(maintainer: &str) -> (String, Option<String>) {
+    match maintainer.split_once('<') {
+        Some((name, rest)) => (name.trim().to_string(), Some(rest.trim_end_matches('>').trim().to_string())),
+        None => (maintainer.trim().to_string(), None),
+    }
+}
+
+/// Make variables and environment for one packaging target.
+#[derive(Debug, Default)]
+pub struct MakeSettings {
+    pub vars: Vec<(String, String)>,
+    pub env: Vec<(String, String)>,
+}
+
+/// Settings for packaging `release` as `format`. `macros` is where the RPM
+/// macro file carrying the packager goes.
+pub fn make_settings(format: PackageFormat, release: &str, package: &PackageConfig, macros: &Path) -> Result<MakeSettings> {
+    let mut settings = MakeSettings::default();
+    let (vars, env) = (&mut settings.vars, &mut settings.env);
+    match format {
+        PackageFormat::Deb => {
+            if let Some(revision) = &package.revision {
+                vars.push(("KDEB_PKGVERSION".to_string(), format!("{}-{}", release, revision)));
+            }
+            if let Some(dist) = &package.distribution {
+                vars.push(("KDEB_CHANGELOG_DIST".to_string(), dist.clone()));
+            }
+            if let Some(name) = &package.source_name {
+                vars.push(("KDEB_SOURCENAME".to_string(), name.clone()));
+            }
+            if let Some(maintainer) = &package.maintainer {
+                let (name, email) = split_maintainer(maintainer);
+                env.push(("DEBFULLNAME".to_string(), name));
+                if let Some(email) = email {
+                    env.push(("DEBEMAIL".to_string(), email));
+                }
+            }
+        }
+        PackageFormat::Rpm => {
+            // kernel.spec takes its Release from the build version.
+            if let Some(revision) = &package.revision {
+                if !revision.chars().all(|c| c.is_ascii_digit()) {
+                    anyhow::bail!("RPM package revision must be a number, got '{}'", revision);
+                }
+                vars.push(("KBUILD_BUILD_VERSION".to_string(), revision.clone()));
+            }
+            if let Some(maintainer) = &package.maintainer {
+                fs::write(macros, format!("%packager {}\n", maintainer))
+                    .with_context(|| format!("Failed to write {:?}", macros))?;
+                vars.push(("RPMOPTS".to_string(), format!("--load={}", macros.display())));
+            }
+        }
+    }
+    Ok(settings)
+}
+
+/// Moves the packages `format` produced for `release` since `since` into
+/// `dest`. Kbuild writes `.deb` files next to the output directory and RPMs
+/// below `rpmbuild/RPMS` in it (or in `~/rpmbuild` on older kernels); other
+/// packages lying there are left alone.
+pub fn collect_packages(
+    format: PackageFormat,
+    output_dir: &Path,
+    release: &str,
+    since: SystemTime,
+    dest: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    match format {
+        PackageFormat::Deb => {
+            if let Some(parent) = output_dir.parent() {
+                let mut files = Vec::new();
+                for entry in fs::read_dir(parent)? {
+                    let path = entry?.path();
+                    if is_newer(&path, since) {
+                        files.push(path);
+                    }
+                }
+                found = debs_for_release(files, release);
+            }
+        }
+        PackageFormat::Rpm => {
+            let mut roots = vec![output_dir.join("rpmbuild/RPMS")];
+            if let Some(home) = std::env::var_os("HOME") {
+                roots.push(PathBuf::from(home).join("rpmbuild/RPMS"));
+            }
+            for root in roots {
+                find_rpms(&root, release, since, &mut found)?;
+            }
+        }
+    }
+    found.sort();
+
+    fs::create_dir_all(dest).with_context(|| format!("Failed to create {:?}", dest))?;
+    let mut moved = Vec::new();
+    for path in found {
+        let target = dest.join(path.file_name().unwrap_or_default());
+        if fs::rename(&path, &target).is_err() {
+            fs::copy(&path, &target).with_context(|| format!("Failed to copy {:?} to {:?}", path, target))?;
+            fs::remove_file(&path)?;
+        }
+        moved.push(target);
+    }
+    Ok(moved)
+}
+
+/// Splits a Debian file name, `<package>_<version>_<arch>.<ext>`.
+fn deb_name(path: &Path) -> Option<(&str, &str, &str)> {
+    let name = path.file_name()?.to_str()?;
+    let (stem, ext) = name.rsplit_once('.')?;
+    let mut parts = stem.splitn(3, '_');
+    Some((parts.next()?, parts.next()?, ext)).filter(|_| parts.next().is_some())
+}
+
+/// The `.deb`, `.buildinfo` and `.changes` files of `release`: the image,
+/// headers and dbg packages carry the release in their name, and the other
+/// files of the same run (`linux-libc-dev`, the changes) share their version.
+fn debs_for_release(files: Vec<PathBuf>, release: &str) -> Vec<PathBuf> {
+    let (own, own_dbg) = (format!("-{}", release), format!("-{}-dbg", release));
+    let versions: Vec<&str> = files
+        .iter()
+        .filter_map(|path| deb_name(path))
+        .filter(|(package, _, ext)| *ext == "deb" && (package.ends_with(&own) || package.ends_with(&own_dbg)))
+        .map(|(_, version, _)| version)
+        .collect();
+    files
+        .iter()
+        .filter(|path| {
+            deb_name(path).is_some_and(|(_, version, ext)| {
+                matches!(ext, "deb" | "buildinfo" | "changes") && versions.contains(&version)
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+/// RPMs of `release`: `kernel-<release>-<n>.<arch>.rpm` and its
+/// `kernel-headers`/`kernel-devel` siblings. RPM versions cannot contain
+/// `-`, so kernel.spec turns those in the release into `_`.
+fn is_release_rpm(path: &Path, release: &str) -> bool {
+    let version = format!("-{}-", release.replace('-', "_"));
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|name| name.starts_with("kernel") && name.ends_with(".rpm") && name.contains(&version))
+}
+
+fn find_rpms(dir: &Path, release: &str, since: SystemTime, found: &mut Vec<PathBuf>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_rpms(&path, release, since, found)?;
+        } else if is_release_rpm(&path, release) && is_newer(&path, since) {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_newer(path: &Path, since: SystemTime) -> bool {
+    fs::metadata(path).and_then(|m| m.modified()).is_ok_and(|modified| modified >= since)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debs_are_matched_on_the_release() {
+        let files: Vec<PathBuf> = [
+            "linux-image-6.6.0-acme_6.6.0-acme-1_amd64.deb",
+            "linux-image-6.6.0-acme-dbg_6.6.0-acme-1_amd64.deb",
+            "linux-headers-6.6.0-acme_6.6.0-acme-1_amd64.deb",
+            "linux-libc-dev_6.6.0-acme-1_amd64.deb",
+            "linux-upstream_6.6.0-acme-1_amd64.changes",
+            "linux-upstream_6.6.0-acme-1_amd64.buildinfo",
+            "linux-image-6.6.0-other_6.6.0-other-1_amd64.deb",
+            "linux-libc-dev_6.6.0-other-1_amd64.deb",
+            "hello_2.10-3_amd64.deb",
+            "notes.txt",
+        ]
+        .iter()
+        .map(|name| PathBuf::from("/b").join(name))
+        .collect();
+        let found = debs_for_release(files.clone(), "6.6.0-acme");
+        assert_eq!(found, files[..6]);
+        assert!(debs_for_release(files, "6.6.0").is_empty());
+    }
+
+    #[test]
+    fn rpms_are_matched_on_the_release() {
+        assert!(is_release_rpm(Path::new("kernel-6.6.0_acme-1.x86_64.rpm"), "6.6.0-acme"));
+        assert!(is_release_rpm(Path::new("kernel-headers-6.6.0_acme-1.x86_64.rpm"), "6.6.0-acme"));
+        assert!(!is_release_rpm(Path::new("kernel-6.6.0-1.x86_64.rpm"), "6.6.0-acme"));
+        assert!(!is_release_rpm(Path::new("other-6.6.0_acme-1.x86_64.rpm"), "6.6.0-acme"));
+    }
+}
@@ -0,0 +1,270 @@
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+
+const MIB: u64 = 1024 * 1024;
+const GIB: u64 = 1024 * MIB;
+
+/// Rough inode usage of an unpacked kernel tree and of a build on top of it.
+const SOURCE_INODES: u64 = 100_000;
+const BUILD_INODES: u64 = 60_000;
+
+/// Expected disk usage of a transfer. Unknown parts are `None`.
+#[derive(Debug, Default, Clone)]
+pub struct SpaceEstimate {
+    pub archive: Option<u64>,
+    pub extracted: Option<u64>,
+    pub build: Option<u64>,
+}
+
+impl SpaceEstimate {
+    pub fn total(&self) -> u64 {
+        self.archive.unwrap_or(0) + self.extracted.unwrap_or(0) + self.build.unwrap_or(0)
+    }
+
+    pub fn inodes(&self) -> u64 {
+        let mut inodes = 0;
+        if self.extracted.is_some() {
+            inodes += SOURCE_INODES;
+        }
+        if self.build.is_some() {
+            inodes += BUILD_INODES;
+        }
+        inodes
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PreflightReport {
+    /// Problems that make the transfer or build fail; these abort the run.
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl PreflightReport {
+    pub fn print(&self) {
+        for warning in &self.warnings {
+            println!("WARNING: {}", warning);
+        }
+        for error in &self.errors {
+            println!("ERROR: {}", error);
+        }
+    }
+}
+
+/// Guesses the unpacked size of a tarball from its compressed size, using
+/// the ratios observed on kernel.org source tarballs.
+pub fn estimate_extracted_size(file_name: &str, archive_size: u64) -> u64 {
+    let ratio = if file_name.ends_with(".xz") || file_name.ends_with(".txz") {
+        10.0
+    } else if file_name.ends_with(".zst") || file_name.ends_with(".tzst") {
+        8.0
+    } else if file_name.ends_with(".gz") || file_name.ends_with(".tgz") {
+        6.0
+    } else {
+        1.0
+    };
+    (archive_size as f64 * ratio) as u64
+}
+
+/// Estimates the space taken by build output. With a `.config` at hand the
+/// estimate scales with the number of modules and with debug info, which
+/// dominates object sizes; without one a typical distribution-like build is
+/// assumed.
+pub fn estimate_build_size(config: Option<&Path>) -> u64 {
+    let Some(text) = config.and_then(|path| std::fs::read_to_string(path).ok()) else {
+        return 12 * GIB;
+    };
+
+    let modules = text.lines().filter(|line| line.ends_with("=m")).count() as u64;
+    let debug_info = text.lines().any(|line| {
+        line.starts_with("CONFIG_DEBUG_INFO") && line.ends_with("=y") && !line.starts_with("CONFIG_DEBUG_INFO_NONE")
+    });
+
+    let size = 2 * GIB + modules * 6 * MIB;
+    if debug_info { size * 4 } else { size }
+}
+
+/// Checks that `dest` can hold `estimate` and is usable for a kernel build.
+pub fn check(dest: &Path, estimate: &SpaceEstimate, building: bool) -> Result<PreflightReport> {
+    let mut report = PreflightReport::default();
+    let existing = nearest_existing(dest);
+
+    if let Some(stats) = filesystem_stats(&existing)? {
+        let required = estimate.total();
+        if required > 0 {
+            let parts: Vec<String> = [("archive", estimate.archive), ("sources", estimate.extracted), ("build", estimate.build)]
+                .iter()
+                .filter_map(|(name, size)| size.map(|size| format!("{} {}", name, human_size(size))))
+                .collect();
+            println!(
+                "Preflight: {} free on {:?}, about {} needed ({})",
+                human_size(stats.free_bytes),
+                existing,
+                human_size(required),
+                parts.join(", ")
+            );
+            if required > stats.free_bytes {
+                report.errors.push(format!(
+                    "Not enough free space on {:?}: {} needed, {} available",
+                    existing,
+                    human_size(required),
+                    human_size(stats.free_bytes)
+                ));
+            } else if required + required / 10 > stats.free_bytes {
+                report.warnings.push(format!(
+                    "Free space on {:?} is within 10% of the estimated need ({} of {})",
+                    existing,
+                    human_size(stats.free_bytes),
+                    human_size(required)
+                ));
+            }
+        }
+
+        // Filesystems with dynamic inode allocation report no inode totals.
+        if stats.total_inodes > 0 && estimate.inodes() > stats.free_inodes {
+            report.errors.push(format!(
+                "Not enough free inodes on {:?}: about {} needed, {} available",
+                existing,
+                estimate.inodes(),
+                stats.free_inodes
+            ));
+        }
+        if stats.read_only {
+            report.errors.push(format!("{:?} is on a read-only filesystem", existing));
+        }
+    }
+
+    std::fs::create_dir_all(dest).with_context(|| format!("Failed to create {:?}", dest))?;
+    if is_case_insensitive(dest)? {
+        report.errors.push(format!(
+            "{:?} is on a case-insensitive filesystem; kernel sources contain files that differ only in case",
+            dest
+        ));
+    }
+    // Only builds run anything from the destination.
+    if building && is_noexec(dest)? {
+        report.errors.push(format!(
+            "{:?} is on a filesystem mounted noexec; the build runs scripts from the source tree",
+            dest
+        ));
+    }
+
+    Ok(report)
+}
+
+pub fn human_size(bytes: u64) -> String {
+    if bytes >= GIB {
+        format!("{:.1} GiB", bytes as f64 / GIB as f64)
+    } else if bytes >= MIB {
+        format!("{:.1} MiB", bytes as f64 / MIB as f64)
+    } else {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    }
+}
+
+fn nearest_existing(path: &Path) -> PathBuf {
+    let mut current = path;
+    loop {
+        if current.exists() {
+            return current.to_path_buf();
+        }
+        match current.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => current = parent,
+            _ => return PathBuf::from("."),
+        }
+    }
+}
+
+struct FsStats {
+    free_bytes: u64,
+    total_inodes: u64,
+    free_inodes: u64,
+    read_only: bool,
+}
+
+#[cfg(unix)]
+fn filesystem_stats(path: &Path) -> Result<Option<FsStats>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stat` is a valid out pointer.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error()).with_context(|| format!("statvfs failed for {:?}", path));
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(FsStats {
+        free_bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        total_inodes: stat.f_files as u64,
+        free_inodes: stat.f_favail as u64,
+        read_only: stat.f_flag & libc::ST_RDONLY != 0,
+    }))
+}
+
+#[cfg(not(unix))]
+fn filesystem_stats(_path: &Path) -> Result<Option<FsStats>> {
+    Ok(None)
+}
+
+fn is_case_insensitive(dir: &Path) -> Result<bool> {
+    let probe = dir.join(format!(".ktp-case-probe-{}", std::process::id()));
+    std::fs::write(&probe, b"")?;
+    let upper = dir.join(format!(".KTP-CASE-PROBE-{}", std::process::id()));
+    let insensitive = upper.exists();
+    std::fs::remove_file(&probe)?;
+    Ok(insensitive)
+}
+
+#[cfg(unix)]
+fn is_noexec(dir: &Path) -> Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let probe = dir.join(format!(".ktp-exec-probe-{}", std::process::id()));
+    std::fs::write(&probe, b"#!/bin/sh\nexit 0\n")?;
+    std::fs::set_permissions(&probe, std::fs::Permissions::from_mode(0o755))?;
+    let result = std::process::Command::new(&probe).status();
+    std::fs::remove_file(&probe)?;
+    Ok(matches!(result, Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied))
+}
+
+#[cfg(not(unix))]
+fn is_noexec(_dir: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracted_size_follows_the_compression() {
+        assert_eq!(estimate_extracted_size("linux-6.9.tar.xz", 100), 1000);
+        assert_eq!(estimate_extracted_size("linux-6.9.tzst", 100), 800);
+        assert_eq!(estimate_extracted_size("linux-6.9.tar.gz", 100), 600);
+        assert_eq!(estimate_extracted_size("linux-6.9.tar", 100), 100);
+    }
+
+    #[test]
+    fn build_size_scales_with_modules_and_debug_info() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join(".config");
+        assert_eq!(estimate_build_size(None), 12 * GIB);
+        assert_eq!(estimate_build_size(Some(&dir.path().join("missing"))), 12 * GIB);
+
+        std::fs::write(&config, "CONFIG_A=m\nCONFIG_B=m\nCONFIG_C=y\n").unwrap();
+        assert_eq!(estimate_build_size(Some(&config)), 2 * GIB + 12 * MIB);
+
+        std::fs::write(&config, "CONFIG_A=m\nCONFIG_DEBUG_INFO_NONE=y\n").unwrap();
+        assert_eq!(estimate_build_size(Some(&config)), 2 * GIB + 6 * MIB);
+
+        std::fs::write(&config, "CONFIG_A=m\nCONFIG_DEBUG_INFO_DWARF5=y\n").unwrap();
+        assert_eq!(estimate_build_size(Some(&config)), (2 * GIB + 6 * MIB) * 4);
+    }
+
+    #[test]
+    fn human_size_picks_the_unit() {
+        assert_eq!(human_size(512), "0.5 KiB");
+        assert_eq!(human_size(3 * MIB / 2), "1.5 MiB");
+        assert_eq!(human_size(12 * GIB), "12.0 GiB");
+    }
+}
@@ -0,0 +1,206 @@
+use std::path::PathBuf;
+
+/// Temporary directory inside the build directory that sandboxed commands
+/// get as `TMPDIR`, since `/tmp` is read-only for them.
+pub const SANDBOX_TMP: &str = "ktp-tmp";
+
+/// Confinement of make stages: the whole filesystem is read-only except the
+/// build directory, the artifact directory and `writable`, the source tree
+/// stays read-only even inside those, and there is no network. Only
+/// unprivileged user, mount and network namespaces are used, so this is
+/// Linux only.
+#[derive(Debug, Clone, Default)]
+pub struct SandboxOptions {
+    /// Directories writable besides the build and artifact directories.
+    pub writable: Vec<PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::{confine, probe};
+#[cfg(not(target_os = "linux"))]
+pub use unsupported::{confine, probe};
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use anyhow::Result;
+    use tokio::process::Command as TokioCommand;
+
+    // Not exported by the libc crate yet; from <linux/mount.h> and <fcntl.h>.
+    const MOUNT_ATTR_RDONLY: u64 = 0x1;
+    const AT_RECURSIVE: libc::c_uint = 0x8000;
+
+    #[repr(C)]
+    struct MountAttr {
+        attr_set: u64,
+        attr_clr: u64,
+        propagation: u64,
+        userns_fd: u64,
+    }
+
+    /// Makes `cmd` enter a fresh user, mount and network namespace before it
+    /// executes. Inside, the invoking user keeps its own uid and gid, every
+    /// mount is read-only and `writable` (absolute, existing directories) are
+    /// bind-mounted writable again, except for `readonly` ones below them.
+    /// Writable directories below a read-only one, such as a build directory
+    /// inside the source tree, are writable again after that. The network
+    /// namespace has only a loopback device, and it is down.
+    pub fn confine(cmd: &mut TokioCommand, writable: &[PathBuf], readonly: &[PathBuf]) {
+        // Everything is allocated here; the hook runs between fork and exec,
+        // where only async-signal-safe calls are allowed.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let uid_map = format!("{} {} 1", uid, uid).into_bytes();
+        let gid_map = format!("{} {} 1", gid, gid).into_bytes();
+        let binds: Vec<(CString, bool)> = bind_mounts(writable, readonly)
+            .into_iter()
+            .filter_map(|(dir, readonly)| Some((CString::new(dir.as_os_str().as_bytes()).ok()?, readonly)))
+            .collect();
+
+        let setup = move || -> io::Result<()> {
+            unsafe {
+                check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET))?;
+                write_proc(c"/proc/self/setgroups", b"deny")?;
+                write_proc(c"/proc/self/uid_map", &uid_map)?;
+                write_proc(c"/proc/self/gid_map", &gid_map)?;
+
+                // Keep the mount changes below from propagating back to the host.
+                let root = c"/";
+                check(libc::mount(
+                    std::ptr::null(),
+                    root.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                ))?;
+                set_readonly(root, true)?;
+                for (dir, readonly) in &binds {
+                    check(libc::mount(
+                        dir.as_ptr(),
+                        dir.as_ptr(),
+                        std::ptr::null(),
+                        libc::MS_BIND | libc::MS_REC,
+                        std::ptr::null(),
+                    ))?;
+                    set_readonly(dir, *readonly)?;
+                }
+            }
+            Ok(())
+        };
+        unsafe {
+            cmd.pre_exec(setup);
+        }
+    }
+
+    /// The bind mounts `confine` makes over the read-only root, in order,
+    /// with whether each is read-only: `writable`, then `readonly`, then the
+    /// writable directories that a read-only one covered again.
+    pub(super) fn bind_mounts(writable: &[PathBuf], readonly: &[PathBuf]) -> Vec<(PathBuf, bool)> {
+        let nested = writable.iter().filter(|dir| readonly.iter().any(|ro| dir.starts_with(ro)));
+        writable
+            .iter()
+            .map(|dir| (dir.clone(), false))
+            .chain(readonly.iter().map(|dir| (dir.clone(), true)))
+            .chain(nested.map(|dir| (dir.clone(), false)))
+            .collect()
+    }
+
+    /// Checks that this host lets unprivileged processes create the namespaces
+    /// `confine` needs, by running `true` confined.
+    pub async fn probe(writable: &[PathBuf], readonly: &[PathBuf]) -> Result<()> {
+        let mut cmd = TokioCommand::new("true");
+        confine(&mut cmd, writable, readonly);
+        match cmd.status().await {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => anyhow::bail!("Sandbox test command failed ({})", status),
+            Err(e) => anyhow::bail!(
+                "Cannot set up the build sandbox: {}. Unprivileged user namespaces may be disabled \
+                 (sysctls user.max_user_namespaces, kernel.unprivileged_userns_clone, \
+                 kernel.apparmor_restrict_unprivileged_userns), or the kernel is older than 5.12.",
+                e
+            ),
+        }
+    }
+
+    fn check(ret: libc::c_int) -> io::Result<()> {
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    unsafe fn write_proc(path: &CStr, data: &[u8]) -> io::Result<()> {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        check(fd)?;
+        let written = libc::write(fd, data.as_ptr().cast(), data.len());
+        libc::close(fd);
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Sets or clears the read-only flag on the mount at `path` and every mount
+    /// below it. `MS_REMOUNT` would only change the topmost one.
+    unsafe fn set_readonly(path: &CStr, readonly: bool) -> io::Result<()> {
+        let attr = MountAttr {
+            attr_set: if readonly { MOUNT_ATTR_RDONLY } else { 0 },
+            attr_clr: if readonly { 0 } else { MOUNT_ATTR_RDONLY },
+            propagation: 0,
+            userns_fd: 0,
+        };
+        let ret = libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            AT_RECURSIVE,
+            &attr as *const MountAttr,
+            std::mem::size_of::<MountAttr>(),
+        );
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod unsupported {
+    use std::io;
+    use std::path::PathBuf;
+    use anyhow::Result;
+    use tokio::process::Command as TokioCommand;
+
+    /// Makes `cmd` fail to start: without namespaces it cannot be confined,
+    /// and it must not run unconfined.
+    pub fn confine(cmd: &mut TokioCommand, _writable: &[PathBuf], _readonly: &[PathBuf]) {
+        unsafe {
+            cmd.pre_exec(|| Err(io::Error::new(io::ErrorKind::Unsupported, "the build sandbox needs Linux")));
+        }
+    }
+
+    pub async fn probe(_writable: &[PathBuf], _readonly: &[PathBuf]) -> Result<()> {
+        anyhow::bail!("--sandbox is only supported on Linux")
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn paths(dirs: &[&str]) -> Vec<PathBuf> {
+        dirs.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn build_dirs_inside_the_source_tree_stay_writable() {
+        let binds = linux::bind_mounts(&paths(&["/src/linux/out", "/cache"]), &paths(&["/src/linux"]));
+        let binds: Vec<(&str, bool)> = binds.iter().map(|(dir, ro)| (dir.to_str().unwrap(), *ro)).collect();
+        assert_eq!(binds, [("/src/linux/out", false), ("/cache", false), ("/src/linux", true), ("/src/linux/out", false)]);
+
+        let binds = linux::bind_mounts(&paths(&["/build"]), &paths(&["/src/linux"]));
+        assert_eq!(binds, [(PathBuf::from("/build"), false), (PathBuf::from("/src/linux"), true)]);
+    }
+}
@@ -0,0 +1,217 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use git2::CertificateCheckStatus;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+
+use crate::config::TlsConfig;
+
+/// Applies the TLS config to a reqwest client builder. With no custom
+/// settings the builder is returned untouched; otherwise a rustls config
+/// with the system roots, the extra CAs, the client identity and the pin
+/// check replaces reqwest's own.
+pub fn configure_http(builder: reqwest::ClientBuilder, tls: &TlsConfig) -> Result<reqwest::ClientBuilder> {
+    if tls.is_default() {
+        return Ok(builder);
+    }
+    Ok(builder.use_preconfigured_tls(rustls_config(tls)?))
+}
+
+/// Applies the TLS config to libgit2. CA certificates are a process-wide
+/// libgit2 setting; pins are checked through [`git_certificate_check`] and
+/// client certificates refused by [`check_git_remote`].
+pub fn configure_libgit2(tls: &TlsConfig) -> Result<()> {
+    for path in &tls.ca_certs {
+        // SAFETY: called before any libgit2 network operation is started.
+        unsafe { git2::opts::set_ssl_cert_file(path) }
+            .with_context(|| format!("Failed to load CA certificate {:?} into libgit2", path))?;
+    }
+    Ok(())
+}
+
+/// libgit2 cannot present a TLS client certificate, so a Git HTTPS remote
+/// is refused when one is configured instead of connecting without it.
+pub fn check_git_remote(tls: &TlsConfig, url: &str) -> Result<()> {
+    if tls.client_cert.is_some() && url.get(..8).is_some_and(|s| s.eq_ignore_ascii_case("https://")) {
+        anyhow::bail!(
+            "{} needs the configured TLS client certificate, which libgit2 cannot present; use an SSH remote or remove [tls] client_cert",
+            url
+        );
+    }
+    Ok(())
+}
+
+/// `certificate_check` callback for libgit2 remotes that enforces the
+/// configured pins on top of libgit2's own validation.
+pub fn git_certificate_check(
+    pins: &[String],
+) -> impl FnMut(&git2::cert::Cert<'_>, &str) -> Result<CertificateCheckStatus, git2::Error> + '_ {
+    move |cert, host| {
+        if let Some(x509) = cert.as_x509() {
+            check_pin(pins, x509.data())
+                .map_err(|e| git2::Error::from_str(&format!("{}: {}", host, e)))?;
+        }
+        Ok(CertificateCheckStatus::CertificatePassthrough)
+    }
+}
+
+/// Computes the curl-style `sha256//<base64>` pin of a DER certificate's public key.
+pub fn spki_pin(der: &[u8]) -> Result<String> {
+    let cert = X509::from_der(der)?;
+    let spki = cert.public_key()?.public_key_to_der()?;
+    Ok(format!("sha256//{}", openssl::base64::encode_block(&openssl::sha::sha256(&spki))))
+}
+
+fn check_pin(pins: &[String], der: &[u8]) -> Result<()> {
+    if pins.is_empty() {
+        return Ok(());
+    }
+    let pin = spki_pin(der)?;
+    if !pins.iter().any(|p| p == &pin) {
+        anyhow::bail!("server certificate does not match any configured pin (got {})", pin);
+    }
+    Ok(())
+}
+
+fn rustls_config(tls: &TlsConfig) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs().context("Failed to load system CA certificates")?;
+    roots.add_parsable_certificates(&native.into_iter().map(|cert| cert.0).collect::<Vec<_>>());
+    for path in &tls.ca_certs {
+        let data = std::fs::read(path).with_context(|| format!("Failed to read CA certificate {:?}", path))?;
+        for cert in X509::stack_from_pem(&data).with_context(|| format!("Invalid PEM in {:?}", path))? {
+            roots
+                .add(&Certificate(cert.to_der()?))
+                .with_context(|| format!("Unusable CA certificate in {:?}", path))?;
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pins: tls.pins.clone(),
+        }));
+
+    let mut config = match client_identity(tls)? {
+        Some((chain, key)) => {
+            let chain = chain
+                .iter()
+                .map(|cert| cert.to_der().map(Certificate))
+                .collect::<Result<Vec<_>, _>>()?;
+            builder
+                .with_client_auth_cert(chain, PrivateKey(key.private_key_to_pkcs8()?))
+                .context("Invalid client certificate or key")?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Loads the client certificate chain and key from a PKCS#12 bundle or
+/// from PEM files (the key may live in the certificate file).
+fn client_identity(tls: &TlsConfig) -> Result<Option<(Vec<X509>, PKey<Private>)>> {
+    let Some(cert_path) = &tls.client_cert else {
+        return Ok(None);
+    };
+    let data = std::fs::read(cert_path)
+        .with_context(|| format!("Failed to read client certificate {:?}", cert_path))?;
+    let password = tls.client_cert_password();
+
+    if is_pkcs12(cert_path) {
+        let parsed = Pkcs12::from_der(&data)?
+            .parse2(password.as_deref().unwrap_or(""))
+            .with_context(|| format!("Failed to decrypt PKCS#12 bundle {:?}", cert_path))?;
+        let cert = parsed.cert.context("PKCS#12 bundle has no certificate")?;
+        let key = parsed.pkey.context("PKCS#12 bundle has no private key")?;
+        let mut chain = vec![cert];
+        chain.extend(parsed.ca.into_iter().flatten());
+        return Ok(Some((chain, key)));
+    }
+
+    let chain = X509::stack_from_pem(&data)
+        .ok()
+        .filter(|chain| !chain.is_empty())
+        .with_context(|| {
+            format!(
+                "{:?} is not a PEM certificate; PKCS#12 bundles need a .p12 or .pfx extension and DER certificates must be converted to PEM",
+                cert_path
+            )
+        })?;
+    let key_data = match &tls.client_key {
+        Some(path) => std::fs::read(path).with_context(|| format!("Failed to read client key {:?}", path))?,
+        None => data,
+    };
+    let key = match password {
+        Some(password) => PKey::private_key_from_pem_passphrase(&key_data, password.as_bytes()),
+        None => PKey::private_key_from_pem(&key_data),
+    }
+    .context("Failed to load client private key")?;
+    Ok(Some((chain, key)))
+}
+
+/// PKCS#12 bundles are recognised by their `.p12`/`.pfx` extension; every
+/// other file is read as PEM.
+fn is_pkcs12(path: &Path) -> bool {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    ext.eq_ignore_ascii_case("p12") || ext.eq_ignore_ascii_case("pfx")
+}
+
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        check_pin(&self.pins, &end_entity.0).map_err(|e| rustls::Error::General(e.to_string()))?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn git_https_remotes_are_refused_with_a_client_certificate() {
+        let mut tls = TlsConfig::default();
+        assert!(check_git_remote(&tls, "https://git.example.org/linux.git").is_ok());
+        tls.client_cert = Some("/etc/ktp/client.p12".into());
+        assert!(check_git_remote(&tls, "https://git.example.org/linux.git").is_err());
+        assert!(check_git_remote(&tls, "HTTPS://git.example.org/linux.git").is_err());
+        assert!(check_git_remote(&tls, "git@git.example.org:linux.git").is_ok());
+        assert!(check_git_remote(&tls, "ssh://git.example.org/linux.git").is_ok());
+    }
+
+    #[test]
+    fn client_certificate_format_follows_the_extension() {
+        assert!(is_pkcs12(Path::new("/etc/ktp/client.p12")));
+        assert!(is_pkcs12(Path::new("/etc/ktp/client.PFX")));
+        assert!(!is_pkcs12(Path::new("/etc/ktp/client.pem")));
+        assert!(!is_pkcs12(Path::new("/etc/ktp/client.der")));
+
+        let dir = tempfile::tempdir().unwrap();
+        let der = dir.path().join("client.der");
+        std::fs::write(&der, [0x30, 0x82, 0x01, 0x0a]).unwrap();
+        let tls = TlsConfig { client_cert: Some(der), ..TlsConfig::default() };
+        let err = client_identity(&tls).unwrap_err().to_string();
+        assert!(err.contains("is not a PEM certificate"), "{}", err);
+    }
+}
@@ -0,0 +1,130 @@
+use std::path::{Path, PathBuf};
+use tokio::process::Command as TokioCommand;
+
+use crate::config::ToolchainProfile;
+
+/// A tool the build needs, and where (and in which version) it was found.
+#[derive(Debug, Clone)]
+pub struct ToolReport {
+    pub role: &'static str,
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub version: Option<String>,
+}
+
+impl ToolchainProfile {
+    /// `NAME=value` make variables for this profile, in command-line order.
+    pub fn make_vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(arch) = &self.arch {
+            vars.push(("ARCH".to_string(), arch.clone()));
+        }
+        if let Some(prefix) = &self.cross_compile {
+            vars.push(("CROSS_COMPILE".to_string(), prefix.clone()));
+        }
+        if let Some(llvm) = self.llvm.as_ref().and_then(|l| l.make_value()) {
+            vars.push(("LLVM".to_string(), llvm));
+        }
+        if let Some(cc) = &self.cc {
+            vars.push(("CC".to_string(), cc.clone()));
+        }
+        if let Some(ld) = &self.ld {
+            vars.push(("LD".to_string(), ld.clone()));
+        }
+        vars.extend(self.make_vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+        vars
+    }
+
+    /// The compiler, linker and binutils Kbuild will invoke, following the
+    /// kernel's `LLVM=` and `CROSS_COMPILE=` naming rules.
+    pub fn required_tools(&self) -> Vec<(&'static str, String)> {
+        let cross = self.cross_compile.clone().unwrap_or_default();
+        let llvm_tool = |tool: &str| self.llvm_tool(tool).unwrap_or_default();
+
+        let (cc, ld, ar, objcopy) = if self.llvm_tool("clang").is_some() {
+            (llvm_tool("clang"), llvm_tool("ld.lld"), llvm_tool("llvm-ar"), llvm_tool("llvm-objcopy"))
+        } else {
+            (
+                format!("{}gcc", cross),
+                format!("{}ld", cross),
+                format!("{}ar", cross),
+                format!("{}objcopy", cross),
+            )
+        };
+
+        vec![
+            ("compiler", self.cc.clone().unwrap_or(cc)),
+            ("linker", self.ld.clone().unwrap_or(ld)),
+            ("archiver", ar),
+            ("objcopy", objcopy),
+        ]
+    }
+
+    /// The name of an LLVM tool under the profile's `LLVM=` setting, or
+    /// `None` without LLVM.
+    fn llvm_tool(&self, tool: &str) -> Option<String> {
+        let llvm = self.llvm.as_ref().and_then(|l| l.make_value())?;
+        Some(match llvm.as_str() {
+            v if v.ends_with('/') => format!("{}{}", v, tool),
+            v if v.starts_with('-') => format!("{}{}", tool, v),
+            _ => tool.to_string(),
+        })
+    }
+
+    /// The compiler Kbuild uses for host programs (`HOSTCC`).
+    pub fn host_compiler(&self) -> String {
+        self.llvm_tool("clang").unwrap_or_else(|| "gcc".to_string())
+    }
+
+    pub fn describe(&self) -> String {
+        let vars: Vec<String> = self.make_vars().iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        if vars.is_empty() {
+            return format!("'{}'", self.name);
+        }
+        format!("'{}' ({})", self.name, vars.join(" "))
+    }
+}
+
+/// Looks up the profile's tools on `PATH` and asks each for its version.
+pub async fn check_tools(profile: &ToolchainProfile) -> Vec<ToolReport> {
+    let mut reports = Vec::new();
+    for (role, name) in profile.required_tools() {
+        // CC/LD may carry wrapper arguments ("ccache gcc"); check the first word.
+        let program = name.split_whitespace().next().unwrap_or_default().to_string();
+        let path = find_in_path(&program);
+        let version = match &path {
+            Some(path) => tool_version(path).await,
+            None => None,
+        };
+        reports.push(ToolReport { role, name, path, version });
+    }
+    reports
+}
+
+/// First non-empty line of `<tool> --version`.
+pub async fn tool_version(path: &Path) -> Option<String> {
+    let output = TokioCommand::new(path).arg("--version").output().await.ok()?;
+    let text = if output.stdout.is_empty() { output.stderr } else { output.stdout };
+    String::from_utf8_lossy(&text).lines().map(str::trim).find(|line| !line.is_empty()).map(str::to_string)
+}
+
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return path.is_file().then_some(path);
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Like `find_in_path`, but also looks in the sbin directories, which are
+/// often missing from an unprivileged user's `PATH`.
+pub fn find_system_tool(program: &str) -> Option<PathBuf> {
+    find_in_path(program).or_else(|| {
+        ["/usr/sbin", "/sbin"]
+            .iter()
+            .map(|dir| Path::new(dir).join(program))
+            .find(|candidate| candidate.is_file())
+    })
+}